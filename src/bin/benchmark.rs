@@ -1,18 +1,22 @@
 //! Operations on predefined benchmarks
-use anyhow::bail;
+use anyhow::{Context, bail};
 use cbp_experiments::{
-    SimPointResult, ask_for_conditional_branch_predictor, ask_for_config_name,
-    ask_for_indirect_branch_predictor, ask_for_simulate_dir, get_config_path, get_simpoint_dir,
-    get_simulate_dir, get_trace_dir,
+    METADATA_COMMAND, METADATA_CWD, METADATA_TRACER, SimPointResult, SimulateOptions,
+    SimulateResult, TraceFileDecoder, ask_for_conditional_branch_predictor, ask_for_config_name,
+    ask_for_indirect_branch_predictor, ask_for_simulate_dir, combine_simulate_results,
+    convert_trace_file, get_config_path, get_host_metadata, get_image_store_dir, get_simpoint_dir,
+    get_simulate_dir, get_trace_dir, is_predictor_reusable, read_trace_file, simulate_trace,
 };
 use chrono::Local;
 use clap::{Parser, Subcommand, ValueEnum};
 use resolve_path::PathResolveExt;
 use serde::Deserialize;
+use size::Size;
 use std::{
-    collections::VecDeque,
+    collections::{BTreeMap, VecDeque},
     fs::{File, create_dir_all},
-    path::PathBuf,
+    io::BufReader,
+    path::{Path, PathBuf},
     process::Stdio,
     sync::{Arc, Mutex},
     time::Instant,
@@ -102,6 +106,12 @@ enum Commands {
         #[arg(short, long)]
         config_name: Option<String>,
     },
+    /// Move embedded images of traces and slices to the shared image store
+    DedupImages {
+        /// Benchmark config name
+        #[arg(short, long)]
+        config_name: PathBuf,
+    },
 }
 
 #[derive(Clone, Deserialize)]
@@ -138,6 +148,11 @@ fn get_tracer_name(tracer: &Option<Tracer>) -> String {
     }
 }
 
+/// Quote argument for sh
+fn shell_quote(arg: &str) -> String {
+    format!("'{}'", arg.replace('\'', "'\\''"))
+}
+
 fn run_in_shell(cmd: &str) -> anyhow::Result<()> {
     println!("Running {}", cmd);
     let time = Instant::now();
@@ -152,6 +167,82 @@ fn run_in_shell(cmd: &str) -> anyhow::Result<()> {
     Ok(())
 }
 
+/// Convert trace written by the tracers to the latest format and record metadata
+fn add_trace_metadata(
+    trace_file: &Path,
+    metadata: &BTreeMap<String, String>,
+) -> anyhow::Result<()> {
+    println!("Recording metadata to {}", trace_file.display());
+    let time = Instant::now();
+    let tmp_file = trace_file.with_extension("log.tmp");
+    convert_trace_file(trace_file, &tmp_file, metadata, None)?;
+    std::fs::rename(&tmp_file, trace_file)?;
+    println!("Finished in {:?}", time.elapsed());
+    Ok(())
+}
+
+/// Rewrite the trace to reference its images in the image store,
+/// returns the file sizes before and after, or None if there is nothing to move
+fn dedup_trace_images(trace_file: &Path, image_store: &Path) -> anyhow::Result<Option<(u64, u64)>> {
+    let content = read_trace_file(trace_file)?;
+    // stdout and perf logs share the extension
+    let Ok(file) = TraceFileDecoder::open_with_path(&content, trace_file) else {
+        return Ok(None);
+    };
+    if (0..file.num_images).all(|i| file.is_image_stored(i)) {
+        return Ok(None);
+    }
+    drop(file);
+    drop(content);
+
+    let size_before = std::fs::metadata(trace_file)?.len();
+    let tmp_file = trace_file.with_extension("log.tmp");
+    convert_trace_file(trace_file, &tmp_file, &BTreeMap::new(), Some(image_store))?;
+    std::fs::rename(&tmp_file, trace_file)?;
+    Ok(Some((size_before, std::fs::metadata(trace_file)?.len())))
+}
+
+/// Simulate the trace in this process and write the result
+fn simulate_in_process(
+    trace_file: &Path,
+    output_file: &Path,
+    conditional_branch_predictor: &str,
+    indirect_branch_predictor: &str,
+    warmup: u64,
+    simulate: u64,
+) -> anyhow::Result<()> {
+    println!("Simulating {}", trace_file.display());
+    let time = Instant::now();
+    let content = read_trace_file(trace_file)?;
+    let decoder = TraceFileDecoder::open_with_path(&content, trace_file)?;
+    let mut result = simulate_trace(
+        &decoder,
+        conditional_branch_predictor,
+        indirect_branch_predictor,
+        SimulateOptions {
+            warmup: Some(warmup),
+            simulate,
+            ..Default::default()
+        },
+    )
+    .with_context(|| format!("Simulating {} failed", trace_file.display()))?;
+    result.trace_path = Some(trace_file.to_path_buf());
+    std::fs::write(output_file, serde_json::to_vec(&result)?)?;
+    println!(
+        "Finished simulating {} in {:?}, CMPKI {:.2}",
+        trace_file.display(),
+        time.elapsed(),
+        result.cmpki
+    );
+    Ok(())
+}
+
+/// Load the result written by simulate or combine
+fn read_simulate_result(path: &Path) -> anyhow::Result<SimulateResult> {
+    println!("Loading simulation result from {}", path.display());
+    Ok(serde_json::from_reader(BufReader::new(File::open(path)?))?)
+}
+
 fn run_in_parallel<T: Clone + Send + 'static>(
     args: &[T],
     parallel: usize,
@@ -290,6 +381,19 @@ fn main() -> anyhow::Result<()> {
                     let executable = parts.next().unwrap();
                     let args = parts.collect::<Vec<_>>().join(" ");
                     let exe_path = executable.resolve();
+
+                    // provenance of the trace
+                    let mut metadata = get_host_metadata();
+                    metadata.insert(METADATA_TRACER.to_string(), tracer_name.to_string());
+                    metadata.insert(
+                        METADATA_COMMAND.to_string(),
+                        format!("{} {}", exe_path.display(), args),
+                    );
+                    metadata.insert(
+                        METADATA_CWD.to_string(),
+                        tmp_dir.path().display().to_string(),
+                    );
+
                     match tracer {
                         Tracer::Pin => {
                             let args = format!(
@@ -312,6 +416,8 @@ fn main() -> anyhow::Result<()> {
                                 .status()?;
                             assert!(result.success());
                             println!("Finished in {:?}", time.elapsed());
+
+                            add_trace_metadata(&trace_file, &metadata)?;
                         }
                         Tracer::DynamoRIO => {
                             let args = format!(
@@ -334,6 +440,8 @@ fn main() -> anyhow::Result<()> {
                                 .status()?;
                             assert!(result.success());
                             println!("Finished in {:?}", time.elapsed());
+
+                            add_trace_metadata(&trace_file, &metadata)?;
                         }
                         Tracer::IntelPT => {
                             // record intel pt
@@ -360,11 +468,17 @@ fn main() -> anyhow::Result<()> {
                             println!("Finished in {:?}", time.elapsed());
 
                             // conversion
-                            let args = format!(
+                            let mut args = format!(
                                 "time target/release/intel_pt_converter --trace-path {} --output-path {}",
                                 perf_data_file.display(),
                                 trace_file.display(),
                             );
+                            for (key, value) in &metadata {
+                                args += &format!(
+                                    " --metadata {}",
+                                    shell_quote(&format!("{}={}", key, value))
+                                );
+                            }
                             run_in_shell(&args)?;
                         }
                     }
@@ -560,18 +674,32 @@ fn main() -> anyhow::Result<()> {
                                 "{}-{}-simpoint-{}.log",
                                 benchmark.name, command_index, simpoint_index
                             ));
-                            let args = format!(
-                                "target/release/simulate --trace-path {} --conditional-branch-predictor {} --indirect-branch-predictor {} --skip 0 --warmup {} --simulate {} --output-path {}",
-                                trace_file.display(),
-                                conditional_branch_predictor,
-                                indirect_branch_predictor,
-                                // half for warmup, half for simulate
-                                simpoint_config.size / 2,
-                                simpoint_config.size / 2,
-                                output_file.display()
-                            );
-                            run_in_shell(&args)?;
-                            Ok(())
+                            // half for warmup, half for simulate
+                            let half = simpoint_config.size / 2;
+                            if is_predictor_reusable(&conditional_branch_predictor)
+                                && is_predictor_reusable(&indirect_branch_predictor)
+                            {
+                                simulate_in_process(
+                                    &trace_file,
+                                    &output_file,
+                                    &conditional_branch_predictor,
+                                    &indirect_branch_predictor,
+                                    half,
+                                    half,
+                                )
+                            } else {
+                                // the predictors keep their state in globals, use a new process
+                                let args = format!(
+                                    "target/release/simulate --trace-path {} --conditional-branch-predictor {} --indirect-branch-predictor {} --skip 0 --warmup {} --simulate {} --output-path {}",
+                                    trace_file.display(),
+                                    conditional_branch_predictor,
+                                    indirect_branch_predictor,
+                                    half,
+                                    half,
+                                    output_file.display()
+                                );
+                                run_in_shell(&args)
+                            }
                         },
                     )?;
 
@@ -579,34 +707,42 @@ fn main() -> anyhow::Result<()> {
                     // combined result at "{simulate_dir}/per-command/{benchmark.name}-{command_index}.log"
                     let output_file =
                         per_command_dir.join(format!("{}-{}.log", benchmark.name, command_index));
-                    let args = format!(
-                        "target/release/combine --output-path {} simpoint --simpoint-path {} --result-path {}",
-                        output_file.display(),
-                        get_simpoint_dir(&config_name)
-                            .join(format!("{}-{}.json", benchmark.name, command_index))
-                            .display(),
-                        per_simpoint_dir.display(),
-                    );
-                    run_in_shell(&args)?;
+                    let mut inputs = vec![];
+                    for (simpoint_index, phase) in simpoint_config.phases.iter().enumerate() {
+                        let result_file = per_simpoint_dir.join(format!(
+                            "{}-{}-simpoint-{}.log",
+                            benchmark.name, command_index, simpoint_index
+                        ));
+                        inputs.push((read_simulate_result(&result_file)?, phase.simulate_weight()));
+                    }
+                    // use the total instructions count from simpoint result
+                    let (mut combined, image_mismatches) =
+                        combine_simulate_results(inputs, Some(simpoint_config.total_instructions))?;
+                    for mismatch in image_mismatches {
+                        print!("WARNING: Found mismatched images:\n{}", mismatch);
+                    }
+                    combined.trace_path = Some(simpoint_config.trace_path.clone());
+                    println!("Combined result written to {}", output_file.display());
+                    std::fs::write(&output_file, serde_json::to_vec(&combined)?)?;
                 }
 
                 // combine results of different commands
                 // combined result at "{simulate_dir}/per-benchmark/{benchmark.name}.log"
                 let output_file = per_benchmark_dir.join(format!("{}.log", benchmark.name));
-                let mut paths = vec![];
+                let mut inputs = vec![];
                 for (command_index, _command) in benchmark.commands.iter().enumerate() {
                     // combined simpoint result at "{simulate_dir}/per-command/{benchmark.name}-{command_index}.log"
                     let command_file =
                         per_command_dir.join(format!("{}-{}.log", benchmark.name, command_index));
-                    paths.push("--command-paths".to_string());
-                    paths.push(format!("{}", command_file.display()));
+                    // all command results have weight of 1
+                    inputs.push((read_simulate_result(&command_file)?, 1));
                 }
-                let args = format!(
-                    "target/release/combine --output-path {} command {}",
-                    output_file.display(),
-                    paths.join(" ")
-                );
-                run_in_shell(&args)?;
+                let (combined, image_mismatches) = combine_simulate_results(inputs, None)?;
+                for mismatch in image_mismatches {
+                    print!("WARNING: Found mismatched images:\n{}", mismatch);
+                }
+                println!("Combined result written to {}", output_file.display());
+                std::fs::write(&output_file, serde_json::to_vec(&combined)?)?;
             }
         }
         Commands::Report { config_name } => {
@@ -634,6 +770,51 @@ fn main() -> anyhow::Result<()> {
             let args = format!("target/release/report {}", paths.join(" "));
             run_in_shell(&args)?;
         }
+        Commands::DedupImages { config_name } => {
+            let image_store = get_image_store_dir(config_name);
+
+            // traces of each tracer, skipping the symlinks under final, and simpoint slices
+            let mut dirs = vec![get_simpoint_dir(config_name)];
+            for entry in std::fs::read_dir(get_trace_dir(config_name, ""))? {
+                dirs.push(entry?.path());
+            }
+            let mut trace_files = vec![];
+            for dir in dirs {
+                if !dir.is_dir() {
+                    continue;
+                }
+                for entry in std::fs::read_dir(dir)? {
+                    let path = entry?.path();
+                    if path.extension().is_some_and(|ext| ext == "log")
+                        && std::fs::symlink_metadata(&path)?.is_file()
+                    {
+                        trace_files.push(path);
+                    }
+                }
+            }
+            trace_files.sort();
+
+            let mut total_before = 0;
+            let mut total_after = 0;
+            for trace_file in trace_files {
+                if let Some((before, after)) = dedup_trace_images(&trace_file, &image_store)? {
+                    println!(
+                        "Moved images of {} to {}: {} -> {}",
+                        trace_file.display(),
+                        image_store.display(),
+                        Size::from_bytes(before),
+                        Size::from_bytes(after)
+                    );
+                    total_before += before;
+                    total_after += after;
+                }
+            }
+            println!(
+                "Traces shrink from {} to {}",
+                Size::from_bytes(total_before),
+                Size::from_bytes(total_after)
+            );
+        }
     }
 
     Ok(())
@@ -0,0 +1,103 @@
+//! Convert between CBP2016 BT9 traces and our trace format
+use cbp_experiments::{
+    TraceFileDecoder, TraceFileEncoder, export_bt9, import_bt9, read_trace_file,
+};
+use clap::{Parser, Subcommand};
+use std::{
+    fs::File,
+    io::{BufReader, BufWriter},
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+};
+
+#[derive(Parser)]
+#[command(version, about, long_about = None)]
+struct Cli {
+    #[command(subcommand)]
+    command: Commands,
+}
+
+#[derive(Subcommand)]
+enum Commands {
+    /// Convert BT9 trace to trace file
+    Import {
+        /// Path to BT9 trace, decompressed by gzip if it ends with .gz
+        #[arg(short, long)]
+        input_path: PathBuf,
+
+        /// Path to output trace file
+        #[arg(short, long)]
+        output_path: PathBuf,
+    },
+    /// Convert trace file to BT9 trace
+    Export {
+        /// Path to trace file
+        #[arg(short, long)]
+        trace_path: PathBuf,
+
+        /// Path to output BT9 trace, compressed by gzip if it ends with .gz
+        #[arg(short, long)]
+        output_path: PathBuf,
+    },
+}
+
+fn is_gzip(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| ext == "gz")
+}
+
+fn main() -> anyhow::Result<()> {
+    let args = Cli::parse();
+
+    match args.command {
+        Commands::Import {
+            input_path,
+            output_path,
+        } => {
+            let output_file = File::create(&output_path)?;
+            let mut encoder = TraceFileEncoder::open(&output_file)?;
+            if is_gzip(&input_path) {
+                let mut child = Command::new("gzip")
+                    .arg("-dc")
+                    .arg(&input_path)
+                    .stdout(Stdio::piped())
+                    .spawn()?;
+                import_bt9(BufReader::new(child.stdout.take().unwrap()), &mut encoder)?;
+                anyhow::ensure!(child.wait()?.success(), "Failed to decompress BT9 trace");
+            } else {
+                import_bt9(BufReader::new(File::open(&input_path)?), &mut encoder)?;
+            }
+            println!(
+                "Imported {} branches and {} entries from {}",
+                encoder.branches.len(),
+                encoder.num_entries,
+                input_path.display()
+            );
+            encoder.finish()?;
+        }
+        Commands::Export {
+            trace_path,
+            output_path,
+        } => {
+            let content = read_trace_file(&trace_path)?;
+            let file = TraceFileDecoder::open_with_path(&content, &trace_path)?;
+            println!(
+                "Got {} branches and {} entries",
+                file.num_branches, file.num_entries
+            );
+            let output_file = File::create(&output_path)?;
+            if is_gzip(&output_path) {
+                let mut child = Command::new("gzip")
+                    .arg("-c")
+                    .stdin(Stdio::piped())
+                    .stdout(output_file)
+                    .spawn()?;
+                export_bt9(&file, BufWriter::new(child.stdin.take().unwrap()))?;
+                anyhow::ensure!(child.wait()?.success(), "Failed to compress BT9 trace");
+            } else {
+                export_bt9(&file, BufWriter::new(output_file))?;
+            }
+            println!("Exported BT9 trace to {}", output_path.display());
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,62 @@
+//! Convert CBP2025 trace to trace file
+use cbp_experiments::{
+    TraceFileDecoder, TraceFileEncoder, TraceFileSource, TraceSource, import_cbp2025,
+    read_trace_file,
+};
+use clap::Parser;
+use std::{
+    fs::File,
+    path::PathBuf,
+    process::{Command, Stdio},
+};
+
+#[derive(Parser)]
+#[command(version, about, long_about = None)]
+struct Cli {
+    /// Path to CBP2025 trace, decompressed by gzip if it ends with .gz
+    #[arg(short, long)]
+    input_path: PathBuf,
+
+    /// Path to output trace file
+    #[arg(short, long)]
+    output_path: PathBuf,
+}
+
+fn main() -> anyhow::Result<()> {
+    let args = Cli::parse();
+
+    let output_file = File::create(&args.output_path)?;
+    let mut encoder = TraceFileEncoder::open(&output_file)?;
+    if args.input_path.extension().is_some_and(|ext| ext == "gz") {
+        let mut child = Command::new("gzip")
+            .arg("-dc")
+            .arg(&args.input_path)
+            .stdout(Stdio::piped())
+            .spawn()?;
+        import_cbp2025(child.stdout.take().unwrap(), &mut encoder)?;
+        anyhow::ensure!(
+            child.wait()?.success(),
+            "Failed to decompress CBP2025 trace"
+        );
+    } else {
+        import_cbp2025(File::open(&args.input_path)?, &mut encoder)?;
+    }
+    encoder.finish()?;
+
+    // the output may be a pipe
+    if output_file.metadata()?.is_file() {
+        let content = read_trace_file(&args.output_path)?;
+        let file = TraceFileDecoder::open_with_path(&content, &args.output_path)?;
+        let mut source = TraceFileSource::new(&file, 0, 0)?;
+        while source.next_event()?.is_some() {}
+        let instructions = source.instructions();
+        println!(
+            "Imported {} branches, {} entries and {} instructions from {}",
+            file.num_branches,
+            file.num_entries,
+            instructions,
+            args.input_path.display()
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,78 @@
+//! Export trace file to ChampSim trace
+use cbp_experiments::{InstCountFallbacks, TraceFileDecoder, export_champsim, read_trace_file};
+use clap::Parser;
+use std::{
+    fs::File,
+    io::BufWriter,
+    path::PathBuf,
+    process::{Command, Stdio},
+};
+
+#[derive(Parser)]
+#[command(
+    version,
+    about,
+    long_about = None,
+    after_help = "Memory operands are only produced for RIP-relative or absolute addresses on x86, \
+                  other instructions are exported without memory operands"
+)]
+struct Cli {
+    /// Path to trace file
+    #[arg(short, long)]
+    trace_path: PathBuf,
+
+    /// Path to output ChampSim trace, compressed by xz or gzip if it ends with .xz or .gz
+    #[arg(short, long)]
+    output_path: PathBuf,
+}
+
+fn main() -> anyhow::Result<()> {
+    let args = Cli::parse();
+
+    let content = read_trace_file(&args.trace_path)?;
+    let file = TraceFileDecoder::open_with_path(&content, &args.trace_path)?;
+    println!(
+        "Got {} branches, {} entries and {} images",
+        file.num_branches, file.num_entries, file.num_images
+    );
+
+    let output_file = File::create(&args.output_path)?;
+    let compressor = match args.output_path.extension().and_then(|ext| ext.to_str()) {
+        Some("xz") => Some("xz"),
+        Some("gz") => Some("gzip"),
+        _ => None,
+    };
+    let stats = match compressor {
+        Some(compressor) => {
+            let mut child = Command::new(compressor)
+                .arg("-c")
+                .stdin(Stdio::piped())
+                .stdout(output_file)
+                .spawn()?;
+            let stats = export_champsim(&file, BufWriter::new(child.stdin.take().unwrap()))?;
+            anyhow::ensure!(child.wait()?.success(), "Failed to compress ChampSim trace");
+            stats
+        }
+        None => export_champsim(&file, BufWriter::new(output_file))?,
+    };
+    println!(
+        "Exported {} instructions including {} branches to {}",
+        stats.instructions,
+        stats.branches,
+        args.output_path.display()
+    );
+    if stats.gaps > 0 {
+        println!(
+            "Failed to recover the instructions before {} branches from the images, missing {} counted instructions",
+            stats.gaps, stats.missing_instructions
+        );
+    }
+    let fallbacks = stats.inst_count_fallbacks;
+    if fallbacks != InstCountFallbacks::default() {
+        println!(
+            "Taken branches outside the text sections: {} disassembled on demand, {} estimated, {} unknown",
+            fallbacks.disassembled, fallbacks.estimated, fallbacks.unknown
+        );
+    }
+    Ok(())
+}
@@ -1,11 +1,10 @@
 //! Combine simulation results of multiple SimPoint phases
 use cbp_experiments::{
-    Branch, BranchType, ImageWithoutData, SimPointResult, SimulateResult, SimulateResultBranchInfo,
-    is_indirect,
+    BranchType, SimPointResult, SimulateResult, Symbolizer, combine_simulate_results,
 };
 use clap::{Parser, Subcommand};
 use cli_table::{Cell, Table, print_stdout};
-use std::{collections::HashMap, fs::File, io::BufReader, path::PathBuf};
+use std::{fs::File, io::BufReader, path::PathBuf};
 
 #[derive(Parser)]
 #[command(version, about, long_about = None)]
@@ -42,11 +41,6 @@ enum Commands {
 fn main() -> anyhow::Result<()> {
     let args = Cli::parse();
 
-    // combined result
-    let mut branch_info: Vec<SimulateResultBranchInfo> = vec![];
-    let mut conditional_branch_predictor = String::new();
-    let mut indirect_branch_predictor = String::new();
-    let mut images: Vec<ImageWithoutData> = vec![];
     let trace_path: Option<PathBuf>;
 
     // tuple of (input file, weight)
@@ -68,10 +62,7 @@ fn main() -> anyhow::Result<()> {
                     simpoint_index
                 ));
 
-                // since only half of the simpoint is used for simulation (the other half is used for warmup)
-                // the counts should be multipled by phase.weight * 2
-                let weight = phase.weight * 2;
-                input_files.push((result_file, weight));
+                input_files.push((result_file, phase.simulate_weight()));
             }
 
             // use the total instructions count from simpoint result
@@ -87,72 +78,34 @@ fn main() -> anyhow::Result<()> {
         }
     }
 
-    // maintain mapping from branch to index in branch_info array
-    let mut mapping: HashMap<Branch, usize> = HashMap::new();
-    let mut total_instructions = 0;
+    let mut inputs = vec![];
     for (input_file, weight) in input_files {
         println!("Loading simulation result from {}", input_file.display());
         let simulate_result: SimulateResult =
             serde_json::from_reader(BufReader::new(File::open(&input_file)?))?;
-
-        // validate & save metadata
-        if !conditional_branch_predictor.is_empty() {
-            assert_eq!(
-                conditional_branch_predictor,
-                simulate_result.conditional_branch_predictor
-            );
-        }
-        if !images.is_empty() {
-            // generate warning if images differs, it is okay if it is a dynamic library or vdso
-            if images != simulate_result.images {
-                println!("WARNING: Found mismatched images:");
-                for image in &images {
-                    println!("LEFT : {} at 0x{:x}", image.filename, image.start);
-                }
-                for image in &simulate_result.images {
-                    println!("RIGHT: {} at 0x{:x}", image.filename, image.start);
-                }
-            }
-        }
-        conditional_branch_predictor = simulate_result.conditional_branch_predictor;
-        indirect_branch_predictor = simulate_result.indirect_branch_predictor;
-        images = simulate_result.images;
-
-        total_instructions += simulate_result.simulate;
-
-        // merge branch info
-        for info in &simulate_result.branch_info {
-            match mapping.get(&info.branch) {
-                Some(index) => {
-                    branch_info[*index].execution_count += info.execution_count * weight;
-                    branch_info[*index].taken_count += info.taken_count * weight;
-                    branch_info[*index].mispred_count += info.mispred_count * weight;
-                }
-                None => {
-                    mapping.insert(info.branch, branch_info.len());
-                    branch_info.push(SimulateResultBranchInfo {
-                        branch: info.branch,
-                        execution_count: info.execution_count * weight,
-                        taken_count: info.taken_count * weight,
-                        mispred_count: info.mispred_count * weight,
-                    });
-                }
-            }
-        }
+        inputs.push((simulate_result, weight));
     }
-
-    // if merging simpoint result, use the total count instead
-    if let Some(instructions) = override_total_instructions {
-        total_instructions = instructions;
+    let (mut combined, image_mismatches) =
+        combine_simulate_results(inputs, override_total_instructions)?;
+    // it is okay if it is a dynamic library or vdso
+    for mismatch in image_mismatches {
+        print!("WARNING: Found mismatched images:\n{}", mismatch);
     }
+    combined.trace_path = trace_path;
 
     println!("Top branches by misprediction count:");
-    branch_info.sort_by_key(|info| info.mispred_count);
+    let mut items: Vec<_> = combined.branch_info.iter().collect();
+    items.sort_by_key(|info| info.mispred_count);
+    let mut symbolizer = Symbolizer::from_disk(&combined.images);
     let mut table = vec![];
-    for info in branch_info.iter().rev().take(10) {
+    for info in items.iter().rev().take(10) {
         table.push(vec![
             format!("0x{:08x}", info.branch.inst_addr).cell(),
             format!("{:?}", info.branch.branch_type).cell(),
+            symbolizer
+                .symbolize(info.branch.inst_addr)
+                .unwrap_or_else(|| "unknown".to_string())
+                .cell(),
             info.execution_count.cell(),
             info.mispred_count.cell(),
             format!(
@@ -170,6 +123,7 @@ fn main() -> anyhow::Result<()> {
     let table = table.table().title(vec![
         "Branch PC".cell(),
         "Branch Type".cell(),
+        "Symbol".cell(),
         "Execution Count".cell(),
         "Misprediction Count".cell(),
         "Taken Rate (%)".cell(),
@@ -178,17 +132,18 @@ fn main() -> anyhow::Result<()> {
     print_stdout(table)?;
 
     println!("Overall statistics:");
-    // compute statistics
-    let total_br_execution_count: u64 = branch_info.iter().map(|info| info.execution_count).sum();
     println!(
         "- Runtime executions of branches: {}",
-        total_br_execution_count,
+        combined.total_br_execution_count,
+    );
+    println!(
+        "- Total branch mispredictions: {}",
+        combined.total_mispred_count
     );
-    let total_mispred_count: u64 = branch_info.iter().map(|info| info.mispred_count).sum();
-    println!("- Total branch mispredictions: {}", total_mispred_count);
 
     // conditional branch predictions
-    let num_cond_brs_executed = branch_info
+    let num_cond_brs_executed = combined
+        .branch_info
         .iter()
         .filter(|info| {
             info.branch.branch_type == BranchType::ConditionalDirectJump && info.execution_count > 0
@@ -198,85 +153,46 @@ fn main() -> anyhow::Result<()> {
         "- Number of conditional branches executed at least once (static branches per slice): {}",
         num_cond_brs_executed,
     );
-    let total_cond_mispred_count: u64 = branch_info
-        .iter()
-        .filter(|info| info.branch.branch_type == BranchType::ConditionalDirectJump)
-        .map(|info| info.mispred_count)
-        .sum();
+    let total_cond_mispred_count = combined.cond_mispred_count();
     println!(
         "- Conditional branch mispredictions: {}",
         total_cond_mispred_count
     );
-    let cmpki = total_cond_mispred_count as f64 * 1000.0 / total_instructions as f64;
     println!(
         "- Conditional branch mispredictions per kilo instructions (CMPKI): {:.2} = {} * 1000 / {}",
-        cmpki, total_cond_mispred_count, total_instructions
+        combined.cmpki, total_cond_mispred_count, combined.simulate
     );
-    let total_cond_execution_count: u64 = branch_info
-        .iter()
-        .filter(|info| info.branch.branch_type == BranchType::ConditionalDirectJump)
-        .map(|info| info.execution_count)
-        .sum();
     println!(
         "- Runtime executions of conditional branches: {}",
-        total_cond_execution_count,
+        combined.total_cond_execution_count,
     );
-    let cond_branch_prediction_accuracy =
-        100.0 - total_cond_mispred_count as f64 * 100.0 / total_cond_execution_count as f64;
     println!(
         "- Prediction accuracy of conditional branches: {:.2}% = 1 - {} / {}",
-        cond_branch_prediction_accuracy, total_cond_mispred_count, total_cond_execution_count
+        combined.cond_branch_prediction_accuracy.unwrap_or(f64::NAN),
+        total_cond_mispred_count,
+        combined.total_cond_execution_count
     );
 
     // indirect branch prediction
-    let total_indirect_mispred_count: u64 = branch_info
-        .iter()
-        .filter(|info| is_indirect(info.branch.branch_type))
-        .map(|info| info.mispred_count)
-        .sum();
-    let impki = total_indirect_mispred_count as f64 * 1000.0 / total_instructions as f64;
+    let total_indirect_mispred_count = combined.indirect_mispred_count();
     println!(
         "- Indirect branch mispredictions per kilo instructions (IMPKI): {:.2} = {} * 1000 / {}",
-        impki, total_indirect_mispred_count, total_instructions
+        combined.impki, total_indirect_mispred_count, combined.simulate
     );
-    let total_indirect_execution_count: u64 = branch_info
-        .iter()
-        .filter(|info| is_indirect(info.branch.branch_type))
-        .map(|info| info.execution_count)
-        .sum();
+    let total_indirect_execution_count = combined.indirect_execution_count();
     println!(
         "- Runtime executions of indirect branches: {}",
         total_indirect_execution_count,
     );
-    let indirect_branch_prediction_accuracy =
-        100.0 - total_indirect_mispred_count as f64 * 100.0 / total_indirect_execution_count as f64;
     println!(
         "- Prediction accuracy of indirect branches: {:.2}% = 1 - {} / {}",
-        indirect_branch_prediction_accuracy,
+        combined
+            .indirect_branch_prediction_accuracy
+            .unwrap_or(f64::NAN),
         total_indirect_mispred_count,
         total_indirect_execution_count
     );
 
-    let combined = SimulateResult {
-        trace_path,
-        conditional_branch_predictor,
-        indirect_branch_predictor,
-        images,
-        skip: 0,
-        warmup: 0,
-        simulate: total_instructions,
-        branch_info,
-        total_mispred_count,
-        total_br_execution_count,
-        total_cond_execution_count,
-        cmpki,
-        // handle NaN
-        cond_branch_prediction_accuracy: Some(cond_branch_prediction_accuracy),
-        impki,
-        // handle NaN
-        indirect_branch_prediction_accuracy: Some(indirect_branch_prediction_accuracy),
-    };
-
     println!("Combined result written to {}", args.output_path.display());
     std::fs::write(args.output_path, serde_json::to_vec(&combined)?)?;
 
@@ -1,12 +1,16 @@
 //! Parse Intel PT trace in perf.data and convert to our trace format
 
-use cbp_experiments::{BranchType, Image, TraceFileEncoder, find_branches, get_tqdm_style};
+use cbp_experiments::{
+    BranchType, EntryEncoding, Image, METADATA_TRACER, Symbolizer, TraceFileEncoder, find_branches,
+    get_host_metadata, get_tqdm_style,
+};
 use clap::Parser;
 use indicatif::ProgressBar;
 use log::{Level, log_enabled, trace};
 use memmap::{Mmap, MmapOptions};
 use object::{Object, ObjectKind, elf, read::elf::ProgramHeader};
 use std::{
+    cell::RefCell,
     collections::VecDeque,
     fs::File,
     io::{BufWriter, Write},
@@ -27,6 +31,22 @@ struct Cli {
     /// Path to taken branch trace
     #[arg(short, long)]
     taken_trace_path: Option<PathBuf>,
+
+    /// Metadata to record in the output trace, in KEY=VALUE form, e.g. command=...
+    #[arg(long, value_parser = parse_metadata)]
+    metadata: Vec<(String, String)>,
+
+    /// Use variable-width entries, for programs with more than 2^31 unique branches,
+    /// e.g. interpreters and JITted code
+    #[arg(long)]
+    varint_entries: bool,
+}
+
+fn parse_metadata(s: &str) -> anyhow::Result<(String, String)> {
+    match s.split_once('=') {
+        Some((key, value)) => Ok((key.to_string(), value.to_string())),
+        None => anyhow::bail!("Metadata {} is not in KEY=VALUE form", s),
+    }
 }
 
 // for IP Compression
@@ -402,6 +422,14 @@ fn main() -> anyhow::Result<()> {
     println!("Writing to trace file at {}", args.output_path.display());
     let output_file = File::create(&args.output_path)?;
     let mut output_trace = TraceFileEncoder::open(&output_file)?;
+    if args.varint_entries {
+        output_trace.set_entry_encoding(EntryEncoding::Varint)?;
+    }
+    output_trace.metadata = get_host_metadata();
+    output_trace
+        .metadata
+        .insert(METADATA_TRACER.to_string(), "intel-pt".to_string());
+    output_trace.metadata.extend(args.metadata.clone());
 
     // Maintain branch index in output file as optimization
     let mut output_branch_indices: Vec<Option<usize>> = vec![];
@@ -444,17 +472,16 @@ fn main() -> anyhow::Result<()> {
             )
         };
 
+    // created upon the first trace, after all images are found
+    let symbolizer: RefCell<Option<Symbolizer>> = RefCell::new(None);
     let trace =
         |output_trace: &TraceFileEncoder<'_>, branches: &Vec<BranchInfo>, branch_index: usize| {
             if log_enabled!(Level::Trace) {
                 let pc = branches[branch_index].inst_addr;
-                let mut addr = format!("unknown:0x{:x}", pc);
-                for image in &output_trace.images {
-                    if pc >= image.start && pc < image.start + image.len {
-                        addr = format!("{}:0x{:x}", image.filename, pc - image.start);
-                    }
-                }
-                trace!("PC = 0x{:x} ({})", pc, addr);
+                let mut symbolizer = symbolizer.borrow_mut();
+                let symbolizer =
+                    symbolizer.get_or_insert_with(|| Symbolizer::new(&output_trace.images));
+                trace!("PC = 0x{:x} ({})", pc, symbolizer.lookup(pc));
             }
         };
 
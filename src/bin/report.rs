@@ -1,5 +1,5 @@
 //! Combine simulation results of multiple SimPoint phases
-use cbp_experiments::SimulateResult;
+use cbp_experiments::{SimulateResult, Symbolizer};
 use clap::Parser;
 use cli_table::{Cell, Table, print_stdout};
 use std::{fs::File, io::BufReader, path::PathBuf};
@@ -21,6 +21,14 @@ pub struct UniqueBranchInfo {
     pub mispred_count: u64,
 }
 
+/// Path relative to the current directory if possible
+fn relative_path(path: &str) -> String {
+    std::env::current_dir()
+        .ok()
+        .and_then(|dir| pathdiff::diff_paths(path, dir))
+        .map_or_else(|| path.to_string(), |path| path.display().to_string())
+}
+
 fn main() -> anyhow::Result<()> {
     let args = Cli::parse();
 
@@ -68,33 +76,17 @@ fn main() -> anyhow::Result<()> {
 
         // 3. sort by mispred count
         items.sort_by_key(|info| info.mispred_count);
+        let mut symbolizer = Symbolizer::from_disk(&simulate_result.images);
         let mut table = vec![];
         for info in items.iter().rev().take(10) {
-            let addr = info.branch_inst_addr;
-            let mut addr_fmt = format!("unknown:0x{:x}", addr);
-            let mut line_fmt = "unknown".to_string();
-            for image in &simulate_result.images {
-                if addr >= image.start && addr < image.start + image.len {
-                    addr_fmt = format!(
-                        "{}:0x{:x}",
-                        pathdiff::diff_paths(&image.filename, std::env::current_dir()?)
-                            .unwrap()
-                            .display(),
-                        addr - image.start
-                    );
-                    let file = addr2line::Loader::new(&image.filename).unwrap();
-                    if let Some(location) = file.find_location(addr).unwrap() {
-                        line_fmt = format!(
-                            "{}:{}",
-                            pathdiff::diff_paths(location.file.unwrap(), std::env::current_dir()?)
-                                .unwrap()
-                                .display(),
-                            location.line.unwrap()
-                        );
-                    }
-                    break;
-                }
-            }
+            let location = symbolizer.lookup(info.branch_inst_addr);
+            let addr_fmt = match &location.image {
+                Some((filename, offset)) => format!("{}:0x{:x}", relative_path(filename), offset),
+                None => location.image_offset(),
+            };
+            let line_fmt = location
+                .source_location()
+                .map_or_else(|| "unknown".to_string(), |line| relative_path(&line));
 
             table.push(vec![
                 format!("0x{:08x}", info.branch_inst_addr).cell(),
@@ -106,6 +98,7 @@ fn main() -> anyhow::Result<()> {
                 )
                 .cell(),
                 addr_fmt.cell(),
+                location.symbol.as_deref().unwrap_or("unknown").cell(),
                 line_fmt.cell(),
             ]);
         }
@@ -115,6 +108,7 @@ fn main() -> anyhow::Result<()> {
             "Misp. count".cell(),
             "Taken rate (%)".cell(),
             "Image & offset".cell(),
+            "Symbol".cell(),
             "Source location".cell(),
         ]);
         print_stdout(table)?;
@@ -1,7 +1,7 @@
 //! Use SimPoint methodology to reduce trace length
 use cbp_experiments::{
-    SimPointPhase, SimPointResult, TraceFileDecoder, TraceFileEncoder,
-    create_inst_index_mapping_from_images, get_inst_index, get_tqdm_style,
+    METADATA_SLICE_INSTRUCTIONS, SimPointPhase, SimPointResult, TraceFileDecoder, TraceFileEncoder,
+    TraceFileSource, TraceSliceWriter, TraceSource, get_tqdm_style,
 };
 use clap::Parser;
 use indicatif::ProgressIterator;
@@ -15,6 +15,9 @@ use memmap::MmapOptions;
 use ndarray::{Array2, Axis};
 use std::{fs::File, path::PathBuf};
 
+/// background threads decoding the entries
+const DECODE_THREADS: usize = 2;
+
 #[derive(Parser)]
 #[command(version, about, long_about = None)]
 struct Cli {
@@ -34,12 +37,6 @@ struct Cli {
     output_prefix: String,
 }
 
-#[derive(Debug, Clone, Copy, Default)]
-pub struct BranchInfo {
-    inst_addr_index: u64,
-    targ_addr_index: u64,
-}
-
 /// SimPoint slice: a slice is a part of the full simulation trace
 #[derive(Debug, Clone, Default)]
 pub struct SimPointSlice {
@@ -94,23 +91,18 @@ fn main() -> anyhow::Result<()> {
     let content = unsafe { MmapOptions::new().map(&file)? };
 
     // parse trace file
-    let file = TraceFileDecoder::open(&content);
+    let file = TraceFileDecoder::open_with_path(&content, &args.trace_path)?;
     println!(
         "Got {} branches and {} entries",
         file.num_branches, file.num_entries
     );
 
-    // create a mapping from instruction address to instruction index for instruction counting
-    let file_images = file.get_images()?;
-    let mapping = create_inst_index_mapping_from_images(&file_images)?;
-
-    let mut branch_infos = vec![BranchInfo::default(); file.num_branches];
-
-    // preprocess instruction indices for all branches
-    for (i, branch) in file.branches.iter().enumerate() {
-        branch_infos[i].inst_addr_index = get_inst_index(&mapping, branch.inst_addr);
-        branch_infos[i].targ_addr_index = get_inst_index(&mapping, branch.targ_addr);
-    }
+    // use embedded instruction counts if available, otherwise disassemble the images
+    let mut source = TraceFileSource::new(&file, 0, DECODE_THREADS)?;
+    anyhow::ensure!(
+        source.has_inst_counts(),
+        "Instruction counts are required for SimPoint"
+    );
 
     let pbar = indicatif::ProgressBar::new(file.num_entries as u64);
     pbar.set_style(get_tqdm_style());
@@ -118,49 +110,37 @@ fn main() -> anyhow::Result<()> {
     println!("Each SimPoint slice contains {} instructions", args.size);
     println!("Basic block vector is of dimension {}", file.num_branches);
 
-    let mut last_targ_addr_index = None;
     let mut instructions = 0;
+    let mut num_events = 0u64;
     let mut slices: Vec<SimPointSlice> = vec![];
     let mut current_simpoint_start_instruction = 0;
     let mut current_simpoint_basic_block_vector = vec![0u64; file.num_branches];
-    for entries in file.entries()? {
-        for entry in entries {
-            let br_index = entry.get_br_index();
-            let taken = entry.get_taken();
-
-            // add instruction counting
-            if taken {
-                let curr_index = branch_infos[br_index].inst_addr_index;
-                if let Some(last_index) = last_targ_addr_index {
-                    // count instructions from last target address to the current branch address
-                    assert!(curr_index >= last_index);
-                    let new_insts = (curr_index - last_index + 1) as u64;
-                    instructions += new_insts;
-
-                    // sum up instructions in the basic block, marked by the ending branch
-                    current_simpoint_basic_block_vector[br_index] += new_insts;
-                }
-                last_targ_addr_index = Some(branch_infos[br_index].targ_addr_index);
-            }
-
-            if instructions >= args.size + current_simpoint_start_instruction {
-                // create a new simpoint
-                let sum_insts: u64 = current_simpoint_basic_block_vector.iter().sum();
-                slices.push(SimPointSlice {
-                    start_instruction: current_simpoint_start_instruction,
-                    end_instruction: args.size + current_simpoint_start_instruction,
-                    // normalize
-                    basic_block_vector: current_simpoint_basic_block_vector
-                        .iter()
-                        .map(|val| *val as f64 / sum_insts as f64)
-                        .collect(),
-                });
-                current_simpoint_start_instruction += args.size;
-                current_simpoint_basic_block_vector.fill(0);
-            }
+    while let Some(event) = source.next_event()? {
+        instructions = event.instructions;
+
+        // sum up instructions in the basic block, marked by the ending branch
+        current_simpoint_basic_block_vector[event.br_index] += event.new_instructions;
+
+        if instructions >= args.size + current_simpoint_start_instruction {
+            // create a new simpoint
+            let sum_insts: u64 = current_simpoint_basic_block_vector.iter().sum();
+            slices.push(SimPointSlice {
+                start_instruction: current_simpoint_start_instruction,
+                end_instruction: args.size + current_simpoint_start_instruction,
+                // normalize
+                basic_block_vector: current_simpoint_basic_block_vector
+                    .iter()
+                    .map(|val| *val as f64 / sum_insts as f64)
+                    .collect(),
+            });
+            current_simpoint_start_instruction += args.size;
+            current_simpoint_basic_block_vector.fill(0);
         }
 
-        pbar.inc(entries.len() as u64);
+        num_events += 1;
+        if num_events.is_multiple_of(1024 * 1024) {
+            pbar.set_position(num_events);
+        }
     }
     pbar.finish();
 
@@ -299,7 +279,7 @@ fn main() -> anyhow::Result<()> {
     // iterate entries again and save the representative slice in each phase
     println!("Saving {} slices", phases.len());
     let mut trace_files = vec![];
-    let mut encoders = vec![];
+    let mut writers = vec![];
     println!(
         "Creating SimPoint slices at {}-simpoint-[{}-{}].log",
         args.output_prefix,
@@ -307,44 +287,52 @@ fn main() -> anyhow::Result<()> {
         phases.len() - 1
     );
     for (phase_index, _phase) in phases.iter().enumerate() {
-        let trace_path = format!("{}-simpoint-{}.log", args.output_prefix, phase_index);
-        trace_files.push(File::create(&trace_path)?);
+        let trace_path = PathBuf::from(format!(
+            "{}-simpoint-{}.log",
+            args.output_prefix, phase_index
+        ));
+        trace_files.push((File::create(&trace_path)?, trace_path));
     }
-    for trace_file in &trace_files {
+    let file_images = file.get_images()?;
+    for ((trace_file, trace_path), phase) in trace_files.iter().zip(phases.iter()) {
         let mut encoder = TraceFileEncoder::open(trace_file)?;
-        // for simplicity, copy all branches instead of re-creating one on the fly
-        encoder.branches = file.branches.to_vec();
-        // clone images
-        encoder.images = file_images.clone();
-        encoders.push(encoder);
+        encoder.set_entry_encoding(file.entry_encoding)?;
+        // keep provenance of the original trace
+        encoder.metadata = file.metadata.clone();
+        encoder.image_store = file.image_store.clone();
+        encoder.set_trace_path(trace_path);
+        encoder.metadata.insert(
+            METADATA_SLICE_INSTRUCTIONS.to_string(),
+            format!("{}-{}", phase.start_instruction, phase.end_instruction),
+        );
+        // only the branches and images referenced in the slice are kept
+        writers.push(TraceSliceWriter::new(encoder, file.num_branches));
     }
 
-    let pbar = indicatif::ProgressBar::new(file.num_entries as u64);
+    let pbar = indicatif::ProgressBar::new(total_instructions);
     pbar.set_style(get_tqdm_style());
 
-    let mut last_targ_addr_index = None;
-    let mut instructions = 0;
     let mut current_phase_index = 0;
-    for entries in file.entries()? {
-        for entry in entries {
-            let br_index = entry.get_br_index();
-            let taken = entry.get_taken();
-
-            // add instruction counting
-            if taken {
-                let curr_index = branch_infos[br_index].inst_addr_index;
-                if let Some(last_index) = last_targ_addr_index {
-                    // count instructions from last target address to the current branch address
-                    assert!(curr_index >= last_index);
-                    let new_insts = (curr_index - last_index + 1) as u64;
-                    instructions += new_insts;
-                }
-                last_targ_addr_index = Some(branch_infos[br_index].targ_addr_index);
-            }
+    while current_phase_index < phases.len() {
+        // jump to the chunk containing the current phase,
+        // reusing the instruction counts instead of disassembling the images again
+        source.seek_to_instruction(phases[current_phase_index].start_instruction)?;
+        pbar.set_position(source.instructions());
+
+        let mut num_events = 0u64;
+        let mut reseek = false;
+        while let Some(event) = source.next_event()? {
+            let instructions = event.instructions;
 
             // beyond the current simpoint representative slice?
             if instructions > phases[current_phase_index].end_instruction {
                 current_phase_index += 1;
+
+                // skip to the next slice, unless it starts from the beginning
+                if file.is_seekable() {
+                    reseek = true;
+                    break;
+                }
             }
 
             // all slices are finished?
@@ -356,22 +344,35 @@ fn main() -> anyhow::Result<()> {
             if instructions >= phases[current_phase_index].start_instruction
                 && instructions <= phases[current_phase_index].end_instruction
             {
-                encoders[current_phase_index].record_event_with_branch_index(br_index, taken)?;
+                // instructions before the slice are not counted
+                let writer = &mut writers[current_phase_index];
+                writer.encoder.set_thread(event.thread_id);
+                writer.record(
+                    &file.branches,
+                    event.br_index,
+                    event.taken,
+                    event
+                        .new_instructions
+                        .min(instructions - phases[current_phase_index].start_instruction),
+                )?;
+            }
+
+            num_events += 1;
+            if num_events.is_multiple_of(1024 * 1024) {
+                pbar.set_position(instructions);
             }
         }
 
-        // all slices are finished?
-        if current_phase_index == phases.len() {
+        if !reseek {
+            // reached the end of trace
             break;
         }
-
-        pbar.inc(entries.len() as u64);
     }
     pbar.finish();
 
     // finish each slice
-    for encoder in encoders {
-        encoder.finish()?;
+    for writer in writers {
+        writer.finish(&file_images)?;
     }
 
     let result = SimPointResult {
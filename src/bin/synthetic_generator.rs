@@ -0,0 +1,70 @@
+//! Generate synthetic traces for predictor testing, with embedded instruction counts
+//!
+//! The description is a TOML file, e.g.:
+//!
+//! ```toml
+//! seed = 1
+//! iterations = 10000
+//! block_size = 4
+//!
+//! [[patterns]]
+//! Loop = { trip_count = 10 }
+//!
+//! [[patterns]]
+//! Correlated = { distance = 40 }
+//!
+//! [[patterns]]
+//! Random = { bias = 0.9 }
+//!
+//! [[patterns]]
+//! Switch = { targets = 8 }
+//!
+//! [[patterns]]
+//! Calls = { depth = 4 }
+//! ```
+use cbp_experiments::{SyntheticConfig, TraceFileEncoder, generate_synthetic};
+use clap::Parser;
+use std::{fs::File, path::PathBuf};
+
+#[derive(Parser)]
+#[command(version, about, long_about = None)]
+struct Cli {
+    /// Path to trace description in TOML
+    #[arg(short, long)]
+    config_path: PathBuf,
+
+    /// Path to output trace file
+    #[arg(short, long)]
+    output_path: PathBuf,
+
+    /// Override the seed of the description
+    #[arg(short, long)]
+    seed: Option<u64>,
+
+    /// Override the iterations of the description
+    #[arg(short, long)]
+    iterations: Option<u64>,
+}
+
+fn main() -> anyhow::Result<()> {
+    let args = Cli::parse();
+    let mut config = SyntheticConfig::new(&args.config_path)?;
+    if let Some(seed) = args.seed {
+        config.seed = seed;
+    }
+    if let Some(iterations) = args.iterations {
+        config.iterations = iterations;
+    }
+
+    let output_file = File::create(&args.output_path)?;
+    let mut encoder = TraceFileEncoder::open(&output_file)?;
+    generate_synthetic(config, &mut encoder)?;
+    println!(
+        "Generated {} branches and {} entries to {}",
+        encoder.branches.len(),
+        encoder.num_entries,
+        args.output_path.display()
+    );
+    encoder.finish()?;
+    Ok(())
+}
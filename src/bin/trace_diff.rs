@@ -0,0 +1,209 @@
+//! Compare two trace files of the same program and locate the first divergence
+use cbp_experiments::{
+    BranchDiff, DiffEvent, Symbolizer, TraceFileDecoder, diff_traces, get_tqdm_style,
+    read_trace_file,
+};
+use clap::Parser;
+use cli_table::{Cell, Table, print_stdout};
+use std::path::PathBuf;
+
+#[derive(Parser)]
+#[command(version, about, long_about = None)]
+struct Cli {
+    /// Path to the first trace file
+    #[arg(short, long)]
+    first_trace_path: PathBuf,
+
+    /// Path to the second trace file
+    #[arg(short, long)]
+    second_trace_path: PathBuf,
+
+    /// Number of branches before the first divergence to display
+    #[arg(short, long, default_value = "16")]
+    context: usize,
+
+    /// Number of branches with the largest execution count differences to display
+    #[arg(short, long, default_value = "10")]
+    top: usize,
+}
+
+fn describe(symbolizer: &mut Symbolizer, event: &DiffEvent) -> String {
+    let inst_location = symbolizer.lookup(event.inst_addr).to_string();
+    format!(
+        "0x{:08x} ({}) => 0x{:08x} ({}) {}",
+        event.inst_addr,
+        inst_location,
+        event.targ_addr,
+        symbolizer.lookup(event.targ_addr),
+        if event.taken { "T" } else { "N" }
+    )
+}
+
+/// Symbols of the images in the trace, none if the images are unavailable
+fn symbolizer(file: &TraceFileDecoder) -> Symbolizer {
+    match file.get_images() {
+        Ok(images) => Symbolizer::new(&images),
+        Err(_) => Symbolizer::new(&[]),
+    }
+}
+
+fn main() -> anyhow::Result<()> {
+    let args = Cli::parse();
+
+    let first_content = read_trace_file(&args.first_trace_path)?;
+    let first_file = TraceFileDecoder::open_with_path(&first_content, &args.first_trace_path)?;
+    let second_content = read_trace_file(&args.second_trace_path)?;
+    let second_file = TraceFileDecoder::open_with_path(&second_content, &args.second_trace_path)?;
+    for (path, file) in [
+        (&args.first_trace_path, &first_file),
+        (&args.second_trace_path, &second_file),
+    ] {
+        println!(
+            "Got {} branches and {} entries from {}",
+            file.num_branches,
+            file.num_entries,
+            path.display()
+        );
+    }
+
+    let pbar =
+        indicatif::ProgressBar::new(first_file.num_entries.max(second_file.num_entries) as u64);
+    pbar.set_style(get_tqdm_style());
+    let diff = diff_traces(&first_file, &second_file, args.context, |common_entries| {
+        pbar.set_position(common_entries)
+    })?;
+    pbar.finish();
+    for (path, instructions) in [&args.first_trace_path, &args.second_trace_path]
+        .iter()
+        .zip(diff.instructions)
+    {
+        // instruction counting requires embedded instruction counts or images
+        if instructions.is_none() {
+            println!("Instruction counts are unavailable in {}", path.display());
+        }
+    }
+
+    let instructions = |instructions: Option<u64>| {
+        instructions.map_or_else(
+            || "unknown".to_string(),
+            |instructions| instructions.to_string(),
+        )
+    };
+    match &diff.divergence {
+        None => {
+            println!("Traces are identical: {} entries", diff.common_entries);
+            return Ok(());
+        }
+        Some(divergence) => {
+            println!(
+                "First divergence after {} common entries, at instruction {} of the first trace and {} of the second trace:",
+                diff.common_entries,
+                instructions(divergence.instructions[0]),
+                instructions(divergence.instructions[1])
+            );
+            for (name, file, event) in [
+                ("first", &first_file, divergence.events[0]),
+                ("second", &second_file, divergence.events[1]),
+            ] {
+                match event {
+                    Some(event) => {
+                        println!("- {}: {}", name, describe(&mut symbolizer(file), &event))
+                    }
+                    None => println!("- {}: end of trace", name),
+                }
+            }
+        }
+    }
+
+    let mut first_symbolizer = symbolizer(&first_file);
+    if !diff.history.is_empty() {
+        println!("Preceding {} branches:", diff.history.len());
+        let mut table = vec![];
+        for (event, event_instructions) in &diff.history {
+            table.push(vec![
+                instructions(*event_instructions).cell(),
+                format!("0x{:08x}", event.inst_addr).cell(),
+                first_symbolizer.lookup(event.inst_addr).to_string().cell(),
+                format!("0x{:08x}", event.targ_addr).cell(),
+                first_symbolizer.lookup(event.targ_addr).to_string().cell(),
+                if event.taken { "T" } else { "N" }.cell(),
+            ]);
+        }
+        let table = table.table().title(vec![
+            "Instruction".cell(),
+            "Branch PC".cell(),
+            "Branch Location".cell(),
+            "Target".cell(),
+            "Target Location".cell(),
+            "Taken".cell(),
+        ]);
+        print_stdout(table)?;
+    }
+
+    println!("Statistics:");
+    for (name, trace_instructions) in ["first", "second"].iter().zip(diff.instructions) {
+        println!(
+            "- Instructions in the {} trace: {}",
+            name,
+            instructions(trace_instructions)
+        );
+    }
+    let only_first = diff
+        .branch_diffs
+        .values()
+        .filter(|diff| diff.execution_count[1] == 0)
+        .count();
+    let only_second = diff
+        .branch_diffs
+        .values()
+        .filter(|diff| diff.execution_count[0] == 0)
+        .count();
+    let different = diff
+        .branch_diffs
+        .values()
+        .filter(|diff| {
+            diff.execution_count[0] != diff.execution_count[1]
+                || diff.taken_count[0] != diff.taken_count[1]
+        })
+        .count();
+    println!("- Branches only in the first trace: {}", only_first);
+    println!("- Branches only in the second trace: {}", only_second);
+    println!(
+        "- Branches with different execution or taken counts: {} out of {}",
+        different,
+        diff.branch_diffs.len()
+    );
+
+    println!("Top branches by execution and taken count difference:");
+    let mut items: Vec<(&(u64, u64), &BranchDiff)> = diff.branch_diffs.iter().collect();
+    items.sort_by_key(|(_, diff)| {
+        (
+            diff.execution_count[0].abs_diff(diff.execution_count[1]),
+            diff.taken_count[0].abs_diff(diff.taken_count[1]),
+        )
+    });
+    let mut table = vec![];
+    for ((inst_addr, targ_addr), diff) in items.iter().rev().take(args.top) {
+        table.push(vec![
+            format!("0x{:08x}", inst_addr).cell(),
+            format!("0x{:08x}", targ_addr).cell(),
+            diff.execution_count[0].cell(),
+            diff.execution_count[1].cell(),
+            diff.taken_count[0].cell(),
+            diff.taken_count[1].cell(),
+            first_symbolizer.lookup(*inst_addr).to_string().cell(),
+        ]);
+    }
+    let table = table.table().title(vec![
+        "Branch PC".cell(),
+        "Target".cell(),
+        "Execution Count (First)".cell(),
+        "Execution Count (Second)".cell(),
+        "Taken Count (First)".cell(),
+        "Taken Count (Second)".cell(),
+        "Location".cell(),
+    ]);
+    print_stdout(table)?;
+
+    Ok(())
+}
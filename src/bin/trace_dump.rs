@@ -0,0 +1,207 @@
+//! Print the branches of trace file in text format, or convert the text format back to trace file
+use cbp_experiments::{
+    DumpLine, Symbolizer, TraceFileDecoder, TraceFileEncoder, TraceFileSource, TraceSource,
+    import_trace_dump, read_trace_file,
+};
+use clap::{Parser, Subcommand};
+use std::{
+    fs::File,
+    io::{BufReader, BufWriter, ErrorKind, Write},
+    path::PathBuf,
+};
+
+#[derive(Parser)]
+#[command(version, about, long_about = None)]
+struct Cli {
+    #[command(subcommand)]
+    command: Commands,
+}
+
+#[derive(Subcommand)]
+enum Commands {
+    /// Print dynamic branches of trace file
+    Dump {
+        /// Path to trace file
+        #[arg(short, long)]
+        trace_path: PathBuf,
+
+        /// Print branches from this instruction count
+        #[arg(short, long)]
+        start: Option<u64>,
+
+        /// Stop printing at this instruction count, exclusive
+        #[arg(short, long)]
+        end: Option<u64>,
+
+        /// Only print branches in images whose filename contains this string
+        #[arg(short, long)]
+        image: Option<String>,
+
+        /// Only print branches in functions whose symbol contains this string
+        #[arg(long)]
+        symbol: Option<String>,
+    },
+    /// Convert text format to trace file
+    Import {
+        /// Path to text file
+        #[arg(short, long)]
+        input_path: PathBuf,
+
+        /// Path to output trace file
+        #[arg(short, long)]
+        output_path: PathBuf,
+    },
+}
+
+fn dump(
+    trace_path: PathBuf,
+    start: Option<u64>,
+    end: Option<u64>,
+    image: Option<String>,
+    symbol: Option<String>,
+) -> anyhow::Result<()> {
+    let content = read_trace_file(&trace_path)?;
+    let file = TraceFileDecoder::open_with_path(&content, &trace_path)?;
+    // (start, len, filename) of each image, available even if the image data is not
+    let images = file
+        .images
+        .iter()
+        .map(|image| Ok((image.start, image.len, image.get_filename()?)))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    let mut out = BufWriter::new(std::io::stdout().lock());
+    writeln!(
+        out,
+        "# {} branches, {} entries from {}",
+        file.num_branches,
+        file.num_entries,
+        trace_path.display()
+    )?;
+    for (start, len, filename) in &images {
+        writeln!(
+            out,
+            "# image 0x{:x}-0x{:x} {}",
+            start,
+            start + len,
+            filename
+        )?;
+    }
+    // images may be unavailable, e.g. missing from the image store
+    let mut symbolizer = match file.get_images() {
+        Ok(images) => Symbolizer::new(&images),
+        Err(err) => {
+            writeln!(out, "# symbols are unavailable: {}", err)?;
+            Symbolizer::new(&[])
+        }
+    };
+    writeln!(
+        out,
+        "# instructions pc type taken target length # symbol -> target symbol"
+    )?;
+
+    // instruction counting requires embedded instruction counts or images
+    let mut source = TraceFileSource::new(&file, start.unwrap_or(0), 0)?;
+    let can_count = source.has_inst_counts();
+    anyhow::ensure!(
+        can_count || (start.is_none() && end.is_none()),
+        "Instruction range requires instruction counts"
+    );
+
+    // filter and symbols of each static branch, computed lazily
+    let mut branch_infos: Vec<Option<(bool, String)>> = vec![None; file.num_branches];
+    while let Some(event) = source.next_event()? {
+        let instructions = event.instructions;
+        if let Some(end) = end
+            && instructions >= end
+        {
+            break;
+        }
+        if let Some(start) = start
+            && instructions < start
+        {
+            continue;
+        }
+
+        let (selected, comment) = branch_infos[event.br_index].get_or_insert_with(|| {
+            let inst_symbol = symbolizer.symbolize(event.inst_addr);
+            let targ_symbol = symbolizer.symbolize(event.targ_addr);
+            let in_image = match &image {
+                Some(image) => images.iter().any(|(start, len, filename)| {
+                    filename.contains(image.as_str())
+                        && event.inst_addr >= *start
+                        && event.inst_addr < start + len
+                }),
+                None => true,
+            };
+            let in_symbol = match &symbol {
+                Some(symbol) => inst_symbol
+                    .as_ref()
+                    .is_some_and(|name| name.contains(symbol.as_str())),
+                None => true,
+            };
+            (
+                in_image && in_symbol,
+                format!(
+                    "{} -> {}",
+                    inst_symbol.as_deref().unwrap_or("?"),
+                    targ_symbol.as_deref().unwrap_or("?")
+                ),
+            )
+        });
+        if !*selected {
+            continue;
+        }
+
+        let line = DumpLine {
+            instructions: can_count.then_some(instructions),
+            inst_addr: event.inst_addr,
+            targ_addr: event.targ_addr,
+            inst_length: event.inst_length,
+            branch_type: event.branch_type,
+            taken: event.taken,
+        };
+        writeln!(out, "{} # {}", line.format(), comment)?;
+    }
+    out.flush()?;
+    Ok(())
+}
+
+fn main() -> anyhow::Result<()> {
+    let args = Cli::parse();
+
+    match args.command {
+        Commands::Dump {
+            trace_path,
+            start,
+            end,
+            image,
+            symbol,
+        } => {
+            if let Err(err) = dump(trace_path, start, end, image, symbol) {
+                // stop silently when piped to head etc.
+                if let Some(io_err) = err.downcast_ref::<std::io::Error>()
+                    && io_err.kind() == ErrorKind::BrokenPipe
+                {
+                    return Ok(());
+                }
+                return Err(err);
+            }
+        }
+        Commands::Import {
+            input_path,
+            output_path,
+        } => {
+            let output_file = File::create(&output_path)?;
+            let mut encoder = TraceFileEncoder::open(&output_file)?;
+            import_trace_dump(BufReader::new(File::open(&input_path)?), &mut encoder)?;
+            println!(
+                "Imported {} branches and {} entries from {}",
+                encoder.branches.len(),
+                encoder.num_entries,
+                input_path.display()
+            );
+            encoder.finish()?;
+        }
+    }
+    Ok(())
+}
@@ -1,41 +1,49 @@
 //! Display info and statistics of trace file
 use cbp_experiments::{
-    Branch, BranchType, TraceFileDecoder, create_inst_index_mapping_from_images, get_inst_index,
-    get_tqdm_style,
+    Branch, BranchType, Entry, EntryEncoding, EntrySizeEstimator, Symbolizer, TraceFileDecoder,
+    TraceFileSource, TraceSource, get_tqdm_style, read_trace_file,
 };
 use clap::Parser;
 use cli_table::{Cell, Table, print_stdout};
 use log::{Level, log_enabled, trace};
-use memmap::MmapOptions;
 use size::Size;
-use std::collections::HashMap;
-use std::fs::File;
 use std::path::PathBuf;
 
+/// background threads decoding the entries
+const DECODE_THREADS: usize = 2;
+
 #[derive(Parser)]
 #[command(version, about, long_about = None)]
 struct Cli {
-    /// Path to trace file
+    /// Path to trace file, can be a pipe, e.g. /dev/stdin
     #[arg(short, long)]
     trace_path: PathBuf,
+
+    /// Decode all entries and validate the trace file, instead of computing statistics
+    #[arg(short, long)]
+    verify: bool,
 }
 
 #[derive(Debug, Clone, Copy, Default)]
 pub struct BranchInfo {
     execution_count: u64,
     taken_count: u64,
-    inst_addr_index: u64,
-    targ_addr_index: u64,
+}
+
+fn encoding_name(encoding: EntryEncoding) -> &'static str {
+    match encoding {
+        EntryEncoding::Fixed => "fixed-width",
+        EntryEncoding::Varint => "variable-width",
+    }
 }
 
 fn main() -> anyhow::Result<()> {
     env_logger::init();
 
     let args = Cli::parse();
-    let file = File::open(&args.trace_path)?;
-    let content = unsafe { MmapOptions::new().map(&file)? };
+    let content = read_trace_file(&args.trace_path)?;
     // parse trace file
-    let file = TraceFileDecoder::open(&content);
+    let file = TraceFileDecoder::open_with_path(&content, &args.trace_path)?;
     println!(
         "Got {} branches, {}({:.2e}, {:.2} bit/entry) entries and {} images from {} trace",
         file.num_branches,
@@ -46,19 +54,74 @@ fn main() -> anyhow::Result<()> {
         Size::from_bytes(content.len())
     );
 
-    println!("Loaded images:");
-    for image in file.images.iter() {
+    if file.chunks.is_empty() {
+        println!("Trace version {}, not chunked", file.version);
+    } else {
+        println!(
+            "Trace version {}, {} chunks, {}",
+            file.version,
+            file.num_chunks,
+            if file.is_seekable() {
+                "seekable by instruction count"
+            } else {
+                "instruction counts unavailable"
+            }
+        );
+    }
+    println!(
+        "Entries are {}, taking {}",
+        encoding_name(file.entry_encoding),
+        Size::from_bytes(file.compressed_entries.len())
+    );
+    if file.has_inst_counts() {
+        println!("Instruction counts are embedded");
+    }
+    if !file.thread_segments.is_empty() {
         println!(
-            "Image {} ({} bytes) loaded to 0x{:x}-0x{:x}",
-            image.get_filename()?,
-            image.data_size,
-            image.start,
-            image.start + image.len
+            "Got {} threads in {} segments",
+            file.thread_ids().len(),
+            file.thread_segments.len()
         );
     }
 
+    if !file.metadata.is_empty() {
+        println!("Metadata:");
+        for (key, value) in &file.metadata {
+            println!("- {}: {}", key, value);
+        }
+    }
+
+    if args.verify {
+        println!("Verifying {} entries", file.num_entries);
+        file.verify()?;
+        println!("Trace file is valid");
+        return Ok(());
+    }
+
+    println!("Loaded images:");
+    for (i, image) in file.images.iter().enumerate() {
+        if file.is_image_stored(i) {
+            println!(
+                "Image {} ({} bytes in image store as {}) loaded to 0x{:x}-0x{:x}",
+                image.get_filename()?,
+                file.image_refs[i].size,
+                file.image_refs[i].file_name(),
+                image.start,
+                image.start + image.len
+            );
+        } else {
+            println!(
+                "Image {} ({} bytes) loaded to 0x{:x}-0x{:x}",
+                image.get_filename()?,
+                image.data_size,
+                image.start,
+                image.start + image.len
+            );
+        }
+    }
+
     let mut branch_type_counts = [0usize; BranchType::Invalid.repr as usize];
-    for branch in file.branches {
+    for branch in file.branches.iter() {
         branch_type_counts[branch.branch_type.repr as usize] += 1;
     }
 
@@ -88,62 +151,65 @@ fn main() -> anyhow::Result<()> {
         branch_type_counts[BranchType::ConditionalDirectJump.repr as usize]
     );
 
-    // create a mapping from instruction address to instruction index for instruction counting
-    let file_images = file.get_images()?;
-    let mapping: HashMap<u64, u64> = create_inst_index_mapping_from_images(&file_images)?;
-
     let mut branch_infos = vec![BranchInfo::default(); file.num_branches];
 
-    // preprocess instruction indices for all branches
-    for (i, branch) in file.branches.iter().enumerate() {
-        branch_infos[i].inst_addr_index = get_inst_index(&mapping, branch.inst_addr);
-        branch_infos[i].targ_addr_index = get_inst_index(&mapping, branch.targ_addr);
+    // use embedded instruction counts if available, otherwise disassemble the images
+    let mut source = TraceFileSource::new(&file, 0, DECODE_THREADS)?;
+    if !source.has_inst_counts() {
+        println!("Instruction counts are unavailable");
     }
 
+    // compare with the other encoding
+    let other_encoding = match file.entry_encoding {
+        EntryEncoding::Fixed => EntryEncoding::Varint,
+        EntryEncoding::Varint => EntryEncoding::Fixed,
+    };
+    let mut estimator = Some(EntrySizeEstimator::new(other_encoding));
+
+    // images may be unavailable, e.g. missing from the image store
+    let mut symbolizer = match file.get_images() {
+        Ok(images) => Symbolizer::new(&images),
+        Err(err) => {
+            println!("Symbols are unavailable: {}", err);
+            Symbolizer::new(&[])
+        }
+    };
+
     println!("Iterating entries");
     let pbar = indicatif::ProgressBar::new(file.num_entries as u64);
     pbar.set_style(get_tqdm_style());
-    let mut last_targ_addr_index = None;
-    let mut instructions = 0;
-    for entries in file.entries()? {
-        for entry in entries {
-            let br_index = entry.get_br_index();
-            let taken = entry.get_taken();
-            branch_infos[br_index].execution_count += 1;
-            branch_infos[br_index].taken_count += taken as u64;
-
-            if log_enabled!(Level::Trace) {
-                let pc = file.branches[br_index].inst_addr;
-                let mut addr = format!("unknown:0x{:x}", pc);
-                for image in file.images {
-                    if pc >= image.start && pc < image.start + image.len {
-                        addr =
-                            format!("{}:0x{:x}", image.get_filename().unwrap(), pc - image.start);
-                    }
-                }
-                trace!(
-                    "PC = 0x{:x} ({}) {}",
-                    pc,
-                    addr,
-                    if taken { "T" } else { "N" }
-                );
-            }
+    let mut num_events = 0u64;
+    while let Some(event) = source.next_event()? {
+        branch_infos[event.br_index].execution_count += 1;
+        branch_infos[event.br_index].taken_count += event.taken as u64;
+        if let Some(current) = &mut estimator
+            && let Err(err) = current.push(Entry::from(event.br_index, event.taken))
+        {
+            println!(
+                "Entries cannot be {}: {}",
+                encoding_name(other_encoding),
+                err
+            );
+            estimator = None;
+        }
 
-            // instruction counting
-            if taken {
-                let curr_index = branch_infos[br_index].inst_addr_index;
-                if let Some(last_index) = last_targ_addr_index {
-                    // count instructions from last target address to the current branch address
-                    assert!(curr_index >= last_index);
-                    instructions += curr_index - last_index + 1;
-                }
-                last_targ_addr_index = Some(branch_infos[br_index].targ_addr_index);
-            }
+        if log_enabled!(Level::Trace) {
+            let pc = event.inst_addr;
+            trace!(
+                "PC = 0x{:x} ({}) {}",
+                pc,
+                symbolizer.lookup(pc),
+                if event.taken { "T" } else { "N" }
+            );
         }
 
-        pbar.inc(entries.len() as u64);
+        num_events += 1;
+        if num_events.is_multiple_of(1024 * 1024) {
+            pbar.set_position(num_events);
+        }
     }
     pbar.finish();
+    let instructions = source.instructions();
 
     // accuracy: on a trimmed leela test (5% of total)
     // perf stat reported: 110979252909 instructions
@@ -152,12 +218,27 @@ fn main() -> anyhow::Result<()> {
     // slow down of counting instructions: 18s -> 38s, roughly 2x
     println!("Executed {} instructions", instructions);
 
+    if let Some(estimator) = estimator {
+        let size = estimator.finish()?;
+        println!(
+            "Entries would take {} ({:.2} bit/entry) if {}, {:.1}% of the current {} ({:.2} bit/entry)",
+            Size::from_bytes(size),
+            size as f64 * 8.0 / file.num_entries as f64,
+            encoding_name(other_encoding),
+            size as f64 * 100.0 / file.compressed_entries.len() as f64,
+            Size::from_bytes(file.compressed_entries.len()),
+            file.compressed_entries.len() as f64 * 8.0 / file.num_entries as f64,
+        );
+    }
+
     println!("Top branches by execution count:");
-    let mut items: Vec<(&BranchInfo, &Branch)> = branch_infos.iter().zip(file.branches).collect();
+    let mut items: Vec<(&BranchInfo, &Branch)> =
+        branch_infos.iter().zip(file.branches.iter()).collect();
 
     items.sort_by_key(|(info, _)| info.execution_count);
     let mut table = vec![];
     for (info, branch) in items.iter().rev().take(10) {
+        let location = symbolizer.lookup(branch.inst_addr);
         table.push(vec![
             format!("0x{:08x}", branch.inst_addr).cell(),
             format!("{:?}", branch.branch_type).cell(),
@@ -167,7 +248,12 @@ fn main() -> anyhow::Result<()> {
                 info.taken_count as f64 * 100.0 / info.execution_count as f64
             )
             .cell(),
-            file.get_addr_location(branch.inst_addr)?.cell(),
+            location.image_offset().cell(),
+            location.symbol.as_deref().unwrap_or("unknown").cell(),
+            location
+                .source_location()
+                .unwrap_or_else(|| "unknown".to_string())
+                .cell(),
         ]);
     }
     let table = table.table().title(vec![
@@ -176,6 +262,8 @@ fn main() -> anyhow::Result<()> {
         "Execution Count".cell(),
         "Taken Rate (%)".cell(),
         "Image & Offset".cell(),
+        "Symbol".cell(),
+        "Source Location".cell(),
     ]);
     print_stdout(table)?;
 
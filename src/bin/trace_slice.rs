@@ -0,0 +1,68 @@
+//! Extract an instruction range of trace file into a new trace file
+use cbp_experiments::{TraceFileDecoder, read_trace_file, slice_trace};
+use clap::Parser;
+use std::path::PathBuf;
+
+#[derive(Parser)]
+#[command(version, about, long_about = None)]
+struct Cli {
+    /// Path to trace file
+    #[arg(short, long)]
+    trace_path: PathBuf,
+
+    /// Path to output trace file
+    #[arg(short, long)]
+    output_path: PathBuf,
+
+    /// First instruction of the slice
+    #[arg(short, long)]
+    start: u64,
+
+    /// End of the slice in instructions, exclusive
+    #[arg(short, long)]
+    end: u64,
+
+    /// Warmup count in instructions, prepended to the slice
+    #[arg(short, long, default_value = "0")]
+    warmup: u64,
+}
+
+fn main() -> anyhow::Result<()> {
+    let args = Cli::parse();
+    anyhow::ensure!(
+        args.start < args.end,
+        "Slice start {} is not before end {}",
+        args.start,
+        args.end
+    );
+
+    let content = read_trace_file(&args.trace_path)?;
+    let file = TraceFileDecoder::open_with_path(&content, &args.trace_path)?;
+    println!(
+        "Got {} branches, {} entries and {} images",
+        file.num_branches, file.num_entries, file.num_images
+    );
+    if !file.is_seekable() {
+        println!("Trace is not seekable, decoding from the beginning");
+    }
+
+    println!(
+        "Extracting instructions [{}, {}) with {} warmup instructions to {}",
+        args.start,
+        args.end,
+        args.warmup,
+        args.output_path.display()
+    );
+    slice_trace(&file, &args.output_path, args.start, args.end, args.warmup)?;
+
+    // the output may be a pipe
+    if std::fs::metadata(&args.output_path)?.is_file() {
+        let output_content = read_trace_file(&args.output_path)?;
+        let output = TraceFileDecoder::open_with_path(&output_content, &args.output_path)?;
+        println!(
+            "Slice contains {} branches, {} entries and {} images",
+            output.num_branches, output.num_entries, output.num_images
+        );
+    }
+    Ok(())
+}
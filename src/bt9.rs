@@ -0,0 +1,505 @@
+//! CBP2016 BT9 trace format: a text format of a branch graph and a sequence of edges.
+//!
+//! Each node is a static branch, each edge is an execution of its source node,
+//! with the outcome, target and the count of non-branch instructions until the destination node.
+//! Node 0 is a placeholder for the beginning of trace.
+use crate::{
+    BranchEvent, BranchIndexer, BranchType, METADATA_TRACER, TraceFileDecoder, TraceFileEncoder,
+    TraceFileSource, TraceSource, import_source,
+};
+use anyhow::{Context, bail};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    io::{BufRead, Lines, Write},
+};
+
+/// Static branch of BT9 trace
+#[derive(Debug, Clone, Copy)]
+struct Bt9Node {
+    addr: u64,
+    size: u32,
+    /// None for node 0
+    branch_type: Option<BranchType>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Bt9Edge {
+    src: usize,
+    taken: bool,
+    target: u64,
+    inst_cnt: u64,
+}
+
+/// Parse hex number with optional 0x prefix
+fn parse_hex(s: &str) -> anyhow::Result<u64> {
+    let s = s.strip_prefix("0x").unwrap_or(s);
+    u64::from_str_radix(s, 16).with_context(|| format!("Invalid hex number {}", s))
+}
+
+/// Map branch class, e.g. JMP+DIR+CND, to branch type.
+/// Conditional calls, returns and indirect jumps are mapped to the unconditional ones.
+fn parse_class(class: &str) -> anyhow::Result<BranchType> {
+    let tokens: Vec<&str> = class.split('+').collect();
+    let indirect = tokens.contains(&"IND");
+    let conditional = tokens.contains(&"CND");
+    Ok(match tokens.first() {
+        Some(&"RET") => BranchType::Return,
+        Some(&"CALL") if indirect => BranchType::IndirectCall,
+        Some(&"CALL") => BranchType::DirectCall,
+        Some(&"JMP") if indirect => BranchType::IndirectJump,
+        Some(&"JMP") if conditional => BranchType::ConditionalDirectJump,
+        Some(&"JMP") => BranchType::DirectJump,
+        _ => bail!("Unsupported branch class {}", class),
+    })
+}
+
+fn format_class(branch_type: BranchType) -> anyhow::Result<(&'static str, &'static str)> {
+    // (class, behavior)
+    Ok(match branch_type {
+        BranchType::DirectJump => ("JMP+DIR+UCD", "DIR+UCD"),
+        BranchType::IndirectJump => ("JMP+IND+UCD", "IND+UCD"),
+        BranchType::DirectCall => ("CALL+DIR+UCD", "DIR+UCD"),
+        BranchType::IndirectCall => ("CALL+IND+UCD", "IND+UCD"),
+        BranchType::Return => ("RET+IND+UCD", "IND+UCD"),
+        BranchType::ConditionalDirectJump => ("JMP+DIR+CND", "DIR+CND"),
+        _ => bail!("Unsupported branch type {:?}", branch_type),
+    })
+}
+
+#[derive(PartialEq)]
+enum Bt9Section {
+    Header,
+    Nodes,
+    Edges,
+    Sequence,
+}
+
+/// Events of BT9 trace, using the instruction counts in BT9.
+/// The header fields are kept in the metadata with bt9. prefix.
+pub struct Bt9Source<R: BufRead> {
+    lines: Lines<R>,
+    line_index: usize,
+    metadata: BTreeMap<String, String>,
+    nodes: Vec<Option<Bt9Node>>,
+    edges: Vec<Option<Bt9Edge>>,
+    // taken target of conditional branches, used as the target of not taken edges
+    taken_targets: HashMap<usize, u64>,
+    indexer: BranchIndexer,
+    // instructions since the last taken branch
+    pending: u64,
+    instructions: u64,
+}
+
+impl<R: BufRead> Bt9Source<R> {
+    /// Parse the header, nodes and edges, until the edge sequence
+    pub fn new(reader: R) -> anyhow::Result<Self> {
+        let mut source = Self {
+            lines: reader.lines(),
+            line_index: 0,
+            metadata: BTreeMap::new(),
+            nodes: vec![],
+            edges: vec![],
+            taken_targets: HashMap::new(),
+            indexer: BranchIndexer::default(),
+            pending: 0,
+            instructions: 0,
+        };
+        source
+            .metadata
+            .insert(METADATA_TRACER.to_string(), "bt9".to_string());
+
+        let mut section = Bt9Section::Header;
+        while section != Bt9Section::Sequence {
+            let Some(line) = source.next_line()? else {
+                bail!("BT9 trace ends without EOF");
+            };
+            let context = || format!("Failed to parse line {}: {}", source.line_index, line);
+
+            match line.as_str() {
+                "BT9_NODES" => section = Bt9Section::Nodes,
+                "BT9_EDGES" => section = Bt9Section::Edges,
+                "BT9_EDGE_SEQUENCE" => section = Bt9Section::Sequence,
+                _ => match section {
+                    Bt9Section::Header => {
+                        if let Some((key, value)) = line.split_once(':') {
+                            source
+                                .metadata
+                                .insert(format!("bt9.{}", key.trim()), value.trim().to_string());
+                        }
+                    }
+                    Bt9Section::Nodes => {
+                        // NODE id virtual_address physical_address opcode size class: ... behavior: ...
+                        let tokens: Vec<&str> = line.split_whitespace().collect();
+                        if tokens.len() < 6 || tokens[0] != "NODE" {
+                            bail!("{}", context());
+                        }
+                        let id: usize = tokens[1].parse().with_context(context)?;
+                        let branch_type = match tokens.iter().position(|token| *token == "class:") {
+                            Some(index) => Some(
+                                parse_class(tokens.get(index + 1).copied().unwrap_or_default())
+                                    .with_context(context)?,
+                            ),
+                            None => None,
+                        };
+                        if id >= source.nodes.len() {
+                            source.nodes.resize(id + 1, None);
+                        }
+                        source.nodes[id] = Some(Bt9Node {
+                            addr: parse_hex(tokens[2]).with_context(context)?,
+                            size: tokens[5].parse().with_context(context)?,
+                            branch_type,
+                        });
+                    }
+                    Bt9Section::Edges => {
+                        // EDGE id src_id dest_id taken br_virt_target br_phy_target inst_cnt traverse_cnt
+                        let tokens: Vec<&str> = line.split_whitespace().collect();
+                        if tokens.len() < 8 || tokens[0] != "EDGE" {
+                            bail!("{}", context());
+                        }
+                        let id: usize = tokens[1].parse().with_context(context)?;
+                        if id >= source.edges.len() {
+                            source.edges.resize(id + 1, None);
+                        }
+                        source.edges[id] = Some(Bt9Edge {
+                            src: tokens[2].parse().with_context(context)?,
+                            taken: tokens[4] == "T",
+                            target: parse_hex(tokens[5]).with_context(context)?,
+                            inst_cnt: tokens[7].parse().with_context(context)?,
+                        });
+                    }
+                    Bt9Section::Sequence => unreachable!(),
+                },
+            }
+        }
+
+        for edge in source.edges.iter().flatten() {
+            if edge.taken
+                && let Some(Some(node)) = source.nodes.get(edge.src)
+                && node.branch_type == Some(BranchType::ConditionalDirectJump)
+            {
+                source.taken_targets.insert(edge.src, edge.target);
+            }
+        }
+        Ok(source)
+    }
+
+    /// Next line that is not empty or a comment
+    fn next_line(&mut self) -> anyhow::Result<Option<String>> {
+        for line in self.lines.by_ref() {
+            self.line_index += 1;
+            let line = line?;
+            let line = line.trim();
+            if !line.is_empty() && !line.starts_with('#') {
+                return Ok(Some(line.to_string()));
+            }
+        }
+        Ok(None)
+    }
+}
+
+impl<R: BufRead> TraceSource for Bt9Source<R> {
+    fn next_event(&mut self) -> anyhow::Result<Option<BranchEvent>> {
+        loop {
+            let Some(line) = self.next_line()? else {
+                bail!("BT9 trace ends without EOF");
+            };
+            if line == "EOF" {
+                return Ok(None);
+            }
+            let id: usize = line
+                .parse()
+                .with_context(|| format!("Failed to parse line {}: {}", self.line_index, line))?;
+            let Some(Some(edge)) = self.edges.get(id) else {
+                bail!("Edge {} in sequence is not defined", id);
+            };
+            let Some(Some(node)) = self.nodes.get(edge.src) else {
+                bail!("Node {} of edge {} is not defined", edge.src, id);
+            };
+            let Some(branch_type) = node.branch_type else {
+                // beginning of trace
+                self.pending += edge.inst_cnt;
+                continue;
+            };
+
+            let targ_addr = if edge.taken {
+                edge.target
+            } else {
+                *self.taken_targets.get(&edge.src).unwrap_or(&edge.target)
+            };
+            let (br_index, targ_addr) =
+                self.indexer
+                    .index(node.addr, targ_addr, branch_type, edge.taken);
+            self.pending += 1;
+            let mut new_instructions = 0;
+            if edge.taken {
+                new_instructions = self.pending;
+                self.instructions += new_instructions;
+                self.pending = edge.inst_cnt;
+            } else {
+                self.pending += edge.inst_cnt;
+            }
+            return Ok(Some(BranchEvent {
+                br_index,
+                inst_addr: node.addr,
+                targ_addr,
+                inst_length: node.size,
+                branch_type,
+                taken: edge.taken,
+                new_instructions,
+                instructions: self.instructions,
+                thread_id: 0,
+            }));
+        }
+    }
+
+    fn has_inst_counts(&self) -> bool {
+        true
+    }
+
+    fn metadata(&self) -> BTreeMap<String, String> {
+        self.metadata.clone()
+    }
+}
+
+/// Convert BT9 trace to our trace format, see Bt9Source
+pub fn import_bt9(reader: impl BufRead, encoder: &mut TraceFileEncoder) -> anyhow::Result<()> {
+    import_source(&mut Bt9Source::new(reader)?, encoder)
+}
+
+/// Source, destination, taken, target and instruction count of an edge
+type EdgeKey = (Option<usize>, Option<usize>, bool, u64, u64);
+
+/// Walk the trace and generate BT9 edges, with branch index as node, None for node 0.
+/// The instructions executed at each taken branch are attributed to the edge
+/// after the previous taken branch, since the positions of not taken branches are unknown.
+/// Returns the total instruction count.
+fn walk_edges(decoder: &TraceFileDecoder, mut f: impl FnMut(EdgeKey)) -> anyhow::Result<u64> {
+    let mut source = TraceFileSource::new(decoder, 0, 0)?;
+    if !source.has_inst_counts() {
+        bail!("Instruction counts are required to export BT9 trace");
+    }
+
+    let edge = |(src, taken): (Option<usize>, bool), dest: Option<usize>, inst_cnt: u64| {
+        let target = match src {
+            Some(br_index) => decoder.branches[br_index].targ_addr,
+            None => 0,
+        };
+        (src, dest, taken, target, inst_cnt)
+    };
+
+    // events since the last taken branch, beginning with the last taken branch
+    let mut run: Vec<(Option<usize>, bool)> = vec![(None, false)];
+    while let Some(event) = source.next_event()? {
+        run.push((Some(event.br_index), event.taken));
+        if event.taken {
+            let non_branch_insts = event.new_instructions.saturating_sub(run.len() as u64 - 1);
+            for i in 0..run.len() - 1 {
+                f(edge(
+                    run[i],
+                    run[i + 1].0,
+                    if i == 0 { non_branch_insts } else { 0 },
+                ));
+            }
+            run = vec![(Some(event.br_index), true)];
+        }
+    }
+
+    // the last branch goes back to node 0
+    if run.len() > 1 || run[0].0.is_some() {
+        run.push((None, false));
+        for i in 0..run.len() - 1 {
+            f(edge(run[i], run[i + 1].0, 0));
+        }
+    }
+    Ok(source.instructions())
+}
+
+/// Convert our trace format to BT9 trace, it takes two passes over the trace
+pub fn export_bt9(decoder: &TraceFileDecoder, mut writer: impl Write) -> anyhow::Result<()> {
+    // first pass: collect nodes and edges
+    // node id of each branch address, node 0 is reserved
+    let mut node_ids: HashMap<u64, usize> = HashMap::new();
+    // (branch index, taken count, not taken count, taken targets) of each node
+    let mut nodes: Vec<(usize, u64, u64, HashSet<u64>)> = vec![];
+    let mut edge_ids: HashMap<EdgeKey, usize> = HashMap::new();
+    // (edge, traverse count)
+    let mut edges: Vec<(EdgeKey, u64)> = vec![];
+    let mut num_branches = 0u64;
+    let get_node_id = |node_ids: &mut HashMap<u64, usize>,
+                       nodes: &mut Vec<(usize, u64, u64, HashSet<u64>)>,
+                       br_index: Option<usize>| {
+        let br_index = br_index?;
+        let addr = decoder.branches[br_index].inst_addr;
+        Some(*node_ids.entry(addr).or_insert_with(|| {
+            nodes.push((br_index, 0, 0, HashSet::new()));
+            nodes.len()
+        }))
+    };
+    let total_instructions = walk_edges(decoder, |key| {
+        let (src, dest, taken, target, _) = key;
+        if let Some(node_id) = get_node_id(&mut node_ids, &mut nodes, src) {
+            let node = &mut nodes[node_id - 1];
+            if taken {
+                node.1 += 1;
+                node.3.insert(target);
+            } else {
+                node.2 += 1;
+            }
+            num_branches += 1;
+        }
+        get_node_id(&mut node_ids, &mut nodes, dest);
+        let id = *edge_ids.entry(key).or_insert_with(|| {
+            edges.push((key, 0));
+            edges.len() - 1
+        });
+        edges[id].1 += 1;
+    })?;
+
+    writeln!(writer, "BT9_SPA_TRACE_FORMAT")?;
+    writeln!(writer, "bt9_minor_version: 0")?;
+    writeln!(writer, "has_physical_address: 0")?;
+    writeln!(writer, "md5_checksum: 0")?;
+    writeln!(
+        writer,
+        "conversion_date: {}",
+        chrono::Local::now().to_rfc3339()
+    )?;
+    writeln!(writer, "original_stf_input_file: -")?;
+    writeln!(writer, "total_instruction_count: {}", total_instructions)?;
+    writeln!(writer, "branch_instruction_count: {}", num_branches)?;
+    writeln!(writer, "invalid_physical_branch_target_count: 0")?;
+    writeln!(writer, "A32_instruction_count: 0")?;
+    writeln!(writer, "A64_instruction_count: 0")?;
+    writeln!(writer, "T32_instruction_count: 0")?;
+    writeln!(
+        writer,
+        "unidentified_instruction_count: {}",
+        total_instructions
+    )?;
+
+    writeln!(writer, "BT9_NODES")?;
+    writeln!(
+        writer,
+        "#NODE id virtual_address physical_address opcode size"
+    )?;
+    writeln!(writer, "NODE 0 0 - 0 0")?;
+    for (i, (br_index, taken_cnt, not_taken_cnt, targets)) in nodes.iter().enumerate() {
+        let branch = &decoder.branches[*br_index];
+        let (class, behavior) = format_class(branch.branch_type)?;
+        writeln!(
+            writer,
+            "NODE {} 0x{:016x} - 0 {} class: {} behavior: {} taken_cnt: {} not_taken_cnt: {} tgt_cnt: {}",
+            i + 1,
+            branch.inst_addr,
+            branch.inst_length,
+            class,
+            behavior,
+            taken_cnt,
+            not_taken_cnt,
+            targets.len()
+        )?;
+    }
+
+    writeln!(writer, "BT9_EDGES")?;
+    writeln!(
+        writer,
+        "#EDGE id src_id dest_id taken br_virt_target br_phy_target inst_cnt traverse_cnt"
+    )?;
+    let node_id = |br_index: Option<usize>| match br_index {
+        Some(br_index) => node_ids[&decoder.branches[br_index].inst_addr],
+        None => 0,
+    };
+    for (i, ((src, dest, taken, target, inst_cnt), traverse_cnt)) in edges.iter().enumerate() {
+        writeln!(
+            writer,
+            "EDGE {} {} {} {} 0x{:016x} - {} {}",
+            i,
+            node_id(*src),
+            node_id(*dest),
+            if *taken { "T" } else { "N" },
+            target,
+            inst_cnt,
+            traverse_cnt
+        )?;
+    }
+
+    // second pass: write edge sequence
+    writeln!(writer, "BT9_EDGE_SEQUENCE")?;
+    let mut result = Ok(());
+    walk_edges(decoder, |key| {
+        if result.is_ok() {
+            result = writeln!(writer, "{}", edge_ids[&key]);
+        }
+    })?;
+    result?;
+    writeln!(writer, "EOF")?;
+    writer.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::format_class;
+    use crate::{Branch, BranchType, TraceFileDecoder, TraceFileEncoder, export_bt9, import_bt9};
+
+    #[test]
+    fn test_format_class() {
+        assert_eq!(
+            format_class(BranchType::Return).unwrap(),
+            ("RET+IND+UCD", "IND+UCD")
+        );
+        assert!(format_class(BranchType::Invalid).is_err());
+    }
+
+    #[test]
+    fn test_bt9_roundtrip() {
+        let mut content = vec![];
+        let mut encoder = TraceFileEncoder::open_stream(&mut content).unwrap();
+        encoder.branches = vec![
+            Branch {
+                inst_addr: 0x1000,
+                targ_addr: 0x2000,
+                inst_length: 2,
+                branch_type: BranchType::ConditionalDirectJump,
+            },
+            Branch {
+                inst_addr: 0x2010,
+                targ_addr: 0x1000,
+                inst_length: 5,
+                branch_type: BranchType::DirectJump,
+            },
+        ];
+        let mut total = 0;
+        for i in 0..1000 {
+            let taken = i % 3 != 0;
+            encoder.record_event_with_inst_count(0, taken, 3).unwrap();
+            total += taken as u64 * 3;
+            if taken {
+                encoder.record_event_with_inst_count(1, true, 5).unwrap();
+                total += 5;
+            }
+        }
+        encoder.finish().unwrap();
+        let decoder = TraceFileDecoder::open(&content).unwrap();
+
+        let mut bt9 = vec![];
+        export_bt9(&decoder, &mut bt9).unwrap();
+        let bt9 = String::from_utf8(bt9).unwrap();
+        assert!(bt9.contains(&format!("total_instruction_count: {}", total)));
+
+        let mut imported = vec![];
+        let mut encoder = TraceFileEncoder::open_stream(&mut imported).unwrap();
+        import_bt9(bt9.as_bytes(), &mut encoder).unwrap();
+        encoder.finish().unwrap();
+        let imported = TraceFileDecoder::open(&imported).unwrap();
+        assert_eq!(imported.num_entries, decoder.num_entries);
+        assert_eq!(imported.branches.to_vec(), decoder.branches.to_vec());
+
+        // same instruction counts at each taken branch
+        let mut seek = decoder.seek_to_instruction(0).unwrap();
+        let mut expected = seek.inst_counts.take().unwrap();
+        let mut seek = imported.seek_to_instruction(0).unwrap();
+        let mut actual = seek.inst_counts.take().unwrap();
+        assert!(expected.by_ref().eq(actual.by_ref()));
+    }
+}
@@ -0,0 +1,224 @@
+//! CBP2025 trace format: a binary record per instruction, derived from the CVP-1 trace format.
+//!
+//! Each record contains the PC, the instruction class, the effective address of loads and stores,
+//! the outcome and target of branches, and the input and output registers with the output values.
+use crate::{
+    BranchEvent, BranchIndexer, BranchType, METADATA_TRACER, TraceFileEncoder, TraceSource,
+    import_source,
+};
+use anyhow::bail;
+use std::{
+    collections::BTreeMap,
+    io::{BufReader, ErrorKind, Read},
+};
+
+/// Instruction class of CBP2025 traces
+const CBP2025_LOAD: u8 = 1;
+const CBP2025_STORE: u8 = 2;
+const CBP2025_COND_BRANCH: u8 = 3;
+const CBP2025_UNCOND_DIRECT_BRANCH: u8 = 4;
+const CBP2025_UNCOND_INDIRECT_BRANCH: u8 = 5;
+const CBP2025_UNDEF: u8 = 8;
+const CBP2025_CALL_DIRECT: u8 = 9;
+const CBP2025_CALL_INDIRECT: u8 = 10;
+const CBP2025_RETURN: u8 = 11;
+
+/// Flags register, its value takes 8 bytes like integer registers
+const CBP2025_FLAG_REG: u8 = 64;
+
+/// CBP2025 traces are AArch64
+const CBP2025_INST_LENGTH: u32 = 4;
+
+fn read_u8(reader: &mut impl Read) -> anyhow::Result<u8> {
+    let mut buf = [0u8; 1];
+    reader.read_exact(&mut buf)?;
+    Ok(buf[0])
+}
+
+fn read_u64(reader: &mut impl Read) -> anyhow::Result<u64> {
+    let mut buf = [0u8; 8];
+    reader.read_exact(&mut buf)?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+fn skip(reader: &mut impl Read, len: u64) -> anyhow::Result<()> {
+    let skipped = std::io::copy(&mut reader.take(len), &mut std::io::sink())?;
+    if skipped != len {
+        bail!("CBP2025 trace ends in the middle of a record");
+    }
+    Ok(())
+}
+
+/// Events of CBP2025 trace, counting every instruction in the trace.
+/// The target of conditional branches is learned from the first taken execution,
+/// and stays zero for conditional branches that are never taken.
+pub struct Cbp2025Source<R: Read> {
+    reader: BufReader<R>,
+    indexer: BranchIndexer,
+    // instructions since the last taken branch
+    pending: u64,
+    instructions: u64,
+}
+
+impl<R: Read> Cbp2025Source<R> {
+    pub fn new(reader: R) -> Self {
+        Self {
+            reader: BufReader::new(reader),
+            indexer: BranchIndexer::default(),
+            pending: 0,
+            instructions: 0,
+        }
+    }
+}
+
+impl<R: Read> TraceSource for Cbp2025Source<R> {
+    fn next_event(&mut self) -> anyhow::Result<Option<BranchEvent>> {
+        let reader = &mut self.reader;
+        loop {
+            let mut buf = [0u8; 8];
+            match reader.read_exact(&mut buf) {
+                Ok(()) => {}
+                Err(err) if err.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+                Err(err) => return Err(err.into()),
+            }
+            let pc = u64::from_le_bytes(buf);
+            let class = read_u8(reader)?;
+            if class > CBP2025_RETURN || class == CBP2025_UNDEF {
+                bail!("Unsupported instruction class {} at pc 0x{:x}", class, pc);
+            }
+            self.pending += 1;
+
+            if class == CBP2025_LOAD || class == CBP2025_STORE {
+                // effective address and access size
+                skip(reader, 9)?;
+            }
+
+            let branch_type = match class {
+                CBP2025_COND_BRANCH => Some(BranchType::ConditionalDirectJump),
+                CBP2025_UNCOND_DIRECT_BRANCH => Some(BranchType::DirectJump),
+                CBP2025_UNCOND_INDIRECT_BRANCH => Some(BranchType::IndirectJump),
+                CBP2025_CALL_DIRECT => Some(BranchType::DirectCall),
+                CBP2025_CALL_INDIRECT => Some(BranchType::IndirectCall),
+                CBP2025_RETURN => Some(BranchType::Return),
+                _ => None,
+            };
+            let mut event = None;
+            if let Some(branch_type) = branch_type {
+                let taken = read_u8(reader)? != 0;
+                let targ_addr = if taken { read_u64(reader)? } else { 0 };
+                if !taken && branch_type != BranchType::ConditionalDirectJump {
+                    bail!("Unconditional branch at pc 0x{:x} is not taken", pc);
+                }
+                let (br_index, targ_addr) = self.indexer.index(pc, targ_addr, branch_type, taken);
+
+                let mut new_instructions = 0;
+                if taken {
+                    new_instructions = self.pending;
+                    self.instructions += new_instructions;
+                    self.pending = 0;
+                }
+                event = Some(BranchEvent {
+                    br_index,
+                    inst_addr: pc,
+                    targ_addr,
+                    inst_length: CBP2025_INST_LENGTH,
+                    branch_type,
+                    taken,
+                    new_instructions,
+                    instructions: self.instructions,
+                    thread_id: 0,
+                });
+            }
+
+            // input registers
+            let num_inputs = read_u8(reader)?;
+            skip(reader, num_inputs as u64)?;
+            // output registers and their values, SIMD registers take 16 bytes
+            let num_outputs = read_u8(reader)?;
+            let mut outputs = vec![0u8; num_outputs as usize];
+            reader.read_exact(&mut outputs)?;
+            for reg in outputs {
+                skip(
+                    reader,
+                    if (32..CBP2025_FLAG_REG).contains(&reg) {
+                        16
+                    } else {
+                        8
+                    },
+                )?;
+            }
+
+            if event.is_some() {
+                return Ok(event);
+            }
+        }
+    }
+
+    fn has_inst_counts(&self) -> bool {
+        true
+    }
+
+    fn metadata(&self) -> BTreeMap<String, String> {
+        BTreeMap::from([(METADATA_TRACER.to_string(), "cbp2025".to_string())])
+    }
+}
+
+/// Convert CBP2025 trace to our trace format, see Cbp2025Source
+pub fn import_cbp2025(reader: impl Read, encoder: &mut TraceFileEncoder) -> anyhow::Result<()> {
+    import_source(&mut Cbp2025Source::new(reader), encoder)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{BranchType, TraceFileDecoder, TraceFileEncoder, import_cbp2025};
+
+    #[test]
+    fn test_cbp2025_import() {
+        let mut trace = vec![];
+        for i in 0..100u64 {
+            // load x1 <- [x2]
+            trace.extend_from_slice(&0x1000u64.to_le_bytes());
+            trace.push(1);
+            trace.extend_from_slice(&0x8000u64.to_le_bytes());
+            trace.push(8);
+            trace.extend_from_slice(&[1, 2, 1, 1]);
+            trace.extend_from_slice(&i.to_le_bytes());
+            // simd add v0 <- v1
+            trace.extend_from_slice(&0x1004u64.to_le_bytes());
+            trace.push(6);
+            trace.extend_from_slice(&[1, 33, 1, 32]);
+            trace.extend_from_slice(&[0u8; 16]);
+            // b.ne 0x1000, taken except every 10th iteration
+            trace.extend_from_slice(&0x1008u64.to_le_bytes());
+            trace.push(3);
+            if i % 10 == 0 {
+                trace.push(0);
+            } else {
+                trace.push(1);
+                trace.extend_from_slice(&0x1000u64.to_le_bytes());
+            }
+            trace.extend_from_slice(&[1, 64, 0]);
+        }
+
+        let mut content = vec![];
+        let mut encoder = TraceFileEncoder::open_stream(&mut content).unwrap();
+        import_cbp2025(&trace[..], &mut encoder).unwrap();
+        encoder.finish().unwrap();
+        let decoder = TraceFileDecoder::open(&content).unwrap();
+        assert_eq!(decoder.num_entries, 100);
+        assert_eq!(decoder.num_branches, 1);
+        assert_eq!(decoder.branches[0].targ_addr, 0x1000);
+        assert_eq!(
+            decoder.branches[0].branch_type,
+            BranchType::ConditionalDirectJump
+        );
+
+        // three instructions per iteration, not taken iterations are counted at the next taken one
+        let mut seek = decoder.seek_to_instruction(0).unwrap();
+        let inst_counts: Vec<u64> = seek.inst_counts.take().unwrap().collect();
+        assert_eq!(inst_counts.len(), 90);
+        assert_eq!(inst_counts[0], 6);
+        assert_eq!(inst_counts[1], 3);
+        assert_eq!(inst_counts.iter().sum::<u64>(), 300);
+    }
+}
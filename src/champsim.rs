@@ -0,0 +1,583 @@
+//! Export traces to ChampSim input_instr records, recovering the instructions between branches
+//! by disassembling the images.
+use crate::{
+    Branch, BranchType, Image, InstCountFallbacks, TraceFileDecoder, TraceFileSource, TraceSource,
+};
+use anyhow::Context;
+use capstone::{
+    arch::{
+        ArchOperand,
+        arm64::Arm64Reg,
+        x86::{X86OperandType, X86Reg},
+    },
+    prelude::*,
+};
+use object::{Architecture, Object, ObjectKind, ObjectSection, SectionKind};
+use std::{collections::HashMap, io::Write};
+
+/// Special registers that ChampSim uses to deduce branch types
+const CHAMPSIM_REG_STACK_POINTER: u8 = 6;
+const CHAMPSIM_REG_FLAGS: u8 = 25;
+const CHAMPSIM_REG_INSTRUCTION_POINTER: u8 = 26;
+/// Other registers are numbered from here
+const CHAMPSIM_REG_OTHER_BASE: u8 = 32;
+
+const CHAMPSIM_NUM_DESTINATIONS: usize = 2;
+const CHAMPSIM_NUM_SOURCES: usize = 4;
+
+/// ChampSim input_instr record
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ChampsimInstr {
+    pub ip: u64,
+    pub is_branch: bool,
+    pub branch_taken: bool,
+    pub destination_registers: [u8; CHAMPSIM_NUM_DESTINATIONS],
+    pub source_registers: [u8; CHAMPSIM_NUM_SOURCES],
+    pub destination_memory: [u64; CHAMPSIM_NUM_DESTINATIONS],
+    pub source_memory: [u64; CHAMPSIM_NUM_SOURCES],
+}
+
+impl ChampsimInstr {
+    /// Serialize to the 64-byte layout of input_instr
+    pub fn to_bytes(&self) -> [u8; 64] {
+        let mut res = [0u8; 64];
+        res[0..8].copy_from_slice(&self.ip.to_le_bytes());
+        res[8] = self.is_branch as u8;
+        res[9] = self.branch_taken as u8;
+        res[10..12].copy_from_slice(&self.destination_registers);
+        res[12..16].copy_from_slice(&self.source_registers);
+        for (i, addr) in self
+            .destination_memory
+            .iter()
+            .chain(self.source_memory.iter())
+            .enumerate()
+        {
+            res[16 + i * 8..24 + i * 8].copy_from_slice(&addr.to_le_bytes());
+        }
+        res
+    }
+
+    /// Deserialize from the 64-byte layout of input_instr
+    pub fn from_bytes(bytes: &[u8; 64]) -> Self {
+        let u64_at =
+            |offset: usize| u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap());
+        let mut res = ChampsimInstr {
+            ip: u64_at(0),
+            is_branch: bytes[8] != 0,
+            branch_taken: bytes[9] != 0,
+            ..Default::default()
+        };
+        res.destination_registers.copy_from_slice(&bytes[10..12]);
+        res.source_registers.copy_from_slice(&bytes[12..16]);
+        for i in 0..CHAMPSIM_NUM_DESTINATIONS {
+            res.destination_memory[i] = u64_at(16 + i * 8);
+        }
+        for i in 0..CHAMPSIM_NUM_SOURCES {
+            res.source_memory[i] = u64_at(32 + i * 8);
+        }
+        res
+    }
+}
+
+/// Fill a fixed-size operand array, dropping duplicates and what does not fit
+fn fill<T: Copy + Default + PartialEq, const N: usize>(slots: &mut [T; N], values: &[T]) {
+    let mut len = slots.iter().filter(|slot| **slot != T::default()).count();
+    for value in values {
+        if len < N && *value != T::default() && !slots[..len].contains(value) {
+            slots[len] = *value;
+            len += 1;
+        }
+    }
+}
+
+/// Operands of a static instruction
+#[derive(Debug, Clone, Default)]
+struct DecodedInst {
+    len: u64,
+    src_regs: Vec<u8>,
+    dst_regs: Vec<u8>,
+    /// only addresses that are known statically, i.e. absolute or PC-relative
+    src_mem: Vec<u64>,
+    dst_mem: Vec<u64>,
+}
+
+/// Decode instructions from the text sections of images on demand
+struct Disassembler<'a> {
+    cs: Capstone,
+    arch: Architecture,
+    /// (runtime address, content) of text sections
+    sections: Vec<(u64, &'a [u8])>,
+    cache: HashMap<u64, Option<DecodedInst>>,
+    /// ChampSim register number of each capstone register
+    regs: HashMap<RegId, u8>,
+}
+
+impl<'a> Disassembler<'a> {
+    fn new(images: &'a [Image]) -> anyhow::Result<Self> {
+        let mut arch = Architecture::X86_64;
+        let mut sections = vec![];
+        for image in images {
+            let file = object::File::parse(image.data.as_slice())
+                .with_context(|| format!("Failed to parse image {}", image.filename))?;
+            let load_base = match file.kind() {
+                ObjectKind::Executable => 0,
+                ObjectKind::Dynamic => image.start,
+                _ => continue,
+            };
+            arch = file.architecture();
+            for section in file.sections() {
+                if section.kind() == SectionKind::Text
+                    && let Some((offset, size)) = section.file_range()
+                {
+                    sections.push((
+                        section.address() + load_base,
+                        &image.data[offset as usize..(offset + size) as usize],
+                    ));
+                }
+            }
+        }
+
+        let cs = match arch {
+            Architecture::X86_64 => Capstone::new()
+                .x86()
+                .mode(arch::x86::ArchMode::Mode64)
+                .syntax(arch::x86::ArchSyntax::Att)
+                .detail(true)
+                .build()?,
+            Architecture::Aarch64 => Capstone::new()
+                .arm64()
+                .mode(arch::arm64::ArchMode::Arm)
+                .detail(true)
+                .build()?,
+            _ => anyhow::bail!("Unsupported architecture {:?}", arch),
+        };
+        let mut regs = HashMap::new();
+        let special = match arch {
+            Architecture::X86_64 => [
+                (X86Reg::X86_REG_RSP, CHAMPSIM_REG_STACK_POINTER),
+                (X86Reg::X86_REG_EFLAGS, CHAMPSIM_REG_FLAGS),
+                (X86Reg::X86_REG_RIP, CHAMPSIM_REG_INSTRUCTION_POINTER),
+            ],
+            _ => [
+                (Arm64Reg::ARM64_REG_SP, CHAMPSIM_REG_STACK_POINTER),
+                (Arm64Reg::ARM64_REG_NZCV, CHAMPSIM_REG_FLAGS),
+                // AArch64 has no program counter register
+                (Arm64Reg::ARM64_REG_INVALID, 0),
+            ],
+        };
+        for (reg, champsim_reg) in special {
+            regs.insert(RegId(reg as u16), champsim_reg);
+        }
+
+        Ok(Self {
+            cs,
+            arch,
+            sections,
+            cache: HashMap::new(),
+            regs,
+        })
+    }
+
+    fn map_reg(&mut self, reg: RegId) -> u8 {
+        let index = self.regs.len();
+        *self.regs.entry(reg).or_insert_with(|| {
+            // wrap around if there are too many registers
+            (CHAMPSIM_REG_OTHER_BASE as usize + index % (256 - CHAMPSIM_REG_OTHER_BASE as usize))
+                as u8
+        })
+    }
+
+    fn decode(&mut self, addr: u64) -> Option<DecodedInst> {
+        if let Some(inst) = self.cache.get(&addr) {
+            return inst.clone();
+        }
+        let inst = self.decode_uncached(addr);
+        self.cache.insert(addr, inst.clone());
+        inst
+    }
+
+    fn decode_uncached(&mut self, addr: u64) -> Option<DecodedInst> {
+        let (start, content) = self
+            .sections
+            .iter()
+            .find(|(start, content)| addr >= *start && addr < *start + content.len() as u64)?;
+        let code = &content[(addr - start) as usize..];
+        let insns = self.cs.disasm_count(code, addr, 1).ok()?;
+        let insn = insns.iter().next()?;
+        let detail = self.cs.insn_detail(insn).ok()?;
+        let len = insn.len() as u64;
+        let regs_read: Vec<RegId> = detail.regs_read().to_vec();
+        let regs_write: Vec<RegId> = detail.regs_write().to_vec();
+
+        let mut src_mem = vec![];
+        let mut dst_mem = vec![];
+        if self.arch == Architecture::X86_64 {
+            for op in detail.arch_detail().operands() {
+                if let ArchOperand::X86Operand(op) = op
+                    && let X86OperandType::Mem(mem) = op.op_type
+                    && mem.index() == RegId(X86Reg::X86_REG_INVALID as u16)
+                    && mem.segment() == RegId(X86Reg::X86_REG_INVALID as u16)
+                {
+                    let mem_addr = if mem.base() == RegId(X86Reg::X86_REG_RIP as u16) {
+                        (addr + len).wrapping_add_signed(mem.disp())
+                    } else if mem.base() == RegId(X86Reg::X86_REG_INVALID as u16) {
+                        mem.disp() as u64
+                    } else {
+                        continue;
+                    };
+                    // lea does not access memory
+                    let Some(access) = op.access else {
+                        continue;
+                    };
+                    if access.is_readable() {
+                        src_mem.push(mem_addr);
+                    }
+                    if access.is_writable() {
+                        dst_mem.push(mem_addr);
+                    }
+                }
+            }
+        }
+
+        drop(detail);
+        drop(insns);
+
+        Some(DecodedInst {
+            len,
+            src_regs: regs_read.into_iter().map(|reg| self.map_reg(reg)).collect(),
+            dst_regs: regs_write
+                .into_iter()
+                .map(|reg| self.map_reg(reg))
+                .collect(),
+            src_mem,
+            dst_mem,
+        })
+    }
+}
+
+/// Build the record of a branch with the register pattern that ChampSim uses to deduce its type
+fn branch_record(branch: &Branch, taken: bool, inst: Option<&DecodedInst>) -> ChampsimInstr {
+    let mut record = ChampsimInstr {
+        ip: branch.inst_addr,
+        is_branch: true,
+        branch_taken: taken,
+        ..Default::default()
+    };
+    // registers other than sp, flags and ip, read by indirect branches
+    let mut others: Vec<u8> = inst
+        .map(|inst| {
+            inst.src_regs
+                .iter()
+                .copied()
+                .filter(|reg| {
+                    ![
+                        CHAMPSIM_REG_STACK_POINTER,
+                        CHAMPSIM_REG_FLAGS,
+                        CHAMPSIM_REG_INSTRUCTION_POINTER,
+                    ]
+                    .contains(reg)
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+    if others.is_empty() {
+        others.push(CHAMPSIM_REG_OTHER_BASE);
+    }
+
+    let sp = CHAMPSIM_REG_STACK_POINTER;
+    let ip = CHAMPSIM_REG_INSTRUCTION_POINTER;
+    let (dst, src): (Vec<u8>, Vec<u8>) = match branch.branch_type {
+        BranchType::DirectJump => (vec![ip], vec![ip]),
+        BranchType::IndirectJump => (vec![ip], others),
+        BranchType::ConditionalDirectJump => (vec![ip], vec![ip, CHAMPSIM_REG_FLAGS]),
+        BranchType::DirectCall => (vec![ip, sp], vec![ip, sp]),
+        BranchType::IndirectCall => (vec![ip, sp], [vec![ip, sp], others].concat()),
+        BranchType::Return => (vec![ip, sp], vec![sp]),
+        _ => (vec![ip], vec![]),
+    };
+    fill(&mut record.destination_registers, &dst);
+    fill(&mut record.source_registers, &src);
+    if let Some(inst) = inst {
+        fill(&mut record.source_memory, &inst.src_mem);
+        fill(&mut record.destination_memory, &inst.dst_mem);
+    }
+    record
+}
+
+/// Statistics of ChampSim export
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ChampsimExportStats {
+    pub instructions: u64,
+    pub branches: u64,
+    /// times the instructions before a branch could not be recovered from the images
+    pub gaps: u64,
+    /// instructions counted in the trace but missing from the export due to the gaps
+    pub missing_instructions: u64,
+    /// taken branches whose instruction counts are not exact
+    pub inst_count_fallbacks: InstCountFallbacks,
+}
+
+/// Convert trace to ChampSim input_instr records.
+/// Instructions between branches are recovered by disassembling the images,
+/// and memory operands are only filled if the addresses are known statically.
+/// The walk between branches is bounded by the instruction counts if available,
+/// and follows each thread separately.
+pub fn export_champsim(
+    decoder: &TraceFileDecoder,
+    mut writer: impl Write,
+) -> anyhow::Result<ChampsimExportStats> {
+    let images = decoder.get_images()?;
+    anyhow::ensure!(
+        !images.is_empty(),
+        "Images are required to export ChampSim trace"
+    );
+    let mut disasm = Disassembler::new(&images)?;
+    let mut stats = ChampsimExportStats::default();
+
+    let mut source = TraceFileSource::new(decoder, 0, 0)?;
+    let has_inst_counts = source.has_inst_counts();
+    // (address of the next instruction, records since the last taken branch) of each thread,
+    // the address is unknown at the beginning
+    let mut threads: HashMap<u64, (Option<u64>, u64)> = HashMap::new();
+    while let Some(event) = source.next_event()? {
+        let (next_pc, block_records) = threads.entry(event.thread_id).or_default();
+        // records before the branch that the instruction counts allow
+        let limit = if has_inst_counts && event.taken && next_pc.is_some() {
+            event.new_instructions.saturating_sub(*block_records + 1)
+        } else {
+            u64::MAX
+        };
+
+        // walk from the last branch to this branch
+        if let Some(mut pc) = *next_pc {
+            let mut walked = 0;
+            while pc != event.inst_addr {
+                let inst = match disasm.decode(pc) {
+                    Some(inst) if pc < event.inst_addr && walked < limit => inst,
+                    _ => {
+                        stats.gaps += 1;
+                        if limit != u64::MAX {
+                            stats.missing_instructions += limit - walked;
+                        }
+                        break;
+                    }
+                };
+                let mut record = ChampsimInstr {
+                    ip: pc,
+                    ..Default::default()
+                };
+                fill(&mut record.destination_registers, &inst.dst_regs);
+                fill(&mut record.source_registers, &inst.src_regs);
+                fill(&mut record.destination_memory, &inst.dst_mem);
+                fill(&mut record.source_memory, &inst.src_mem);
+                writer.write_all(&record.to_bytes())?;
+                stats.instructions += 1;
+                walked += 1;
+                pc += inst.len;
+            }
+            *block_records += walked;
+        }
+
+        let inst = disasm.decode(event.inst_addr);
+        writer.write_all(&branch_record(&event.branch(), event.taken, inst.as_ref()).to_bytes())?;
+        stats.instructions += 1;
+        stats.branches += 1;
+        if event.taken {
+            *next_pc = Some(event.targ_addr);
+            *block_records = 0;
+        } else {
+            *next_pc = Some(event.inst_addr + event.inst_length as u64);
+            *block_records += 1;
+        }
+    }
+    stats.inst_count_fallbacks = source.inst_count_fallbacks();
+    writer.flush()?;
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        BranchType, ChampsimInstr, Image, TraceFileDecoder, TraceFileEncoder, champsim::fill,
+        export_champsim,
+    };
+
+    /// Minimal x86-64 executable with a single text section at the given address
+    fn executable(text_addr: u64, code: &[u8]) -> Vec<u8> {
+        let shstrtab = b"\0.text\0.shstrtab\0";
+        let shstrtab_offset = 64 + code.len();
+        let shoff = (shstrtab_offset + shstrtab.len()).next_multiple_of(8);
+        let mut elf = vec![0x7f, b'E', b'L', b'F', 2, 1, 1];
+        elf.resize(16, 0);
+        // e_type = ET_EXEC, e_machine = EM_X86_64, e_version
+        elf.extend_from_slice(&2u16.to_le_bytes());
+        elf.extend_from_slice(&62u16.to_le_bytes());
+        elf.extend_from_slice(&1u32.to_le_bytes());
+        // e_entry, e_phoff, e_shoff, e_flags
+        elf.extend_from_slice(&text_addr.to_le_bytes());
+        elf.extend_from_slice(&0u64.to_le_bytes());
+        elf.extend_from_slice(&(shoff as u64).to_le_bytes());
+        elf.extend_from_slice(&0u32.to_le_bytes());
+        // e_ehsize, e_phentsize, e_phnum, e_shentsize, e_shnum, e_shstrndx
+        for value in [64u16, 56, 0, 64, 3, 2] {
+            elf.extend_from_slice(&value.to_le_bytes());
+        }
+        elf.extend_from_slice(code);
+        elf.extend_from_slice(shstrtab);
+        elf.resize(shoff, 0);
+        // null, .text and .shstrtab section headers
+        let sections = [
+            (0u32, 0u32, 0u64, 0u64, 0usize, 0usize),
+            (1, 1, 6, text_addr, 64, code.len()),
+            (7, 3, 0, 0, shstrtab_offset, shstrtab.len()),
+        ];
+        for (name, kind, flags, addr, offset, size) in sections {
+            elf.extend_from_slice(&name.to_le_bytes());
+            elf.extend_from_slice(&kind.to_le_bytes());
+            elf.extend_from_slice(&flags.to_le_bytes());
+            elf.extend_from_slice(&addr.to_le_bytes());
+            elf.extend_from_slice(&(offset as u64).to_le_bytes());
+            elf.extend_from_slice(&(size as u64).to_le_bytes());
+            elf.extend_from_slice(&[0; 8]);
+            elf.extend_from_slice(&1u64.to_le_bytes());
+            elf.extend_from_slice(&0u64.to_le_bytes());
+        }
+        elf
+    }
+
+    #[test]
+    fn test_export_champsim() {
+        let code = [
+            0x48, 0x8b, 0x05, 0x10, 0x00, 0x00, 0x00, // 0x401000: mov 0x10(%rip), %rax
+            0x48, 0x89, 0x1c, 0x25, 0x00, 0x20, 0x40, 0x00, // 0x401007: mov %rbx, 0x402000
+            0x48, 0x01, 0xca, // 0x40100f: add %rcx, %rdx
+            0x75, 0xec, // 0x401012: jne 0x401000
+            0xff, 0xe0, // 0x401014: jmp *%rax
+        ];
+        let data = executable(0x401000, &code);
+        let mut content = vec![];
+        let mut encoder = TraceFileEncoder::open_stream(&mut content).unwrap();
+        encoder.images.push(Image {
+            start: 0x400000,
+            len: 0x2000,
+            data,
+            filename: "/bin/test".to_string(),
+        });
+        let jne =
+            encoder.get_branch_index(0x401012, 0x401000, 2, BranchType::ConditionalDirectJump);
+        let jmp = encoder.get_branch_index(0x401014, 0x401000, 2, BranchType::IndirectJump);
+        let jmp_out = encoder.get_branch_index(0x401014, 0x500000, 2, BranchType::IndirectJump);
+        for (br_index, taken, instructions) in [
+            (jne, true, 4),
+            (jne, true, 4),
+            (jne, false, 0),
+            (jmp, true, 5),
+            (jne, true, 4),
+            (jne, false, 0),
+            // leaves the text section, the instructions there cannot be recovered
+            (jmp_out, true, 5),
+            (jne, true, 10),
+        ] {
+            encoder
+                .record_event_with_inst_count(br_index, taken, instructions)
+                .unwrap();
+        }
+        encoder.finish().unwrap();
+        let decoder = TraceFileDecoder::open(&content).unwrap();
+
+        let mut exported = vec![];
+        let stats = export_champsim(&decoder, &mut exported).unwrap();
+        let records: Vec<ChampsimInstr> = exported
+            .chunks(64)
+            .map(|chunk| ChampsimInstr::from_bytes(chunk.try_into().unwrap()))
+            .collect();
+        assert_eq!(stats.instructions, records.len() as u64);
+        assert_eq!(stats.branches, 8);
+        assert_eq!(stats.gaps, 1);
+        assert_eq!(stats.missing_instructions, 9);
+
+        // the first branch starts the walk, then each loop iteration has 3 instructions
+        let ips: Vec<u64> = records.iter().map(|record| record.ip).collect();
+        let iteration = [0x401000, 0x401007, 0x40100f, 0x401012];
+        let expected = [
+            &[0x401012][..],
+            &iteration,
+            &iteration,
+            &[0x401014],
+            &iteration,
+            &iteration,
+            &[0x401014],
+            &[0x401012],
+        ]
+        .concat();
+        assert_eq!(ips, expected);
+        let flags: Vec<(bool, bool)> = records
+            .iter()
+            .filter(|record| record.is_branch)
+            .map(|record| (record.ip == 0x401012, record.branch_taken))
+            .collect();
+        assert_eq!(
+            flags,
+            [
+                (true, true),
+                (true, true),
+                (true, false),
+                (false, true),
+                (true, true),
+                (true, false),
+                (false, true),
+                (true, true)
+            ]
+        );
+
+        // registers: ip 26, flags 25, others from 32 and consistent across instructions
+        let (load, store, add, jne, jmp) = (
+            &records[1],
+            &records[2],
+            &records[3],
+            &records[4],
+            &records[9],
+        );
+        let rax = load.destination_registers[0];
+        assert!(rax >= 32);
+        assert_eq!(load.source_registers, [26, 0, 0, 0]);
+        assert_eq!(jmp.destination_registers, [26, 0]);
+        assert_eq!(jmp.source_registers, [rax, 0, 0, 0]);
+        assert_eq!(jne.destination_registers, [26, 0]);
+        assert_eq!(jne.source_registers, [26, 25, 0, 0]);
+        assert!(add.destination_registers.contains(&25));
+        assert!(
+            add.source_registers
+                .iter()
+                .filter(|reg| **reg >= 32)
+                .count()
+                == 2
+        );
+        assert!(!store.source_registers.contains(&rax));
+
+        // memory operands with RIP-relative and absolute addresses
+        assert_eq!(load.source_memory, [0x401017, 0, 0, 0]);
+        assert_eq!(load.destination_memory, [0, 0]);
+        assert_eq!(store.destination_memory, [0x402000, 0]);
+        assert_eq!(store.source_memory, [0, 0, 0, 0]);
+        assert_eq!(add.source_memory, [0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_champsim_instr_layout() {
+        let mut record = ChampsimInstr {
+            ip: 0x401000,
+            is_branch: true,
+            branch_taken: true,
+            ..Default::default()
+        };
+        fill(&mut record.destination_registers, &[26, 6, 26, 7]);
+        fill(&mut record.source_registers, &[26, 6]);
+        record.source_memory[3] = 0x1234;
+        let bytes = record.to_bytes();
+        assert_eq!(&bytes[0..8], &0x401000u64.to_le_bytes());
+        assert_eq!(&bytes[8..16], &[1, 1, 26, 6, 26, 6, 0, 0]);
+        assert_eq!(&bytes[56..64], &0x1234u64.to_le_bytes());
+        assert_eq!(ChampsimInstr::from_bytes(&bytes), record);
+    }
+}
@@ -0,0 +1,216 @@
+//! Compare two trace files of the same program
+use crate::{TraceFileDecoder, TraceFileSource, TraceSource};
+use std::collections::{HashMap, VecDeque};
+
+/// A branch event, compared by (inst_addr, targ_addr, taken)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DiffEvent {
+    pub inst_addr: u64,
+    pub targ_addr: u64,
+    pub taken: bool,
+}
+
+/// Execution statistics of a branch in both traces
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BranchDiff {
+    pub execution_count: [u64; 2],
+    pub taken_count: [u64; 2],
+}
+
+/// First entry where the traces differ, None for the trace that has ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TraceDivergence {
+    pub events: [Option<DiffEvent>; 2],
+    /// Instructions executed up to the divergent branch, None without instruction counts
+    pub instructions: [Option<u64>; 2],
+}
+
+/// Result of comparing two traces
+#[derive(Debug, Clone, Default)]
+pub struct TraceDiff {
+    /// Entries before the first divergence
+    pub common_entries: u64,
+    /// None if the traces are identical
+    pub divergence: Option<TraceDivergence>,
+    /// Common branches before the divergence along with the instructions executed
+    /// up to each of them in the first trace
+    pub history: Vec<(DiffEvent, Option<u64>)>,
+    /// Instructions of each trace, None without instruction counts
+    pub instructions: [Option<u64>; 2],
+    /// Statistics of each (inst_addr, targ_addr) branch
+    pub branch_diffs: HashMap<(u64, u64), BranchDiff>,
+}
+
+/// Events of a trace file along with instruction counts
+struct EventIterator<'a> {
+    source: TraceFileSource<'a>,
+}
+
+impl<'a> EventIterator<'a> {
+    fn new(file: &'a TraceFileDecoder<'a>) -> anyhow::Result<Self> {
+        Ok(Self {
+            source: TraceFileSource::new(file, 0, 0)?,
+        })
+    }
+
+    fn next_event(&mut self) -> anyhow::Result<Option<DiffEvent>> {
+        Ok(self.source.next_event()?.map(|event| DiffEvent {
+            inst_addr: event.inst_addr,
+            targ_addr: event.targ_addr,
+            taken: event.taken,
+        }))
+    }
+
+    /// instructions executed so far, if instruction counting is available
+    fn instructions(&self) -> Option<u64> {
+        self.source
+            .has_inst_counts()
+            .then(|| self.source.instructions())
+    }
+}
+
+/// Walk two traces in lockstep until the first divergence, keeping up to `context` branches
+/// before it, then collect the branch statistics of the remaining entries.
+/// `on_progress` receives the common entries so far.
+pub fn diff_traces(
+    first: &TraceFileDecoder,
+    second: &TraceFileDecoder,
+    context: usize,
+    mut on_progress: impl FnMut(u64),
+) -> anyhow::Result<TraceDiff> {
+    let mut first_events = EventIterator::new(first)?;
+    let mut second_events = EventIterator::new(second)?;
+
+    let mut history: VecDeque<(DiffEvent, Option<u64>)> = VecDeque::new();
+    let mut common_entries = 0u64;
+    let mut branch_diffs: HashMap<(u64, u64), BranchDiff> = HashMap::new();
+    let mut record = |side: usize, event: &DiffEvent| {
+        let diff = branch_diffs
+            .entry((event.inst_addr, event.targ_addr))
+            .or_default();
+        diff.execution_count[side] += 1;
+        diff.taken_count[side] += event.taken as u64;
+    };
+    let events = loop {
+        match (first_events.next_event()?, second_events.next_event()?) {
+            (Some(first), Some(second)) => {
+                record(0, &first);
+                record(1, &second);
+                if first != second {
+                    break Some([Some(first), Some(second)]);
+                }
+                common_entries += 1;
+                if context > 0 {
+                    if history.len() == context {
+                        history.pop_front();
+                    }
+                    history.push_back((first, first_events.instructions()));
+                }
+                if common_entries.is_multiple_of(1024 * 1024) {
+                    on_progress(common_entries);
+                }
+            }
+            (None, None) => break None,
+            (first, second) => {
+                if let Some(first) = &first {
+                    record(0, first);
+                }
+                if let Some(second) = &second {
+                    record(1, second);
+                }
+                break Some([first, second]);
+            }
+        }
+    };
+    let divergence = events.map(|events| TraceDivergence {
+        events,
+        instructions: [first_events.instructions(), second_events.instructions()],
+    });
+
+    // collect statistics of the remaining entries
+    while let Some(event) = first_events.next_event()? {
+        record(0, &event);
+    }
+    while let Some(event) = second_events.next_event()? {
+        record(1, &event);
+    }
+
+    Ok(TraceDiff {
+        common_entries,
+        divergence,
+        history: history.into(),
+        instructions: [first_events.instructions(), second_events.instructions()],
+        branch_diffs,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Branch, BranchType, DiffEvent, TraceFileDecoder, TraceFileEncoder, diff_traces};
+
+    /// Encode a loop of 100 iterations, the given iteration exits the loop early
+    fn encode(exit_at: Option<u64>, inst_counts: bool) -> Vec<u8> {
+        let mut content = vec![];
+        let mut encoder = TraceFileEncoder::open_stream(&mut content).unwrap();
+        encoder.branches = vec![Branch {
+            inst_addr: 0x1000,
+            targ_addr: 0x0ff0,
+            inst_length: 2,
+            branch_type: BranchType::ConditionalDirectJump,
+        }];
+        for i in 0..100 {
+            let taken = Some(i) != exit_at;
+            if inst_counts {
+                encoder.record_event_with_inst_count(0, taken, 5).unwrap();
+            } else {
+                encoder.record_event_with_branch_index(0, taken).unwrap();
+            }
+        }
+        encoder.finish().unwrap();
+        content
+    }
+
+    #[test]
+    fn test_diff_traces() {
+        let first = encode(None, true);
+        let first = TraceFileDecoder::open(&first).unwrap();
+        let second = encode(Some(40), true);
+        let second = TraceFileDecoder::open(&second).unwrap();
+
+        let diff = diff_traces(&first, &second, 4, |_| {}).unwrap();
+        assert_eq!(diff.common_entries, 40);
+        let divergence = diff.divergence.unwrap();
+        let event = |taken| DiffEvent {
+            inst_addr: 0x1000,
+            targ_addr: 0x0ff0,
+            taken,
+        };
+        assert_eq!(divergence.events, [Some(event(true)), Some(event(false))]);
+        assert_eq!(divergence.instructions, [Some(205), Some(200)]);
+        assert_eq!(diff.history.len(), 4);
+        assert_eq!(diff.history[3], (event(true), Some(200)));
+        assert_eq!(diff.instructions, [Some(500), Some(495)]);
+        let branch_diff = diff.branch_diffs[&(0x1000, 0x0ff0)];
+        assert_eq!(branch_diff.execution_count, [100, 100]);
+        assert_eq!(branch_diff.taken_count, [100, 99]);
+
+        // identical traces have no divergence
+        let diff = diff_traces(&first, &first, 4, |_| {}).unwrap();
+        assert_eq!(diff.common_entries, 100);
+        assert!(diff.divergence.is_none());
+    }
+
+    #[test]
+    fn test_diff_traces_without_inst_counts() {
+        let first = encode(None, false);
+        let first = TraceFileDecoder::open(&first).unwrap();
+        let second = encode(Some(40), false);
+        let second = TraceFileDecoder::open(&second).unwrap();
+
+        let diff = diff_traces(&first, &second, 0, |_| {}).unwrap();
+        assert_eq!(diff.common_entries, 40);
+        assert_eq!(diff.divergence.unwrap().instructions, [None, None]);
+        assert!(diff.history.is_empty());
+        assert_eq!(diff.instructions, [None, None]);
+    }
+}
@@ -0,0 +1,248 @@
+//! Text format of traces, one dynamic branch per line:
+//!
+//! `<instructions> <pc> <type> <T|N> <target> <length> [# comment]`
+//!
+//! The instruction count is the instructions executed so far, updated at taken branches,
+//! or `-` if unknown. Addresses are hex with 0x prefix. Anything after `#` is ignored.
+use crate::{BranchEvent, BranchIndexer, BranchType, TraceFileEncoder, TraceSource, import_source};
+use anyhow::{Context, bail};
+use std::io::{BufRead, Lines};
+
+/// Short name of branch type in text format
+pub fn branch_type_name(branch_type: BranchType) -> &'static str {
+    match branch_type {
+        BranchType::DirectJump => "jmp",
+        BranchType::IndirectJump => "jmp_ind",
+        BranchType::DirectCall => "call",
+        BranchType::IndirectCall => "call_ind",
+        BranchType::Return => "ret",
+        BranchType::ConditionalDirectJump => "jcc",
+        _ => "invalid",
+    }
+}
+
+fn parse_branch_type(name: &str) -> Option<BranchType> {
+    Some(match name {
+        "jmp" => BranchType::DirectJump,
+        "jmp_ind" => BranchType::IndirectJump,
+        "call" => BranchType::DirectCall,
+        "call_ind" => BranchType::IndirectCall,
+        "ret" => BranchType::Return,
+        "jcc" => BranchType::ConditionalDirectJump,
+        _ => return None,
+    })
+}
+
+/// A dynamic branch in text format
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DumpLine {
+    pub instructions: Option<u64>,
+    pub inst_addr: u64,
+    pub targ_addr: u64,
+    pub inst_length: u32,
+    pub branch_type: BranchType,
+    pub taken: bool,
+}
+
+impl DumpLine {
+    /// Format without the comment
+    pub fn format(&self) -> String {
+        format!(
+            "{:>12} 0x{:x} {:<8} {} 0x{:x} {}",
+            match self.instructions {
+                Some(instructions) => instructions.to_string(),
+                None => "-".to_string(),
+            },
+            self.inst_addr,
+            branch_type_name(self.branch_type),
+            if self.taken { "T" } else { "N" },
+            self.targ_addr,
+            self.inst_length
+        )
+    }
+
+    /// Parse a line, None if it is empty or a comment
+    pub fn parse(line: &str) -> anyhow::Result<Option<DumpLine>> {
+        let line = match line.split_once('#') {
+            Some((line, _)) => line,
+            None => line,
+        };
+        let tokens: Vec<&str> = line.split_whitespace().collect();
+        if tokens.is_empty() {
+            return Ok(None);
+        }
+        if tokens.len() != 6 {
+            bail!("Expected 6 fields, got {}", tokens.len());
+        }
+        let parse_hex = |s: &str| -> anyhow::Result<u64> {
+            let hex = s
+                .strip_prefix("0x")
+                .with_context(|| format!("Missing 0x prefix in {}", s))?;
+            Ok(u64::from_str_radix(hex, 16)?)
+        };
+        Ok(Some(DumpLine {
+            instructions: match tokens[0] {
+                "-" => None,
+                count => Some(count.parse()?),
+            },
+            inst_addr: parse_hex(tokens[1])?,
+            branch_type: parse_branch_type(tokens[2])
+                .with_context(|| format!("Unknown branch type {}", tokens[2]))?,
+            taken: match tokens[3] {
+                "T" => true,
+                "N" => false,
+                taken => bail!("Expected T or N, got {}", taken),
+            },
+            targ_addr: parse_hex(tokens[4])?,
+            inst_length: tokens[5].parse()?,
+        }))
+    }
+}
+
+/// Events of text format, with the instruction counts if the first line has one
+pub struct DumpSource<R: BufRead> {
+    lines: Lines<R>,
+    line_index: usize,
+    indexer: BranchIndexer,
+    // the first line is parsed ahead to tell whether instructions are counted
+    first: Option<DumpLine>,
+    has_inst_counts: bool,
+    instructions: u64,
+}
+
+impl<R: BufRead> DumpSource<R> {
+    pub fn new(reader: R) -> anyhow::Result<Self> {
+        let mut source = Self {
+            lines: reader.lines(),
+            line_index: 0,
+            indexer: BranchIndexer::default(),
+            first: None,
+            has_inst_counts: false,
+            instructions: 0,
+        };
+        source.first = source.next_line()?;
+        source.has_inst_counts = source.first.is_some_and(|dump| dump.instructions.is_some());
+        Ok(source)
+    }
+
+    fn next_line(&mut self) -> anyhow::Result<Option<DumpLine>> {
+        for line in self.lines.by_ref() {
+            self.line_index += 1;
+            let line = line?;
+            let context = || format!("Failed to parse line {}: {}", self.line_index, line);
+            if let Some(dump) = DumpLine::parse(&line).with_context(context)? {
+                return Ok(Some(dump));
+            }
+        }
+        Ok(None)
+    }
+}
+
+impl<R: BufRead> TraceSource for DumpSource<R> {
+    fn next_event(&mut self) -> anyhow::Result<Option<BranchEvent>> {
+        let dump = match self.first.take() {
+            Some(dump) => dump,
+            None => match self.next_line()? {
+                Some(dump) => dump,
+                None => return Ok(None),
+            },
+        };
+
+        let mut new_instructions = 0;
+        if self.has_inst_counts {
+            let Some(instructions) = dump.instructions else {
+                bail!("Instruction count is missing at line {}", self.line_index);
+            };
+            if instructions < self.instructions {
+                bail!("Instruction count decreases at line {}", self.line_index);
+            }
+            if dump.taken {
+                new_instructions = instructions - self.instructions;
+                self.instructions = instructions;
+            }
+        }
+        let (br_index, targ_addr) =
+            self.indexer
+                .index(dump.inst_addr, dump.targ_addr, dump.branch_type, dump.taken);
+        Ok(Some(BranchEvent {
+            br_index,
+            inst_addr: dump.inst_addr,
+            targ_addr,
+            inst_length: dump.inst_length,
+            branch_type: dump.branch_type,
+            taken: dump.taken,
+            new_instructions,
+            instructions: self.instructions,
+            thread_id: 0,
+        }))
+    }
+
+    fn has_inst_counts(&self) -> bool {
+        self.has_inst_counts
+    }
+}
+
+/// Convert text format to trace, the instruction counts are embedded if provided
+pub fn import_trace_dump(
+    reader: impl BufRead,
+    encoder: &mut TraceFileEncoder,
+) -> anyhow::Result<()> {
+    import_source(&mut DumpSource::new(reader)?, encoder)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{BranchType, DumpLine, TraceFileDecoder, TraceFileEncoder, import_trace_dump};
+
+    #[test]
+    fn test_dump_roundtrip() {
+        let text = "\
+# hand-made loop
+          10 0x1000 jcc      T 0x2000 2 # foo+0x10 -> bar
+          10 0x2010 jcc      N 0x2100 6
+          15 0x2020 ret      T 0x1002 1
+";
+        let mut content = vec![];
+        let mut encoder = TraceFileEncoder::open_stream(&mut content).unwrap();
+        import_trace_dump(text.as_bytes(), &mut encoder).unwrap();
+        encoder.finish().unwrap();
+        let decoder = TraceFileDecoder::open(&content).unwrap();
+        assert_eq!(decoder.num_entries, 3);
+        assert_eq!(decoder.num_branches, 3);
+
+        // format back
+        let mut seek = decoder.seek_to_instruction(0).unwrap();
+        let mut inst_counter = decoder.inst_counter(&mut seek).unwrap();
+        let mut instructions = 0;
+        let mut lines = vec![];
+        for entries in seek.entries {
+            for entry in entries {
+                let branch = decoder.branches[entry.get_br_index()];
+                if entry.get_taken() {
+                    instructions += inst_counter.count_taken(entry.get_br_index());
+                }
+                lines.push(
+                    DumpLine {
+                        instructions: Some(instructions),
+                        inst_addr: branch.inst_addr,
+                        targ_addr: branch.targ_addr,
+                        inst_length: branch.inst_length,
+                        branch_type: branch.branch_type,
+                        taken: entry.get_taken(),
+                    }
+                    .format(),
+                );
+            }
+        }
+        let expected: Vec<String> = text
+            .lines()
+            .filter_map(|line| DumpLine::parse(line).unwrap())
+            .map(|line| line.format())
+            .collect();
+        assert_eq!(lines, expected);
+        assert_eq!(
+            DumpLine::parse(&lines[2]).unwrap().unwrap().branch_type,
+            BranchType::Return
+        );
+    }
+}
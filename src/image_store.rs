@@ -0,0 +1,70 @@
+use anyhow::{Context, bail};
+use sha2::{Digest, Sha256};
+use std::path::{Path, PathBuf};
+
+/// Reference to an image in the image store by content, see image_ref in common.h
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq)]
+pub struct ImageRef {
+    pub size: u64,
+    pub sha256: [u8; 32],
+}
+
+impl ImageRef {
+    pub fn from_data(data: &[u8]) -> Self {
+        Self {
+            size: data.len() as u64,
+            sha256: Sha256::digest(data).into(),
+        }
+    }
+
+    /// File name in the image store: {sha256}-{size}
+    pub fn file_name(&self) -> String {
+        let hash: String = self
+            .sha256
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+        format!("{}-{}", hash, self.size)
+    }
+}
+
+/// Image store of every trace from $CBP_IMAGE_STORE if set,
+/// instead of the one recorded in the trace, e.g. after moving the image store alone
+pub fn image_store_from_env() -> Option<PathBuf> {
+    std::env::var_os("CBP_IMAGE_STORE")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+}
+
+/// Add image content to the image store, unless it is already there
+pub fn store_image(store_dir: &Path, data: &[u8]) -> anyhow::Result<ImageRef> {
+    let image_ref = ImageRef::from_data(data);
+    let path = store_dir.join(image_ref.file_name());
+    if path.exists() {
+        return Ok(image_ref);
+    }
+
+    std::fs::create_dir_all(store_dir)
+        .with_context(|| format!("Failed to create image store {}", store_dir.display()))?;
+    // write to a temporary file first, in case another process is storing the same image
+    let tmp_path = store_dir.join(format!(
+        "{}.tmp.{}",
+        image_ref.file_name(),
+        std::process::id()
+    ));
+    std::fs::write(&tmp_path, data)?;
+    std::fs::rename(&tmp_path, &path)?;
+    Ok(image_ref)
+}
+
+/// Read image content from the image store and check it against the reference
+pub fn load_image(store_dir: &Path, image_ref: &ImageRef) -> anyhow::Result<Vec<u8>> {
+    let path = store_dir.join(image_ref.file_name());
+    let data = std::fs::read(&path)
+        .with_context(|| format!("Failed to read image {} from image store", path.display()))?;
+    if ImageRef::from_data(&data) != *image_ref {
+        bail!("Image {} in image store is corrupted", path.display());
+    }
+    Ok(data)
+}
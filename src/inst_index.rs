@@ -0,0 +1,660 @@
+use crate::{Branch, Image, ImageRef, riscv_instructions};
+use anyhow::{Context, bail};
+use capstone::prelude::*;
+use object::{
+    Architecture, Object, ObjectKind, ObjectSection, ObjectSegment, SectionKind, SegmentFlags,
+};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+/// Gaps between a branch target and the next taken branch longer than this
+/// are unlikely to be straight-line code, and are not estimated
+const MAX_ESTIMATED_GAP: u64 = 64 * 1024;
+
+/// Version of the instruction index cache files,
+/// bump it whenever ImageInstIndex::from_data finds different instructions
+const INST_INDEX_CACHE_VERSION: u64 = 1;
+
+/// Addresses of the static instructions in the text sections of an image,
+/// as linked, i.e. before adding the load base of shared libraries
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ImageInstIndex {
+    /// lowest instruction address
+    pub base: u64,
+    /// sorted offsets of the instructions from base
+    pub offsets: Vec<u32>,
+}
+
+impl ImageInstIndex {
+    /// Disassemble the text sections of the image
+    pub fn from_data(data: &[u8]) -> anyhow::Result<ImageInstIndex> {
+        let file = object::File::parse(data)?;
+        let mut addrs = vec![];
+        for section in file.sections() {
+            if section.kind() == SectionKind::Text {
+                let content = section.data()?;
+                addrs.extend(instruction_addrs(
+                    file.architecture(),
+                    content,
+                    section.address(),
+                )?);
+            }
+        }
+        Self::from_addrs(addrs)
+    }
+
+    /// Disassemble the executable segment containing the address, as linked,
+    /// None if there is no such segment
+    pub fn from_segment(data: &[u8], addr: u64) -> anyhow::Result<Option<ImageInstIndex>> {
+        let file = object::File::parse(data)?;
+        let Some(segment) = file.segments().find(|segment| {
+            is_executable(segment.flags())
+                && addr >= segment.address()
+                && addr - segment.address() < segment.size()
+        }) else {
+            return Ok(None);
+        };
+        let addrs = instruction_addrs(file.architecture(), segment.data()?, segment.address())?;
+        Self::from_addrs(addrs).map(Some)
+    }
+
+    fn from_addrs(mut addrs: Vec<u64>) -> anyhow::Result<ImageInstIndex> {
+        addrs.sort();
+        addrs.dedup();
+
+        let base = addrs.first().copied().unwrap_or(0);
+        let mut offsets = vec![];
+        for addr in addrs {
+            match u32::try_from(addr - base) {
+                Ok(offset) => offsets.push(offset),
+                Err(_) => bail!("Instructions span more than 4 GiB from 0x{:x}", base),
+            }
+        }
+        Ok(ImageInstIndex { base, offsets })
+    }
+
+    /// Load from the cache, or disassemble the image and add it to the cache
+    pub fn from_cache(cache_dir: &Path, data: &[u8]) -> anyhow::Result<ImageInstIndex> {
+        let path = cache_dir.join(format!("{}.insts", ImageRef::from_data(data).file_name()));
+        if let Ok(content) = std::fs::read(&path)
+            && let Some(index) = Self::parse(&content)
+        {
+            return Ok(index);
+        }
+
+        let index = Self::from_data(data)?;
+        // the cache is best effort, e.g. it may be read-only
+        let _ = index.store(cache_dir, &path);
+        Ok(index)
+    }
+
+    /// Cache file: version, base and number of offsets as u64, then the offsets as u32,
+    /// in little endian. Other versions and unsorted offsets are rejected.
+    fn parse(content: &[u8]) -> Option<ImageInstIndex> {
+        let version = u64::from_le_bytes(content.get(0..8)?.try_into().ok()?);
+        if version != INST_INDEX_CACHE_VERSION {
+            return None;
+        }
+        let base = u64::from_le_bytes(content.get(8..16)?.try_into().ok()?);
+        let count = u64::from_le_bytes(content.get(16..24)?.try_into().ok()?);
+        let offsets = content.get(24..)?;
+        if offsets.len() as u64 != count.checked_mul(4)? {
+            return None;
+        }
+        let offsets: Vec<u32> = offsets
+            .chunks_exact(4)
+            .map(|bytes| u32::from_le_bytes(bytes.try_into().unwrap()))
+            .collect();
+        // lookups binary search the offsets
+        if !offsets.is_sorted() {
+            return None;
+        }
+        Some(ImageInstIndex { base, offsets })
+    }
+
+    fn store(&self, cache_dir: &Path, path: &Path) -> anyhow::Result<()> {
+        let mut content = Vec::with_capacity(24 + self.offsets.len() * 4);
+        content.extend_from_slice(&INST_INDEX_CACHE_VERSION.to_le_bytes());
+        content.extend_from_slice(&self.base.to_le_bytes());
+        content.extend_from_slice(&(self.offsets.len() as u64).to_le_bytes());
+        for offset in &self.offsets {
+            content.extend_from_slice(&offset.to_le_bytes());
+        }
+
+        std::fs::create_dir_all(cache_dir)?;
+        // write to a temporary file first, in case another process is caching the same image
+        let mut tmp_path = path.as_os_str().to_owned();
+        tmp_path.push(format!(".tmp.{}", std::process::id()));
+        std::fs::write(&tmp_path, content)?;
+        std::fs::rename(&tmp_path, path)?;
+        Ok(())
+    }
+}
+
+/// Addresses of the instructions in the code linked at the address
+fn instruction_addrs(
+    architecture: Architecture,
+    code: &[u8],
+    address: u64,
+) -> anyhow::Result<Vec<u64>> {
+    let mut cs = match architecture {
+        Architecture::X86_64 => Capstone::new()
+            .x86()
+            .mode(arch::x86::ArchMode::Mode64)
+            .syntax(arch::x86::ArchSyntax::Att)
+            .detail(true)
+            .build()?,
+        Architecture::Aarch64 => Capstone::new()
+            .arm64()
+            .mode(arch::arm64::ArchMode::Arm)
+            .detail(true)
+            .build()?,
+        Architecture::Riscv64 => {
+            return Ok(riscv_instructions(code, address)
+                .map(|(addr, _)| addr)
+                .collect());
+        }
+        _ => bail!("Unsupported architecture {:?}", architecture),
+    };
+    // handle unsupported instructions
+    cs.set_skipdata(true)?;
+    let insns = cs.disasm_all(code, address)?;
+    Ok(insns.as_ref().iter().map(|insn| insn.address()).collect())
+}
+
+fn is_executable(flags: SegmentFlags) -> bool {
+    match flags {
+        SegmentFlags::Elf { p_flags } => p_flags & object::elf::PF_X != 0,
+        _ => false,
+    }
+}
+
+/// Address where the image is loaded, relative to the addresses as linked
+fn load_base(file: &object::File, image: &Image) -> anyhow::Result<u64> {
+    match file.kind() {
+        ObjectKind::Executable => Ok(0),
+        ObjectKind::Dynamic => Ok(image.start),
+        kind => bail!("Unsupported file kind {:?}", kind),
+    }
+}
+
+/// An image in InstIndexMapping at its load address
+#[derive(Debug, Clone)]
+struct MappedImage {
+    /// address of the first instruction after loading
+    start: u64,
+    index: ImageInstIndex,
+    /// instruction index of the first instruction
+    first_index: u64,
+}
+
+/// Mapping from instruction address to instruction index for instruction counting,
+/// the instructions in the text sections of all images are indexed from low to high address.
+/// Executable segments disassembled on demand are indexed after them.
+#[derive(Debug, Clone, Default)]
+pub struct InstIndexMapping {
+    /// sorted by start address
+    images: Vec<MappedImage>,
+    num_insts: u64,
+    /// executable segments disassembled on demand, sorted by start address
+    segments: Vec<MappedImage>,
+    num_segment_insts: u64,
+    /// address ranges of the segments tried on demand
+    tried_segments: Vec<(u64, u64)>,
+}
+
+impl InstIndexMapping {
+    /// Index of the instruction at the address, None if it is not an instruction
+    pub fn get(&self, addr: u64) -> Option<u64> {
+        Self::find(&self.images, addr).or_else(|| Self::find(&self.segments, addr))
+    }
+
+    fn find(images: &[MappedImage], addr: u64) -> Option<u64> {
+        let image = &images[images
+            .partition_point(|image| image.start <= addr)
+            .checked_sub(1)?];
+        let offset = u32::try_from(addr - image.start).ok()?;
+        let position = image.index.offsets.binary_search(&offset).ok()?;
+        Some(image.first_index + position as u64)
+    }
+
+    /// Index of the instruction at the address, disassembling the executable segment
+    /// containing the address on demand if it is outside the text sections,
+    /// e.g. in .init or the PLT
+    pub fn resolve(&mut self, images: &[Image], addr: u64) -> Option<u64> {
+        if let Some(index) = self.get(addr) {
+            return Some(index);
+        }
+        if self
+            .tried_segments
+            .iter()
+            .any(|(start, end)| (*start..*end).contains(&addr))
+        {
+            return None;
+        }
+        let image = images
+            .iter()
+            .find(|image| addr >= image.start && addr - image.start < image.len)?;
+        match self.add_segment(image, addr) {
+            Ok(true) => self.get(addr),
+            Ok(false) => None,
+            Err(err) => {
+                println!(
+                    "Failed to disassemble {} at 0x{:x}: {:?}",
+                    image.filename, addr, err
+                );
+                None
+            }
+        }
+    }
+
+    /// Returns whether an executable segment contains the address
+    fn add_segment(&mut self, image: &Image, addr: u64) -> anyhow::Result<bool> {
+        let file = object::File::parse(image.data.as_slice())?;
+        let load_base = load_base(&file, image)?;
+        let Some(segment) = file.segments().find(|segment| {
+            is_executable(segment.flags())
+                && addr - load_base >= segment.address()
+                && addr - load_base - segment.address() < segment.size()
+        }) else {
+            return Ok(false);
+        };
+        let start = segment.address() + load_base;
+        self.tried_segments.push((start, start + segment.size()));
+
+        let Some(index) = ImageInstIndex::from_segment(&image.data, addr - load_base)? else {
+            return Ok(false);
+        };
+        let num_insts = index.offsets.len() as u64;
+        let segment = MappedImage {
+            start: index.base + load_base,
+            index,
+            first_index: self.num_insts + self.num_segment_insts,
+        };
+        self.num_segment_insts += num_insts;
+        let position = self
+            .segments
+            .partition_point(|other| other.start <= segment.start);
+        self.segments.insert(position, segment);
+        Ok(true)
+    }
+
+    /// Number of static instructions in the text sections of all images
+    pub fn len(&self) -> u64 {
+        self.num_insts
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.num_insts == 0
+    }
+}
+
+/// Taken branches whose instruction counts are not found in the text sections of the images,
+/// e.g. in the PLT, the vDSO or JIT code
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct InstCountFallbacks {
+    /// counted by disassembling the containing executable segment on demand
+    pub disassembled: u64,
+    /// estimated from the code size, assuming instructions as long as the branch
+    pub estimated: u64,
+    /// unknown, only the branch itself is counted
+    pub unknown: u64,
+}
+
+impl InstCountFallbacks {
+    /// Taken branches with inexact instruction counts
+    pub fn inexact(&self) -> u64 {
+        self.estimated + self.unknown
+    }
+
+    /// Accumulate the counts of other, each counted weight times
+    pub fn add_weighted(&mut self, other: InstCountFallbacks, weight: u64) {
+        self.disassembled += other.disassembled * weight;
+        self.estimated += other.estimated * weight;
+        self.unknown += other.unknown * weight;
+    }
+}
+
+/// Instruction indices of a branch, None if not found
+struct BranchInstIndex {
+    inst_addr: u64,
+    targ_addr: u64,
+    inst_length: u64,
+    inst_index: Option<u64>,
+    targ_index: Option<u64>,
+}
+
+/// Count instructions executed at each taken branch from the disassembled images,
+/// falling back to disassembling executable segments on demand,
+/// to an estimate from the code size, or to counting only the branch itself
+pub struct ImageInstCounter {
+    branches: Vec<BranchInstIndex>,
+    /// instruction indices at and above this are from segments disassembled on demand
+    num_text_insts: u64,
+    last_taken_br_index: Option<usize>,
+    pub fallbacks: InstCountFallbacks,
+}
+
+impl ImageInstCounter {
+    pub fn new(mapping: &mut InstIndexMapping, images: &[Image], branches: &[Branch]) -> Self {
+        let mut counter = Self {
+            branches: vec![],
+            num_text_insts: mapping.len(),
+            last_taken_br_index: None,
+            fallbacks: InstCountFallbacks::default(),
+        };
+        for branch in branches {
+            counter.add_branch(mapping, images, branch);
+        }
+        let missing = counter
+            .branches
+            .iter()
+            .filter(|branch| branch.inst_index.is_none() || branch.targ_index.is_none())
+            .count();
+        if missing > 0 {
+            println!(
+                "WARNING: {} of {} branches are outside the disassembled images, their instruction counts may be inexact",
+                missing,
+                branches.len()
+            );
+        }
+        counter
+    }
+
+    /// Number of branches known to the counter
+    pub fn num_branches(&self) -> usize {
+        self.branches.len()
+    }
+
+    /// Add the branch with the next branch index, e.g. when discovered while tracing
+    pub fn add_branch(
+        &mut self,
+        mapping: &mut InstIndexMapping,
+        images: &[Image],
+        branch: &Branch,
+    ) {
+        self.branches.push(BranchInstIndex {
+            inst_addr: branch.inst_addr,
+            targ_addr: branch.targ_addr,
+            inst_length: branch.inst_length as u64,
+            inst_index: mapping.resolve(images, branch.inst_addr),
+            targ_index: mapping.resolve(images, branch.targ_addr),
+        });
+    }
+
+    /// The last taken branch counted, to resume from later
+    pub fn last_taken_br_index(&self) -> Option<usize> {
+        self.last_taken_br_index
+    }
+
+    /// Continue counting after the given taken branch
+    pub fn resume(&mut self, last_taken_br_index: Option<usize>) {
+        self.last_taken_br_index = last_taken_br_index;
+    }
+
+    /// Returns instructions executed since the last taken branch, including the branch itself
+    pub fn count_taken(&mut self, br_index: usize) -> u64 {
+        let instructions = match self.last_taken_br_index {
+            Some(last_br_index) => self.count_between(last_br_index, br_index),
+            None => 0,
+        };
+        self.last_taken_br_index = Some(br_index);
+        instructions
+    }
+
+    /// Count instructions from the target of the last taken branch to the current branch
+    fn count_between(&mut self, last_br_index: usize, br_index: usize) -> u64 {
+        let last = &self.branches[last_br_index];
+        let curr = &self.branches[br_index];
+        let gap = curr.inst_addr.checked_sub(last.targ_addr);
+        if let (Some(from), Some(to), Some(gap)) = (last.targ_index, curr.inst_index, gap)
+            // every instruction takes at least a byte, unless the indices are from different images
+            && to >= from
+            && to - from <= gap
+        {
+            if from >= self.num_text_insts || to >= self.num_text_insts {
+                self.fallbacks.disassembled += 1;
+            }
+            return to - from + 1;
+        }
+        match gap {
+            Some(gap) if gap < MAX_ESTIMATED_GAP => {
+                self.fallbacks.estimated += 1;
+                gap / curr.inst_length.max(1) + 1
+            }
+            _ => {
+                self.fallbacks.unknown += 1;
+                1
+            }
+        }
+    }
+}
+
+/// Directory of the instruction index cache, shared by all traces:
+/// $CBP_INST_INDEX_CACHE if set, otherwise under $XDG_CACHE_HOME or ~/.cache
+pub fn default_inst_index_cache() -> Option<PathBuf> {
+    if let Some(dir) = std::env::var_os("CBP_INST_INDEX_CACHE") {
+        // empty to disable the cache
+        return (!dir.is_empty()).then(|| PathBuf::from(dir));
+    }
+    let cache_home = match std::env::var_os("XDG_CACHE_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(std::env::var_os("HOME")?).join(".cache"),
+    };
+    Some(cache_home.join("cbp-experiments").join("inst-index"))
+}
+
+/// create a mapping from instruction address to instruction index for instruction counting,
+/// the disassembly of each image is cached by content hash if cache_dir is given
+pub fn create_inst_index_mapping_from_images(
+    images: &[Image],
+    cache_dir: Option<&Path>,
+) -> anyhow::Result<InstIndexMapping> {
+    let mut mapping = InstIndexMapping::default();
+    for image in images {
+        let binary_data = image.data.as_slice();
+        let context = || {
+            format!(
+                "{} loaded to 0x{:x} with len {} (file len {})",
+                image.filename,
+                image.start,
+                image.len,
+                binary_data.len()
+            )
+        };
+        let file = object::File::parse(binary_data).with_context(context)?;
+        let load_base = load_base(&file, image).with_context(context)?;
+        let index = match cache_dir {
+            Some(cache_dir) => ImageInstIndex::from_cache(cache_dir, binary_data),
+            None => ImageInstIndex::from_data(binary_data),
+        }
+        .with_context(context)?;
+        if index.offsets.is_empty() {
+            continue;
+        }
+        mapping.images.push(MappedImage {
+            start: index.base + load_base,
+            index,
+            first_index: 0,
+        });
+    }
+
+    // assign index from low to high address
+    mapping.images.sort_by_key(|image| image.start);
+    for image in &mut mapping.images {
+        image.first_index = mapping.num_insts;
+        mapping.num_insts += image.index.offsets.len() as u64;
+    }
+    println!(
+        "Found {} static instructions from {} images",
+        mapping.num_insts,
+        images.len()
+    );
+    Ok(mapping)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        Branch, BranchType, Image, ImageInstCounter, ImageInstIndex, ImageRef, InstCountFallbacks,
+        InstIndexMapping, create_inst_index_mapping_from_images,
+        inst_index::{INST_INDEX_CACHE_VERSION, MappedImage},
+    };
+
+    #[test]
+    fn test_mapping() {
+        let mut mapping = InstIndexMapping::default();
+        for (start, offsets) in [(0x1000, vec![0, 4, 8]), (0x8000, vec![0, 2, 3, 0x100])] {
+            mapping.images.push(MappedImage {
+                start,
+                index: ImageInstIndex { base: 0, offsets },
+                first_index: mapping.num_insts,
+            });
+            mapping.num_insts += mapping.images.last().unwrap().index.offsets.len() as u64;
+        }
+        assert_eq!(mapping.len(), 7);
+        assert_eq!(mapping.get(0x1000), Some(0));
+        assert_eq!(mapping.get(0x1008), Some(2));
+        assert_eq!(mapping.get(0x8003), Some(5));
+        assert_eq!(mapping.get(0x8100), Some(6));
+        // not an instruction
+        assert_eq!(mapping.get(0xfff), None);
+        assert_eq!(mapping.get(0x1002), None);
+        assert_eq!(mapping.get(0x2000), None);
+        assert_eq!(mapping.get(0x1_0000_8000), None);
+    }
+
+    #[test]
+    fn test_cache() {
+        let cache_dir = tempfile::tempdir().unwrap();
+        let data = std::fs::read("/bin/true").unwrap();
+        let index = ImageInstIndex::from_data(&data).unwrap();
+        assert!(!index.offsets.is_empty());
+        assert_eq!(
+            ImageInstIndex::from_cache(cache_dir.path(), &data).unwrap(),
+            index
+        );
+        let path = cache_dir
+            .path()
+            .join(format!("{}.insts", ImageRef::from_data(&data).file_name()));
+        assert!(path.exists());
+
+        // cached content is used instead of disassembling again
+        let fake = ImageInstIndex {
+            base: 0x1000,
+            offsets: vec![0, 1, 2],
+        };
+        fake.store(cache_dir.path(), &path).unwrap();
+        assert_eq!(
+            ImageInstIndex::from_cache(cache_dir.path(), &data).unwrap(),
+            fake
+        );
+
+        // corrupted cache is replaced
+        std::fs::write(&path, [0u8; 7]).unwrap();
+        assert_eq!(
+            ImageInstIndex::from_cache(cache_dir.path(), &data).unwrap(),
+            index
+        );
+
+        // cache of another version is replaced
+        let mut content = std::fs::read(&path).unwrap();
+        content[0..8].copy_from_slice(&(INST_INDEX_CACHE_VERSION + 1).to_le_bytes());
+        std::fs::write(&path, &content).unwrap();
+        assert!(ImageInstIndex::parse(&content).is_none());
+        assert_eq!(
+            ImageInstIndex::from_cache(cache_dir.path(), &data).unwrap(),
+            index
+        );
+
+        // unsorted offsets are rejected
+        let unsorted = ImageInstIndex {
+            base: 0x1000,
+            offsets: vec![0, 2, 1],
+        };
+        unsorted.store(cache_dir.path(), &path).unwrap();
+        assert_eq!(
+            ImageInstIndex::from_cache(cache_dir.path(), &data).unwrap(),
+            index
+        );
+
+        let mapping = create_inst_index_mapping_from_images(
+            &[Image {
+                start: 0x7000_0000,
+                len: data.len() as u64,
+                data: data.clone(),
+                filename: "/bin/true".to_string(),
+            }],
+            Some(cache_dir.path()),
+        )
+        .unwrap();
+        assert_eq!(mapping.len(), index.offsets.len() as u64);
+        // position independent executable is relocated
+        assert_eq!(mapping.get(0x7000_0000 + index.base), Some(0));
+        assert_eq!(
+            mapping.get(0x7000_0000 + index.base + *index.offsets.last().unwrap() as u64),
+            Some(mapping.len() - 1)
+        );
+    }
+
+    #[test]
+    fn test_fallbacks() {
+        let mut mapping = InstIndexMapping::default();
+        mapping.images.push(MappedImage {
+            start: 0x1000,
+            index: ImageInstIndex {
+                base: 0,
+                offsets: vec![0, 4, 8, 12],
+            },
+            first_index: 0,
+        });
+        mapping.num_insts = 4;
+        let branch = |inst_addr, targ_addr| Branch {
+            inst_addr,
+            targ_addr,
+            inst_length: 4,
+            branch_type: BranchType::DirectJump,
+        };
+        let branches = [
+            // within the text section
+            branch(0x100c, 0x1000),
+            // outside of any image
+            branch(0x10_0010, 0x10_0000),
+            branch(0x100c, 0x9000_0000),
+        ];
+        let mut counter = ImageInstCounter::new(&mut mapping, &[], &branches);
+        assert_eq!(counter.count_taken(0), 0);
+        assert_eq!(counter.count_taken(0), 4);
+        assert_eq!(counter.fallbacks, InstCountFallbacks::default());
+        // from the text section to the unknown code
+        assert_eq!(counter.count_taken(1), 1);
+        assert_eq!(counter.fallbacks.unknown, 1);
+        // estimated from the code size
+        assert_eq!(counter.count_taken(1), 5);
+        assert_eq!(counter.fallbacks.estimated, 1);
+        assert_eq!(counter.count_taken(2), 1);
+        assert_eq!(counter.count_taken(0), 1);
+        assert_eq!(counter.fallbacks.unknown, 3);
+        assert_eq!(counter.fallbacks.inexact(), 4);
+
+        // executable segment is disassembled on demand
+        let data = std::fs::read("/bin/true").unwrap();
+        let index = ImageInstIndex::from_data(&data).unwrap();
+        let images = [Image {
+            start: 0x7000_0000,
+            len: data.len() as u64,
+            data,
+            filename: "/bin/true".to_string(),
+        }];
+        let addr = 0x7000_0000 + index.base + index.offsets[1] as u64;
+        let mut mapping = InstIndexMapping::default();
+        assert_eq!(mapping.get(addr), None);
+        let inst_index = mapping.resolve(&images, addr).unwrap();
+        assert_eq!(mapping.get(addr), Some(inst_index));
+        assert!(mapping.resolve(&images, 0x6000_0000).is_none());
+        let mut counter = ImageInstCounter::new(&mut mapping, &images, &[branch(addr, addr)]);
+        counter.count_taken(0);
+        assert_eq!(counter.count_taken(0), 1);
+        assert_eq!(counter.fallbacks.disassembled, 1);
+    }
+}
@@ -1,6 +1,17 @@
+mod bt9;
+mod cbp2025;
+mod champsim;
+mod diff;
+mod dump;
+mod image_store;
+mod inst_index;
 mod path;
 mod simpoint;
 mod simulate;
+mod slice;
+mod source;
+mod symbolize;
+mod synthetic;
 mod tage;
 mod trace;
 mod utils;
@@ -8,10 +19,21 @@ mod utils;
 use cxx::UniquePtr;
 use titlecase::Titlecase;
 
+pub use bt9::*;
+pub use cbp2025::*;
+pub use champsim::*;
+pub use diff::*;
+pub use dump::*;
 pub use ffi::*;
+pub use image_store::*;
+pub use inst_index::*;
 pub use path::*;
 pub use simpoint::*;
 pub use simulate::*;
+pub use slice::*;
+pub use source::*;
+pub use symbolize::*;
+pub use synthetic::*;
 pub use tage::*;
 pub use trace::*;
 pub use utils::*;
@@ -64,6 +86,12 @@ pub fn new_conditional_branch_predictor(name: &str) -> Box<dyn ConditionalBranch
     }
 }
 
+/// Whether the predictor can be simulated more than once in a process,
+/// C++ predictors other than the ideal ones keep their state in globals
+pub fn is_predictor_reusable(name: &str) -> bool {
+    name.starts_with("CustomTage-") || name == "Ideal"
+}
+
 struct CxxConditionalBranchPredictor {
     inner: UniquePtr<ffi::ConditionalBranchPredictor>,
 }
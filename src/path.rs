@@ -2,6 +2,8 @@
 // benchmarks/
 // \- {config-name}/
 //    |- config.json
+//    |- images/
+//       \- {sha256}-{size}
 //    |- simpoint/
 //       |- {benchmark-name}-{command-index}-simpoint-{command-index}.log
 //       |- {benchmark-name}-{command-index}.png
@@ -38,6 +40,11 @@ pub fn get_config_path<P: AsRef<Path>>(config_name: P) -> PathBuf {
         .join("config.json")
 }
 
+/// Image store shared by the traces and slices of a config
+pub fn get_image_store_dir<P: AsRef<Path>>(config_name: P) -> PathBuf {
+    PathBuf::from("benchmarks").join(config_name).join("images")
+}
+
 pub fn get_simpoint_dir<P: AsRef<Path>>(config_name: P) -> PathBuf {
     PathBuf::from("benchmarks")
         .join(config_name)
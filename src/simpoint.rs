@@ -12,6 +12,14 @@ pub struct SimPointPhase {
     pub end_instruction: u64,
 }
 
+impl SimPointPhase {
+    /// Weight of the simulation result of the representative slice,
+    /// since only its second half is simulated after the first half for warmup
+    pub fn simulate_weight(&self) -> u64 {
+        self.weight * 2
+    }
+}
+
 #[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct SimPointResult {
     /// Path to trace file
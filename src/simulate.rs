@@ -1,6 +1,30 @@
-use crate::Branch;
+use crate::{
+    Branch, BranchEvent, BranchType, ConditionalBranchPredictor, IndirectBranchPredictor,
+    InstCountFallbacks, METADATA_WARMUP_INSTRUCTIONS, RoundRobinSource, TraceFileDecoder,
+    TraceFileSource, TraceSource, is_indirect, is_predictor_reusable,
+    new_conditional_branch_predictor, new_indirect_branch_predictor,
+};
+use cxx::UniquePtr;
 use serde::{Deserialize, Serialize};
-use std::path::PathBuf;
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    path::PathBuf,
+    sync::{
+        Arc, Mutex,
+        mpsc::{SyncSender, sync_channel},
+    },
+    thread::{Scope, ScopedJoinHandle},
+};
+
+/// report progress every this many branches
+const PROGRESS_INTERVAL: usize = 1024 * 1024;
+
+/// branches sent to the predictor threads at a time
+const BATCH_SIZE: usize = 4096;
+
+/// batches queued for each predictor thread
+const QUEUED_BATCHES: usize = 16;
 
 #[derive(Serialize, Deserialize, Clone)]
 pub struct SimulateResultBranchInfo {
@@ -12,7 +36,7 @@ pub struct SimulateResultBranchInfo {
     pub mispred_count: u64,
 }
 
-#[derive(Serialize, Deserialize, PartialEq, PartialOrd)]
+#[derive(Serialize, Deserialize, Clone, PartialEq, PartialOrd)]
 pub struct ImageWithoutData {
     pub start: u64,
     pub len: u64,
@@ -53,6 +77,1003 @@ pub struct SimulateResult {
     /// prediction accuracy of indirect branches (%)
     pub indirect_branch_prediction_accuracy: Option<f64>,
 
+    /// taken branches whose instruction counts are not found in the text sections,
+    /// missing in results of older versions
+    #[serde(default)]
+    pub inst_count_fallbacks: InstCountFallbacks,
+
     /// per-branch statistics
     pub branch_info: Vec<SimulateResultBranchInfo>,
 }
+
+impl SimulateResult {
+    /// Sum of the per-branch statistics of the branches matching the filter
+    fn sum(
+        &self,
+        filter: impl Fn(BranchType) -> bool,
+        field: impl Fn(&SimulateResultBranchInfo) -> u64,
+    ) -> u64 {
+        self.branch_info
+            .iter()
+            .filter(|info| filter(info.branch.branch_type))
+            .map(field)
+            .sum()
+    }
+
+    pub fn cond_mispred_count(&self) -> u64 {
+        self.sum(
+            |branch_type| branch_type == BranchType::ConditionalDirectJump,
+            |info| info.mispred_count,
+        )
+    }
+
+    pub fn indirect_mispred_count(&self) -> u64 {
+        self.sum(is_indirect, |info| info.mispred_count)
+    }
+
+    pub fn indirect_execution_count(&self) -> u64 {
+        self.sum(is_indirect, |info| info.execution_count)
+    }
+
+    /// Compute the overall statistics from branch_info, per kilo instructions of simulate
+    pub fn compute_statistics(&mut self) {
+        self.total_mispred_count = self.sum(|_| true, |info| info.mispred_count);
+        self.total_br_execution_count = self.sum(|_| true, |info| info.execution_count);
+        self.total_cond_execution_count = self.sum(
+            |branch_type| branch_type == BranchType::ConditionalDirectJump,
+            |info| info.execution_count,
+        );
+
+        let cond_mispred_count = self.cond_mispred_count();
+        self.cmpki = cond_mispred_count as f64 * 1000.0 / self.simulate as f64;
+        // handle NaN
+        self.cond_branch_prediction_accuracy = Some(
+            100.0 - cond_mispred_count as f64 * 100.0 / self.total_cond_execution_count as f64,
+        );
+
+        let indirect_mispred_count = self.indirect_mispred_count();
+        self.impki = indirect_mispred_count as f64 * 1000.0 / self.simulate as f64;
+        // handle NaN
+        self.indirect_branch_prediction_accuracy = Some(
+            100.0 - indirect_mispred_count as f64 * 100.0 / self.indirect_execution_count() as f64,
+        );
+    }
+}
+
+/// Predictors of multi-threaded traces
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum ThreadMode {
+    /// All threads share one predictor
+    #[default]
+    Shared,
+    /// Each thread has its own predictor, only for reusable predictors
+    PerThread,
+}
+
+/// Order of the branches of threads sharing a predictor
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Interleave {
+    /// In the order recorded in the trace
+    #[default]
+    Recorded,
+    /// Switch threads after a quantum of branches
+    RoundRobin,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum SimulatePhase {
+    Skip,
+    Warmup,
+    Simulate,
+    /// the end of the trace or the simulation
+    Done,
+}
+
+/// Reported when a phase begins and every PROGRESS_INTERVAL branches
+#[derive(Copy, Clone, Debug)]
+pub struct SimulateProgress {
+    pub phase: SimulatePhase,
+    /// instructions executed so far
+    pub instructions: u64,
+    /// instructions into the phase and its length
+    pub position: u64,
+    pub length: u64,
+}
+
+/// Reported for each branch after skipping
+pub struct BranchOutcome<'a> {
+    pub event: &'a BranchEvent,
+    /// counted in the statistics, i.e. after warmup
+    pub measured: bool,
+    /// whether each predictor pair mispredicted the branch
+    pub mispredicted: &'a [bool],
+}
+
+pub type ProgressCallback<'a> = Box<dyn FnMut(&SimulateProgress) + 'a>;
+pub type BranchCallback<'a> = Box<dyn FnMut(&BranchOutcome) + 'a>;
+
+pub struct SimulateOptions<'a> {
+    /// skip count in instructions
+    pub skip: u64,
+    /// warmup count in instructions, defaults to the warmup region marked in the trace or 0
+    pub warmup: Option<u64>,
+    /// simulation count in instructions
+    pub simulate: u64,
+    pub threads: ThreadMode,
+    pub interleave: Interleave,
+    /// branches of each thread per turn in round-robin interleaving
+    pub quantum: u64,
+    /// background threads decoding the trace ahead of simulation, 0 to decode in place
+    pub decode_threads: usize,
+    /// threads simulating the predictors, 0 to simulate on the decoding thread,
+    /// ignored if on_branch is set
+    pub predictor_threads: usize,
+    pub on_progress: Option<ProgressCallback<'a>>,
+    pub on_branch: Option<BranchCallback<'a>>,
+}
+
+impl Default for SimulateOptions<'_> {
+    fn default() -> Self {
+        Self {
+            skip: 0,
+            warmup: None,
+            simulate: 0,
+            threads: ThreadMode::default(),
+            interleave: Interleave::default(),
+            quantum: 10000,
+            decode_threads: 2,
+            predictor_threads: 0,
+            on_progress: None,
+            on_branch: None,
+        }
+    }
+}
+
+impl SimulateOptions<'_> {
+    /// Warmup count in instructions of the trace
+    pub fn resolve_warmup(&self, decoder: &TraceFileDecoder) -> anyhow::Result<u64> {
+        Ok(match self.warmup {
+            Some(warmup) => warmup,
+            None => match decoder.metadata.get(METADATA_WARMUP_INSTRUCTIONS) {
+                Some(warmup) => warmup.parse()?,
+                None => 0,
+            },
+        })
+    }
+
+    fn progress(&self, instructions: u64, warmup: u64) -> SimulateProgress {
+        let (phase, position, length) = if instructions < self.skip {
+            (SimulatePhase::Skip, instructions, self.skip)
+        } else if instructions < self.skip + warmup {
+            (SimulatePhase::Warmup, instructions - self.skip, warmup)
+        } else {
+            (
+                SimulatePhase::Simulate,
+                instructions - self.skip - warmup,
+                self.simulate,
+            )
+        };
+        SimulateProgress {
+            phase,
+            instructions,
+            position,
+            length,
+        }
+    }
+
+    fn report_progress(&mut self, progress: SimulateProgress) {
+        if let Some(on_progress) = &mut self.on_progress {
+            on_progress(&progress);
+        }
+    }
+}
+
+/// Predictors that are not reusable and have been simulated in this process
+static SIMULATED_PREDICTORS: Mutex<Vec<PredictorName>> = Mutex::new(vec![]);
+
+/// Name of a conditional or indirect branch predictor
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum PredictorName {
+    Conditional(String),
+    Indirect(String),
+}
+
+/// Conditional and indirect branch predictors never affect each other, so they are simulated
+/// separately. C++ predictors keep their state in globals, so each distinct predictor is
+/// simulated once and shared by the pairs.
+enum Predictor {
+    Conditional(Box<dyn ConditionalBranchPredictor>),
+    Indirect(UniquePtr<IndirectBranchPredictor>),
+}
+
+impl PredictorName {
+    fn is_reusable(&self) -> bool {
+        let (Self::Conditional(name) | Self::Indirect(name)) = self;
+        is_predictor_reusable(name)
+    }
+
+    /// Fails if any of the predictors has been simulated and is not reusable
+    fn check_unclaimed(names: &[PredictorName], simulated: &[PredictorName]) -> anyhow::Result<()> {
+        for name in names {
+            let (Self::Conditional(predictor) | Self::Indirect(predictor)) = name;
+            anyhow::ensure!(
+                name.is_reusable() || !simulated.contains(name),
+                "Predictor {} keeps its state in globals and has been simulated in this process",
+                predictor
+            );
+        }
+        Ok(())
+    }
+
+    /// Claim all the predictors for a simulation, or none of them if any has been claimed
+    fn claim_all(names: &[PredictorName]) -> anyhow::Result<()> {
+        let mut simulated = SIMULATED_PREDICTORS.lock().unwrap();
+        Self::check_unclaimed(names, &simulated)?;
+        simulated.extend(names.iter().filter(|name| !name.is_reusable()).cloned());
+        Ok(())
+    }
+
+    fn create(&self) -> Predictor {
+        match self {
+            Self::Conditional(name) => {
+                Predictor::Conditional(new_conditional_branch_predictor(name))
+            }
+            Self::Indirect(name) => Predictor::Indirect(new_indirect_branch_predictor(name)),
+        }
+    }
+}
+
+/// A predictor under simulation
+struct Simulation {
+    name: PredictorName,
+    threads: ThreadMode,
+    // predictors of each thread, or a single one if shared
+    predictors: HashMap<u64, Predictor>,
+    // misprediction count of each static branch
+    mispred_counts: Vec<u64>,
+}
+
+impl Simulation {
+    fn new(name: &PredictorName, threads: ThreadMode, num_branches: usize) -> Self {
+        Self {
+            name: name.clone(),
+            threads,
+            predictors: HashMap::new(),
+            mispred_counts: vec![0; num_branches],
+        }
+    }
+
+    /// Predict and train with the branch, counting mispredictions if measured,
+    /// returns whether it is mispredicted
+    fn simulate(&mut self, event: &BranchEvent, measured: bool) -> bool {
+        let predictor_thread_id = match self.threads {
+            ThreadMode::PerThread => event.thread_id,
+            ThreadMode::Shared => 0,
+        };
+        let predictor = self
+            .predictors
+            .entry(predictor_thread_id)
+            .or_insert_with(|| self.name.create());
+
+        let mut mispredicted = false;
+        match predictor {
+            // predict or train conditional branch predictor
+            Predictor::Conditional(conditional_branch_predictor) => {
+                if event.branch_type == BranchType::ConditionalDirectJump {
+                    // requires prediction
+                    let predict =
+                        conditional_branch_predictor.predict(event.inst_addr, event.taken);
+                    mispredicted = predict != event.taken;
+
+                    // update
+                    conditional_branch_predictor.update(
+                        event.inst_addr,
+                        event.branch_type,
+                        event.taken,
+                        predict,
+                        event.targ_addr,
+                    );
+                } else {
+                    // update
+                    conditional_branch_predictor.update_others(
+                        event.inst_addr,
+                        event.branch_type,
+                        true,
+                        event.targ_addr,
+                    );
+                }
+            }
+            // predict or train indirect branch predictor
+            Predictor::Indirect(indirect_branch_predictor) => {
+                if is_indirect(event.branch_type) {
+                    // requires prediction
+                    let predict = indirect_branch_predictor
+                        .pin_mut()
+                        .get_indirect_branch_prediction(
+                            event.inst_addr,
+                            event.branch_type,
+                            event.targ_addr,
+                        );
+                    mispredicted = predict != event.targ_addr;
+                }
+
+                // update
+                indirect_branch_predictor
+                    .pin_mut()
+                    .update_indirect_branch_predictor(
+                        event.inst_addr,
+                        event.branch_type,
+                        event.taken,
+                        event.targ_addr,
+                    );
+            }
+        }
+        if measured {
+            self.mispred_counts[event.br_index] += mispredicted as u64;
+        }
+        mispredicted
+    }
+}
+
+/// Branches and whether they are measured, sent to the predictor threads
+type Batch = Arc<Vec<(BranchEvent, bool)>>;
+
+/// Misprediction counts of the simulations on a predictor thread, by index of predictor
+type ThreadResult = Vec<(usize, Vec<u64>)>;
+
+/// Feed every decoded branch to all simulations, in place or on predictor threads
+enum Simulations<'scope> {
+    InPlace(Vec<Simulation>),
+    Threads {
+        senders: Vec<SyncSender<Batch>>,
+        handles: Vec<ScopedJoinHandle<'scope, ThreadResult>>,
+        batch: Vec<(BranchEvent, bool)>,
+    },
+}
+
+impl<'scope> Simulations<'scope> {
+    fn new<'env>(
+        scope: &'scope Scope<'scope, 'env>,
+        names: &[PredictorName],
+        threads: ThreadMode,
+        predictor_threads: usize,
+        num_branches: usize,
+    ) -> Self {
+        if predictor_threads == 0 {
+            return Self::InPlace(
+                names
+                    .iter()
+                    .map(|name| Simulation::new(name, threads, num_branches))
+                    .collect(),
+            );
+        }
+
+        // distribute the predictors to the threads, they are created on their threads
+        let num_threads = predictor_threads.min(names.len());
+        let mut senders = vec![];
+        let mut handles = vec![];
+        for thread in 0..num_threads {
+            let assigned: Vec<(usize, PredictorName)> = names
+                .iter()
+                .cloned()
+                .enumerate()
+                .skip(thread)
+                .step_by(num_threads)
+                .collect();
+            let (sender, receiver) = sync_channel::<Batch>(QUEUED_BATCHES);
+            senders.push(sender);
+            handles.push(scope.spawn(move || {
+                let mut simulations: Vec<(usize, Simulation)> = assigned
+                    .iter()
+                    .map(|(index, name)| (*index, Simulation::new(name, threads, num_branches)))
+                    .collect();
+                for batch in receiver {
+                    for (event, measured) in batch.iter() {
+                        for (_, simulation) in &mut simulations {
+                            simulation.simulate(event, *measured);
+                        }
+                    }
+                }
+                simulations
+                    .into_iter()
+                    .map(|(index, simulation)| (index, simulation.mispred_counts))
+                    .collect()
+            }));
+        }
+        Self::Threads {
+            senders,
+            handles,
+            batch: Vec::with_capacity(BATCH_SIZE),
+        }
+    }
+
+    /// Simulate the branch, whether each predictor mispredicts is only known in place
+    fn push(
+        &mut self,
+        event: BranchEvent,
+        measured: bool,
+        mispredicted: &mut [bool],
+    ) -> anyhow::Result<()> {
+        match self {
+            Self::InPlace(simulations) => {
+                for (simulation, mispredicted) in simulations.iter_mut().zip(mispredicted) {
+                    *mispredicted = simulation.simulate(&event, measured);
+                }
+            }
+            Self::Threads { batch, .. } => {
+                batch.push((event, measured));
+                if batch.len() == BATCH_SIZE {
+                    self.flush()?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> anyhow::Result<()> {
+        if let Self::Threads { senders, batch, .. } = self
+            && !batch.is_empty()
+        {
+            let batch: Batch = Arc::new(std::mem::replace(batch, Vec::with_capacity(BATCH_SIZE)));
+            for sender in senders.iter() {
+                sender
+                    .send(batch.clone())
+                    .map_err(|_| anyhow::anyhow!("Predictor thread exited unexpectedly"))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Misprediction counts of each predictor
+    fn finish(mut self) -> anyhow::Result<Vec<Vec<u64>>> {
+        self.flush()?;
+        match self {
+            Self::InPlace(simulations) => Ok(simulations
+                .into_iter()
+                .map(|simulation| simulation.mispred_counts)
+                .collect()),
+            Self::Threads {
+                senders, handles, ..
+            } => {
+                // let the threads finish
+                drop(senders);
+                let mut results = vec![];
+                for handle in handles {
+                    results.extend(
+                        handle
+                            .join()
+                            .map_err(|_| anyhow::anyhow!("Predictor thread panicked"))?,
+                    );
+                }
+                results.sort_by_key(|(index, _)| *index);
+                Ok(results
+                    .into_iter()
+                    .map(|(_, mispred_counts)| mispred_counts)
+                    .collect())
+            }
+        }
+    }
+}
+
+/// Simulate a pair of conditional and indirect branch predictors on the trace
+pub fn simulate_trace(
+    decoder: &TraceFileDecoder,
+    conditional_branch_predictor: &str,
+    indirect_branch_predictor: &str,
+    options: SimulateOptions,
+) -> anyhow::Result<SimulateResult> {
+    let pairs = [(
+        conditional_branch_predictor.to_string(),
+        indirect_branch_predictor.to_string(),
+    )];
+    Ok(simulate_trace_pairs(decoder, &pairs, options)?.remove(0))
+}
+
+/// Simulate (conditional, indirect) branch predictor pairs on the trace in a single pass,
+/// returns the result of each pair with trace_path unset.
+/// Predictors that are not reusable can only be simulated once in a process,
+/// and cannot be simulated per thread.
+pub fn simulate_trace_pairs(
+    decoder: &TraceFileDecoder,
+    pairs: &[(String, String)],
+    mut options: SimulateOptions,
+) -> anyhow::Result<Vec<SimulateResult>> {
+    let warmup = options.resolve_warmup(decoder)?;
+
+    // each distinct predictor is simulated once
+    let mut names = vec![];
+    let mut pair_indices = vec![];
+    for (conditional, indirect) in pairs {
+        let mut indices = [0; 2];
+        for (name, index) in [
+            PredictorName::Conditional(conditional.clone()),
+            PredictorName::Indirect(indirect.clone()),
+        ]
+        .into_iter()
+        .zip(&mut indices)
+        {
+            *index = match names.iter().position(|other| *other == name) {
+                Some(index) => index,
+                None => {
+                    names.push(name);
+                    names.len() - 1
+                }
+            };
+        }
+        pair_indices.push(indices);
+    }
+    if options.threads == ThreadMode::PerThread {
+        // instances of a predictor keeping its state in globals would corrupt each other
+        for name in &names {
+            let (PredictorName::Conditional(name) | PredictorName::Indirect(name)) = name;
+            anyhow::ensure!(
+                is_predictor_reusable(name),
+                "Predictor {} keeps its state in globals and cannot have a predictor per thread",
+                name
+            );
+        }
+    }
+    // fail early, but only claim the predictors once the simulation can start
+    PredictorName::check_unclaimed(&names, &SIMULATED_PREDICTORS.lock().unwrap())?;
+
+    let thread_ids = decoder.thread_ids();
+    let mut source: Box<dyn TraceSource> = if thread_ids.len() > 1
+        && options.threads == ThreadMode::Shared
+        && options.interleave == Interleave::RoundRobin
+    {
+        // every thread starts from the beginning
+        let mut threads = vec![];
+        for thread_id in &thread_ids {
+            threads.push(TraceFileSource::thread(
+                decoder,
+                *thread_id,
+                options.decode_threads,
+            )?);
+        }
+        Box::new(RoundRobinSource::new(threads, options.quantum))
+    } else {
+        // jump to the chunk containing the first instruction to simulate
+        Box::new(TraceFileSource::new(
+            decoder,
+            options.skip,
+            options.decode_threads,
+        )?)
+    };
+    // use embedded instruction counts if available, otherwise disassemble the images
+    anyhow::ensure!(
+        source.has_inst_counts(),
+        "Instruction counts are required to simulate"
+    );
+    PredictorName::claim_all(&names)?;
+
+    // execution and taken counts are the same for all predictors
+    let mut execution_counts = vec![0u64; decoder.num_branches];
+    let mut taken_counts = vec![0u64; decoder.num_branches];
+    // per-branch outcomes need the predictions in place
+    let predictor_threads = match options.on_branch {
+        Some(_) => 0,
+        None => options.predictor_threads,
+    };
+
+    // decode once and feed all predictors
+    let mispred_counts = std::thread::scope(|scope| -> anyhow::Result<Vec<Vec<u64>>> {
+        let mut simulations = Simulations::new(
+            scope,
+            &names,
+            options.threads,
+            predictor_threads,
+            decoder.num_branches,
+        );
+        let mut mispredicted = vec![false; names.len()];
+        let mut pair_mispredicted = vec![false; pairs.len()];
+        let mut phase = None;
+        let mut instructions = 0;
+        let mut num_events = 0usize;
+        while let Some(event) = source.next_event()? {
+            instructions = event.instructions;
+            num_events += 1;
+
+            let progress = options.progress(instructions, warmup);
+            if phase != Some(progress.phase) || num_events.is_multiple_of(PROGRESS_INTERVAL) {
+                phase = Some(progress.phase);
+                options.report_progress(progress);
+            }
+
+            if instructions < options.skip {
+                continue;
+            }
+
+            // collect statistics
+            let measured = instructions >= options.skip + warmup;
+            if measured {
+                execution_counts[event.br_index] += 1;
+                taken_counts[event.br_index] += event.taken as u64;
+            }
+
+            simulations.push(event, measured, &mut mispredicted)?;
+            if let Some(on_branch) = &mut options.on_branch {
+                for (pair, [conditional, indirect]) in
+                    pair_mispredicted.iter_mut().zip(&pair_indices)
+                {
+                    *pair = mispredicted[*conditional] || mispredicted[*indirect];
+                }
+                on_branch(&BranchOutcome {
+                    event: &event,
+                    measured,
+                    mispredicted: &pair_mispredicted,
+                });
+            }
+
+            if instructions >= options.skip + warmup + options.simulate {
+                break;
+            }
+        }
+        let mut progress = options.progress(instructions, warmup);
+        progress.phase = SimulatePhase::Done;
+        options.report_progress(progress);
+        simulations.finish()
+    })?;
+
+    let mut images = vec![];
+    for image in decoder.get_images()? {
+        images.push(ImageWithoutData {
+            start: image.start,
+            len: image.len,
+            filename: image.filename,
+        });
+    }
+
+    let inst_count_fallbacks = source.inst_count_fallbacks();
+    let mut results = vec![];
+    for ((conditional, indirect), indices) in pairs.iter().zip(&pair_indices) {
+        let mut result = SimulateResult {
+            trace_path: None,
+            images: images.clone(),
+            conditional_branch_predictor: conditional.clone(),
+            indirect_branch_predictor: indirect.clone(),
+            skip: options.skip,
+            warmup,
+            simulate: options.simulate,
+            total_mispred_count: 0,
+            total_br_execution_count: 0,
+            total_cond_execution_count: 0,
+            cmpki: 0.0,
+            cond_branch_prediction_accuracy: None,
+            impki: 0.0,
+            indirect_branch_prediction_accuracy: None,
+            inst_count_fallbacks,
+            branch_info: vec![],
+        };
+        for (br_index, branch) in decoder.branches.iter().enumerate() {
+            if execution_counts[br_index] > 0 {
+                // conditional and indirect branches are mispredicted by separate predictors
+                result.branch_info.push(SimulateResultBranchInfo {
+                    branch: *branch,
+                    execution_count: execution_counts[br_index],
+                    taken_count: taken_counts[br_index],
+                    mispred_count: indices
+                        .iter()
+                        .map(|index| mispred_counts[*index][br_index])
+                        .sum(),
+                });
+            }
+        }
+        result.compute_statistics();
+        results.push(result);
+    }
+    Ok(results)
+}
+
+/// Images of two combined results that differ,
+/// which is okay if it is a dynamic library or vdso
+#[derive(Clone)]
+pub struct ImageMismatch {
+    pub left: Vec<ImageWithoutData>,
+    pub right: Vec<ImageWithoutData>,
+}
+
+/// Formats as one LEFT or RIGHT line per image
+impl Display for ImageMismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for image in &self.left {
+            writeln!(f, "LEFT : {} at 0x{:x}", image.filename, image.start)?;
+        }
+        for image in &self.right {
+            writeln!(f, "RIGHT: {} at 0x{:x}", image.filename, image.start)?;
+        }
+        Ok(())
+    }
+}
+
+/// Combine weighted simulation results, e.g. of SimPoint phases or different commands.
+/// The statistics are per kilo instructions of total_instructions if given,
+/// otherwise of all simulated instructions.
+/// Also returns the mismatched images between consecutive results.
+pub fn combine_simulate_results(
+    inputs: Vec<(SimulateResult, u64)>,
+    total_instructions: Option<u64>,
+) -> anyhow::Result<(SimulateResult, Vec<ImageMismatch>)> {
+    let mut combined = SimulateResult {
+        trace_path: None,
+        images: vec![],
+        conditional_branch_predictor: String::new(),
+        indirect_branch_predictor: String::new(),
+        skip: 0,
+        warmup: 0,
+        simulate: 0,
+        total_mispred_count: 0,
+        total_br_execution_count: 0,
+        total_cond_execution_count: 0,
+        cmpki: 0.0,
+        cond_branch_prediction_accuracy: None,
+        impki: 0.0,
+        indirect_branch_prediction_accuracy: None,
+        inst_count_fallbacks: InstCountFallbacks::default(),
+        branch_info: vec![],
+    };
+
+    // maintain mapping from branch to index in branch_info array
+    let mut mapping: HashMap<Branch, usize> = HashMap::new();
+    let mut image_mismatches = vec![];
+    for (simulate_result, weight) in inputs {
+        // validate & save metadata
+        if !combined.conditional_branch_predictor.is_empty() {
+            anyhow::ensure!(
+                combined.conditional_branch_predictor
+                    == simulate_result.conditional_branch_predictor,
+                "Cannot combine results of {} and {}",
+                combined.conditional_branch_predictor,
+                simulate_result.conditional_branch_predictor
+            );
+        }
+        if !combined.images.is_empty() && combined.images != simulate_result.images {
+            image_mismatches.push(ImageMismatch {
+                left: combined.images.clone(),
+                right: simulate_result.images.clone(),
+            });
+        }
+        combined.conditional_branch_predictor = simulate_result.conditional_branch_predictor;
+        combined.indirect_branch_predictor = simulate_result.indirect_branch_predictor;
+        combined.images = simulate_result.images;
+
+        combined.simulate += simulate_result.simulate;
+        combined
+            .inst_count_fallbacks
+            .add_weighted(simulate_result.inst_count_fallbacks, weight);
+
+        // merge branch info
+        for info in &simulate_result.branch_info {
+            match mapping.get(&info.branch) {
+                Some(index) => {
+                    let combined_info = &mut combined.branch_info[*index];
+                    combined_info.execution_count += info.execution_count * weight;
+                    combined_info.taken_count += info.taken_count * weight;
+                    combined_info.mispred_count += info.mispred_count * weight;
+                }
+                None => {
+                    mapping.insert(info.branch, combined.branch_info.len());
+                    combined.branch_info.push(SimulateResultBranchInfo {
+                        branch: info.branch,
+                        execution_count: info.execution_count * weight,
+                        taken_count: info.taken_count * weight,
+                        mispred_count: info.mispred_count * weight,
+                    });
+                }
+            }
+        }
+    }
+
+    // if merging simpoint result, use the total count instead
+    if let Some(instructions) = total_instructions {
+        combined.simulate = instructions;
+    }
+    combined.compute_statistics();
+    Ok((combined, image_mismatches))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::PredictorName;
+    use crate::{
+        Branch, BranchType, SimulateOptions, SimulatePhase, SyntheticConfig, SyntheticPattern,
+        ThreadMode, TraceFileDecoder, TraceFileEncoder, combine_simulate_results,
+        generate_synthetic, simulate_trace, simulate_trace_pairs,
+    };
+    use std::{cell::RefCell, collections::HashMap};
+
+    #[test]
+    fn test_simulate_trace() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let mut encoder = TraceFileEncoder::open(file.as_file()).unwrap();
+        generate_synthetic(
+            SyntheticConfig {
+                seed: 1,
+                iterations: 2000,
+                block_size: 4,
+                patterns: vec![
+                    SyntheticPattern::Loop { trip_count: 5 },
+                    SyntheticPattern::Random { bias: 0.5 },
+                    SyntheticPattern::Switch { targets: 4 },
+                ],
+            },
+            &mut encoder,
+        )
+        .unwrap();
+        encoder.finish().unwrap();
+        let content = std::fs::read(file.path()).unwrap();
+        let decoder = TraceFileDecoder::open(&content).unwrap();
+
+        // the ideal predictors never mispredict
+        let pairs = [
+            ("CustomTage-Firestorm".to_string(), "Ideal".to_string()),
+            ("Ideal".to_string(), "Ideal".to_string()),
+        ];
+        let phases = RefCell::new(vec![]);
+        let mispredicted = RefCell::new([0u64; 2]);
+        let results = simulate_trace_pairs(
+            &decoder,
+            &pairs,
+            SimulateOptions {
+                skip: 1000,
+                warmup: Some(1000),
+                simulate: 20000,
+                on_progress: Some(Box::new(|progress| {
+                    phases.borrow_mut().push(progress.phase);
+                })),
+                on_branch: Some(Box::new(|outcome| {
+                    if outcome.measured {
+                        for (count, mispredicted) in mispredicted
+                            .borrow_mut()
+                            .iter_mut()
+                            .zip(outcome.mispredicted)
+                        {
+                            *count += *mispredicted as u64;
+                        }
+                    }
+                })),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(
+            phases.into_inner(),
+            [
+                SimulatePhase::Skip,
+                SimulatePhase::Warmup,
+                SimulatePhase::Simulate,
+                SimulatePhase::Done
+            ]
+        );
+        assert_eq!(results.len(), 2);
+        let mispredicted = mispredicted.into_inner();
+        assert!(mispredicted[0] > 0);
+        assert_eq!(mispredicted[1], 0);
+        for (result, mispredicted) in results.iter().zip(mispredicted) {
+            assert_eq!(result.warmup, 1000);
+            assert_eq!(result.total_mispred_count, mispredicted);
+            assert!(result.total_br_execution_count > result.total_cond_execution_count);
+            assert!(result.total_cond_execution_count > 0);
+        }
+        assert_eq!(results[1].cmpki, 0.0);
+        assert_eq!(
+            results[0].total_mispred_count,
+            results[0].cond_mispred_count()
+        );
+
+        // the same on predictor threads
+        let result = simulate_trace(
+            &decoder,
+            "CustomTage-Firestorm",
+            "Ideal",
+            SimulateOptions {
+                skip: 1000,
+                warmup: Some(1000),
+                simulate: 20000,
+                predictor_threads: 2,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let counts = |result: &crate::SimulateResult| -> HashMap<u64, (u64, u64)> {
+            result
+                .branch_info
+                .iter()
+                .map(|info| {
+                    (
+                        info.branch.inst_addr,
+                        (info.execution_count, info.mispred_count),
+                    )
+                })
+                .collect()
+        };
+        assert_eq!(counts(&result), counts(&results[0]));
+
+        // combining a result twice doubles the counts and keeps the rates
+        let total_mispred_count = result.total_mispred_count;
+        let cmpki = result.cmpki;
+        let (combined, image_mismatches) =
+            combine_simulate_results(vec![(result, 2)], Some(40000)).unwrap();
+        assert!(image_mismatches.is_empty());
+        assert_eq!(combined.total_mispred_count, total_mispred_count * 2);
+        assert!((combined.cmpki - cmpki).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_claim() {
+        // rust and ideal predictors are reusable
+        let reusable = [
+            PredictorName::Conditional("CustomTage-Firestorm".to_string()),
+            PredictorName::Indirect("Ideal".to_string()),
+        ];
+        PredictorName::claim_all(&reusable).unwrap();
+        PredictorName::claim_all(&reusable).unwrap();
+
+        let ittage = PredictorName::Indirect("AndreSeznec-ITTAGE-64KB".to_string());
+        PredictorName::claim_all(std::slice::from_ref(&ittage)).unwrap();
+        assert!(PredictorName::claim_all(std::slice::from_ref(&ittage)).is_err());
+
+        // nothing is claimed if any of the predictors has been claimed
+        let unlimited = PredictorName::Conditional("AndreSeznec-Unlimited".to_string());
+        assert!(PredictorName::claim_all(&[unlimited.clone(), ittage]).is_err());
+        PredictorName::claim_all(&[unlimited]).unwrap();
+    }
+
+    #[test]
+    fn test_per_thread_rejects_globals() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let mut encoder = TraceFileEncoder::open(file.as_file()).unwrap();
+        generate_synthetic(
+            SyntheticConfig {
+                seed: 1,
+                iterations: 100,
+                block_size: 4,
+                patterns: vec![SyntheticPattern::Random { bias: 0.5 }],
+            },
+            &mut encoder,
+        )
+        .unwrap();
+        encoder.finish().unwrap();
+        let content = std::fs::read(file.path()).unwrap();
+        let decoder = TraceFileDecoder::open(&content).unwrap();
+
+        let pairs = [("AndreSeznec-TAGE-SC-L-8KB".to_string(), "Ideal".to_string())];
+        let per_thread = || SimulateOptions {
+            threads: ThreadMode::PerThread,
+            ..Default::default()
+        };
+        assert!(simulate_trace_pairs(&decoder, &pairs, per_thread()).is_err());
+
+        // the rejected predictor is not claimed
+        PredictorName::claim_all(&[PredictorName::Conditional(
+            "AndreSeznec-TAGE-SC-L-8KB".to_string(),
+        )])
+        .unwrap();
+    }
+
+    #[test]
+    fn test_failed_simulation_keeps_predictors_unclaimed() {
+        // no instruction counts
+        let mut content = vec![];
+        let mut encoder = TraceFileEncoder::open_stream(&mut content).unwrap();
+        encoder.branches = vec![Branch {
+            inst_addr: 0x1000,
+            targ_addr: 0x2000,
+            inst_length: 4,
+            branch_type: BranchType::ConditionalDirectJump,
+        }];
+        for i in 0..100 {
+            encoder
+                .record_event_with_branch_index(0, i % 2 == 0)
+                .unwrap();
+        }
+        encoder.finish().unwrap();
+        let decoder = TraceFileDecoder::open(&content).unwrap();
+
+        let pairs = [("AndreSeznec-TAGE-Cookbook".to_string(), "Ideal".to_string())];
+        assert!(simulate_trace_pairs(&decoder, &pairs, SimulateOptions::default()).is_err());
+        PredictorName::claim_all(&[PredictorName::Conditional(
+            "AndreSeznec-TAGE-Cookbook".to_string(),
+        )])
+        .unwrap();
+    }
+}
@@ -0,0 +1,182 @@
+use crate::{
+    Branch, Image, METADATA_SLICE_INSTRUCTIONS, METADATA_WARMUP_INSTRUCTIONS, TraceFileDecoder,
+    TraceFileEncoder, TraceFileSource, TraceSource,
+};
+use std::{fs::File, path::Path};
+
+/// Write a subset of entries of a trace to a new trace,
+/// keeping only the branches and images that are referenced
+pub struct TraceSliceWriter<'a> {
+    pub encoder: TraceFileEncoder<'a>,
+    // branch index in the slice of each branch in the original trace
+    branch_indices: Vec<Option<usize>>,
+}
+
+impl<'a> TraceSliceWriter<'a> {
+    pub fn new(encoder: TraceFileEncoder<'a>, num_branches: usize) -> Self {
+        Self {
+            encoder,
+            branch_indices: vec![None; num_branches],
+        }
+    }
+
+    /// Record an entry of the original trace, along with the instructions executed if taken
+    pub fn record(
+        &mut self,
+        branches: &[Branch],
+        br_index: usize,
+        taken: bool,
+        instructions: u64,
+    ) -> anyhow::Result<()> {
+        let new_br_index = match self.branch_indices[br_index] {
+            Some(index) => index,
+            None => {
+                let index = self.encoder.branches.len();
+                self.encoder.branches.push(branches[br_index]);
+                self.branch_indices[br_index] = Some(index);
+                index
+            }
+        };
+        self.encoder
+            .record_event_with_inst_count(new_br_index, taken, instructions)
+    }
+
+    /// Keep the images containing any of the recorded branches and finish the trace
+    pub fn finish(mut self, images: &[Image]) -> anyhow::Result<()> {
+        let branches = &self.encoder.branches;
+        self.encoder.images = images
+            .iter()
+            .filter(|image| {
+                branches.iter().any(|branch| {
+                    [branch.inst_addr, branch.targ_addr]
+                        .iter()
+                        .any(|addr| *addr >= image.start && *addr < image.start + image.len)
+                })
+            })
+            .cloned()
+            .collect();
+        self.encoder.finish()
+    }
+}
+
+/// Extract instructions [start, end) of the trace to a new trace,
+/// with a warmup region of the preceding instructions at the beginning.
+/// The ranges are recorded in the metadata.
+pub fn slice_trace(
+    decoder: &TraceFileDecoder,
+    output_path: &Path,
+    start: u64,
+    end: u64,
+    warmup: u64,
+) -> anyhow::Result<()> {
+    let begin = start.saturating_sub(warmup);
+
+    // jump to the chunk containing the first instruction
+    let mut source = TraceFileSource::new(decoder, begin, 0)?;
+    anyhow::ensure!(
+        source.has_inst_counts(),
+        "Instruction counts are required for slicing"
+    );
+
+    let output = File::create(output_path)?;
+    let mut encoder = TraceFileEncoder::open(&output)?;
+    encoder.set_entry_encoding(decoder.entry_encoding)?;
+    encoder.metadata = decoder.metadata.clone();
+    encoder.image_store = decoder.image_store.clone();
+    encoder.set_trace_path(output_path);
+    encoder.metadata.insert(
+        METADATA_SLICE_INSTRUCTIONS.to_string(),
+        format!("{}-{}", start, end),
+    );
+    encoder.metadata.insert(
+        METADATA_WARMUP_INSTRUCTIONS.to_string(),
+        (start - begin).to_string(),
+    );
+    let mut writer = TraceSliceWriter::new(encoder, decoder.num_branches);
+
+    while let Some(event) = source.next_event()? {
+        let instructions = event.instructions;
+        if instructions >= end {
+            break;
+        }
+        if instructions >= begin {
+            // instructions before the slice are not counted
+            writer.encoder.set_thread(event.thread_id);
+            writer.record(
+                &decoder.branches,
+                event.br_index,
+                event.taken,
+                event.new_instructions.min(instructions - begin),
+            )?;
+        }
+    }
+
+    writer.finish(&decoder.get_images()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        Branch, BranchType, METADATA_WARMUP_INSTRUCTIONS, TraceFileDecoder, TraceFileEncoder,
+        slice_trace,
+    };
+
+    #[test]
+    fn test_slice() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let mut encoder = TraceFileEncoder::open(file.as_file()).unwrap();
+        encoder.branches = (0..10)
+            .map(|i| Branch {
+                inst_addr: 0x1000 + i * 4,
+                targ_addr: 0x2000,
+                inst_length: 4,
+                branch_type: BranchType::DirectJump,
+            })
+            .collect();
+        // 10 instructions per entry, branch changes every 1000 entries
+        for i in 0..10000 {
+            encoder
+                .record_event_with_inst_count(i / 1000, true, 10)
+                .unwrap();
+        }
+        encoder.finish().unwrap();
+        let content = std::fs::read(file.path()).unwrap();
+        let decoder = TraceFileDecoder::open(&content).unwrap();
+
+        // instructions [30000, 50000) with 5000 warmup instructions
+        let output = tempfile::NamedTempFile::new().unwrap();
+        slice_trace(&decoder, output.path(), 30000, 50000, 5000).unwrap();
+        let content = std::fs::read(output.path()).unwrap();
+        let slice = TraceFileDecoder::open(&content).unwrap();
+        slice.verify().unwrap();
+        assert_eq!(slice.metadata[METADATA_WARMUP_INSTRUCTIONS], "5000");
+        assert_eq!(slice.num_entries, 2500);
+        // only branches 2, 3 and 4 are referenced
+        assert_eq!(slice.num_branches, 3);
+        assert_eq!(slice.branches[0].inst_addr, 0x1000 + 2 * 4);
+        assert!(slice.has_inst_counts());
+    }
+
+    #[test]
+    fn test_slice_without_inst_counts() {
+        // no embedded instruction counts and no images to count them from
+        let mut content = vec![];
+        let mut encoder = TraceFileEncoder::open_stream(&mut content).unwrap();
+        encoder.branches = vec![Branch {
+            inst_addr: 0x1000,
+            targ_addr: 0x2000,
+            inst_length: 4,
+            branch_type: BranchType::DirectJump,
+        }];
+        for _ in 0..100 {
+            encoder.record_event_with_branch_index(0, true).unwrap();
+        }
+        encoder.finish().unwrap();
+        let decoder = TraceFileDecoder::open(&content).unwrap();
+
+        let output = tempfile::NamedTempFile::new().unwrap();
+        for start in [0, 10] {
+            assert!(slice_trace(&decoder, output.path(), start, 50, 0).is_err());
+        }
+    }
+}
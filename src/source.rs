@@ -0,0 +1,377 @@
+use crate::{
+    Branch, BranchType, InstCountFallbacks, ThreadEntryIterator, TraceFileDecoder, TraceFileEncoder,
+};
+use std::collections::{BTreeMap, HashMap};
+
+/// A dynamic branch, decoded from any trace format
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BranchEvent {
+    /// index of the static branch, unique for each (inst_addr, targ_addr) within the source,
+    /// except that conditional branches may learn their target later
+    pub br_index: usize,
+    pub inst_addr: u64,
+    pub targ_addr: u64,
+    pub inst_length: u32,
+    pub branch_type: BranchType,
+    pub taken: bool,
+    /// instructions executed since the last taken branch of the same thread,
+    /// including this branch, zero if not taken
+    pub new_instructions: u64,
+    /// instructions executed so far, updated at taken branches
+    pub instructions: u64,
+    pub thread_id: u64,
+}
+
+impl BranchEvent {
+    pub fn branch(&self) -> Branch {
+        Branch {
+            inst_addr: self.inst_addr,
+            targ_addr: self.targ_addr,
+            inst_length: self.inst_length,
+            branch_type: self.branch_type,
+        }
+    }
+}
+
+/// A trace that yields decoded branch events in order,
+/// implemented for trace files and the imported formats
+pub trait TraceSource {
+    /// Returns None at the end of trace
+    fn next_event(&mut self) -> anyhow::Result<Option<BranchEvent>>;
+
+    /// Whether the events carry instruction counts, otherwise the counts are zero
+    fn has_inst_counts(&self) -> bool;
+
+    /// Provenance of the trace, see METADATA_* for well-known keys
+    fn metadata(&self) -> BTreeMap<String, String> {
+        BTreeMap::new()
+    }
+
+    /// Taken branches so far whose instruction counts are not exact
+    /// or need disassembly outside the text sections
+    fn inst_count_fallbacks(&self) -> InstCountFallbacks {
+        InstCountFallbacks::default()
+    }
+}
+
+/// Events of a trace file, with embedded instruction counts or counted from the images
+pub struct TraceFileSource<'a> {
+    decoder: &'a TraceFileDecoder<'a>,
+    entries: ThreadEntryIterator<'a>,
+    instructions: u64,
+}
+
+impl<'a> TraceFileSource<'a> {
+    /// Events of all threads from the chunk containing the given instruction,
+    /// i.e. the events before the instruction in the chunk are included.
+    /// If decode_threads is not zero, the entries are decoded ahead on background threads.
+    pub fn new(
+        decoder: &'a TraceFileDecoder<'a>,
+        instruction: u64,
+        decode_threads: usize,
+    ) -> anyhow::Result<Self> {
+        let seek = decoder.seek_to_instruction(instruction)?;
+        let instructions = seek.instruction_offset;
+        Ok(Self {
+            decoder,
+            entries: decoder.thread_entries(seek, None, decode_threads)?,
+            instructions,
+        })
+    }
+
+    /// Events of a single thread from the beginning, counting the instructions of the thread
+    pub fn thread(
+        decoder: &'a TraceFileDecoder<'a>,
+        thread_id: u64,
+        decode_threads: usize,
+    ) -> anyhow::Result<Self> {
+        let seek = decoder.seek_to_instruction(0)?;
+        Ok(Self {
+            decoder,
+            entries: decoder.thread_entries(seek, Some(thread_id), decode_threads)?,
+            instructions: 0,
+        })
+    }
+
+    /// Continue from the chunk containing the given instruction, reusing the instruction counter
+    pub fn seek_to_instruction(&mut self, instruction: u64) -> anyhow::Result<()> {
+        let seek = self.decoder.seek_to_instruction(instruction)?;
+        self.instructions = seek.instruction_offset;
+        self.entries.resume(self.decoder, seek)
+    }
+
+    /// Instructions executed before the next event
+    pub fn instructions(&self) -> u64 {
+        self.instructions
+    }
+}
+
+impl TraceSource for TraceFileSource<'_> {
+    fn next_event(&mut self) -> anyhow::Result<Option<BranchEvent>> {
+        let Some(thread_entry) = self.entries.next() else {
+            return Ok(None);
+        };
+        self.instructions += thread_entry.instructions;
+        let br_index = thread_entry.entry.get_br_index();
+        let branch = &self.decoder.branches[br_index];
+        Ok(Some(BranchEvent {
+            br_index,
+            inst_addr: branch.inst_addr,
+            targ_addr: branch.targ_addr,
+            inst_length: branch.inst_length,
+            branch_type: branch.branch_type,
+            taken: thread_entry.entry.get_taken(),
+            new_instructions: thread_entry.instructions,
+            instructions: self.instructions,
+            thread_id: thread_entry.thread_id,
+        }))
+    }
+
+    fn has_inst_counts(&self) -> bool {
+        self.entries.has_inst_counts()
+    }
+
+    fn metadata(&self) -> BTreeMap<String, String> {
+        self.decoder.metadata.clone()
+    }
+
+    fn inst_count_fallbacks(&self) -> InstCountFallbacks {
+        self.entries.inst_count_fallbacks()
+    }
+}
+
+/// Assign branch indices to the events of parsed formats
+#[derive(Default)]
+pub(crate) struct BranchIndexer {
+    mapping: HashMap<(u64, u64), usize>,
+    /// conditional branches by PC, since their target may be unknown when not taken
+    conditional_branches: HashMap<u64, usize>,
+    /// learned target of conditional branches, by branch index
+    targets: Vec<u64>,
+}
+
+impl BranchIndexer {
+    /// Returns the branch index and the target, which is learned for conditional branches
+    pub(crate) fn index(
+        &mut self,
+        inst_addr: u64,
+        targ_addr: u64,
+        branch_type: BranchType,
+        taken: bool,
+    ) -> (usize, u64) {
+        let next_index = self.targets.len();
+        if branch_type == BranchType::ConditionalDirectJump {
+            let br_index = *self
+                .conditional_branches
+                .entry(inst_addr)
+                .or_insert(next_index);
+            if br_index == next_index {
+                self.targets.push(targ_addr);
+            }
+            if taken && self.targets[br_index] == 0 {
+                self.targets[br_index] = targ_addr;
+            }
+            (br_index, self.targets[br_index])
+        } else {
+            let br_index = *self
+                .mapping
+                .entry((inst_addr, targ_addr))
+                .or_insert(next_index);
+            if br_index == next_index {
+                self.targets.push(targ_addr);
+            }
+            (br_index, targ_addr)
+        }
+    }
+}
+
+/// Interleave the threads by taking a quantum of branches from each thread in turn
+pub struct RoundRobinSource<'a> {
+    threads: Vec<TraceFileSource<'a>>,
+    quantum: u64,
+    current: usize,
+    // branches left in the turn of current thread
+    remaining: u64,
+    instructions: u64,
+    // of the threads that have ended
+    fallbacks: InstCountFallbacks,
+}
+
+impl<'a> RoundRobinSource<'a> {
+    pub fn new(threads: Vec<TraceFileSource<'a>>, quantum: u64) -> Self {
+        Self {
+            threads,
+            quantum: quantum.max(1),
+            current: 0,
+            remaining: quantum.max(1),
+            instructions: 0,
+            fallbacks: InstCountFallbacks::default(),
+        }
+    }
+}
+
+impl TraceSource for RoundRobinSource<'_> {
+    fn next_event(&mut self) -> anyhow::Result<Option<BranchEvent>> {
+        while !self.threads.is_empty() {
+            if self.remaining == 0 {
+                self.current = (self.current + 1) % self.threads.len();
+                self.remaining = self.quantum;
+            }
+            match self.threads[self.current].next_event()? {
+                Some(mut event) => {
+                    self.remaining -= 1;
+                    // instructions of all threads so far
+                    self.instructions += event.new_instructions;
+                    event.instructions = self.instructions;
+                    return Ok(Some(event));
+                }
+                None => {
+                    // the thread ends, continue with the next one
+                    let thread = self.threads.remove(self.current);
+                    self.fallbacks
+                        .add_weighted(thread.inst_count_fallbacks(), 1);
+                    if self.current == self.threads.len() {
+                        self.current = 0;
+                    }
+                    self.remaining = self.quantum;
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    fn has_inst_counts(&self) -> bool {
+        self.threads.iter().all(|thread| thread.has_inst_counts())
+    }
+
+    fn inst_count_fallbacks(&self) -> InstCountFallbacks {
+        let mut fallbacks = self.fallbacks;
+        for thread in &self.threads {
+            fallbacks.add_weighted(thread.inst_count_fallbacks(), 1);
+        }
+        fallbacks
+    }
+}
+
+/// Record all events of the source to the trace, with the instruction counts if available.
+/// Conditional branches are keyed by PC, and take the first known target.
+pub fn import_source(
+    source: &mut impl TraceSource,
+    encoder: &mut TraceFileEncoder,
+) -> anyhow::Result<()> {
+    encoder.metadata.extend(source.metadata());
+    let has_inst_counts = source.has_inst_counts();
+    // branch index in the encoder of each branch index in the source
+    let mut branch_indices: Vec<Option<usize>> = vec![];
+    while let Some(event) = source.next_event()? {
+        if event.br_index >= branch_indices.len() {
+            branch_indices.resize(event.br_index + 1, None);
+        }
+        let br_index = match branch_indices[event.br_index] {
+            Some(br_index) => br_index,
+            None => {
+                let br_index = encoder.branches.len();
+                encoder.branches.push(event.branch());
+                branch_indices[event.br_index] = Some(br_index);
+                br_index
+            }
+        };
+        // the target of conditional branches may be learned later
+        let branch = &mut encoder.branches[br_index];
+        if branch.targ_addr == 0 {
+            branch.targ_addr = event.targ_addr;
+        }
+
+        encoder.set_thread(event.thread_id);
+        if has_inst_counts {
+            encoder.record_event_with_inst_count(br_index, event.taken, event.new_instructions)?;
+        } else {
+            encoder.record_event_with_branch_index(br_index, event.taken)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        Branch, BranchIndexer, BranchType, TraceFileDecoder, TraceFileEncoder, TraceFileSource,
+        TraceSource, import_source,
+    };
+
+    #[test]
+    fn test_trace_file_source() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let mut encoder = TraceFileEncoder::open(file.as_file()).unwrap();
+        encoder.branches = vec![
+            Branch {
+                inst_addr: 0x1000,
+                targ_addr: 0x2000,
+                inst_length: 4,
+                branch_type: BranchType::ConditionalDirectJump,
+            },
+            Branch {
+                inst_addr: 0x2010,
+                targ_addr: 0x1000,
+                inst_length: 4,
+                branch_type: BranchType::DirectJump,
+            },
+        ];
+        for _ in 0..100 {
+            encoder.record_event_with_inst_count(0, false, 0).unwrap();
+            encoder.record_event_with_inst_count(1, true, 6).unwrap();
+        }
+        encoder.finish().unwrap();
+        let content = std::fs::read(file.path()).unwrap();
+        let decoder = TraceFileDecoder::open(&content).unwrap();
+
+        let mut source = TraceFileSource::new(&decoder, 0, 0).unwrap();
+        assert!(source.has_inst_counts());
+        let mut num_events = 0;
+        while let Some(event) = source.next_event().unwrap() {
+            num_events += 1;
+            assert_eq!(event.branch(), decoder.branches[event.br_index]);
+            if event.taken {
+                assert_eq!(event.new_instructions, 6);
+            } else {
+                assert_eq!(event.new_instructions, 0);
+            }
+            assert_eq!(event.instructions, num_events / 2 * 6);
+        }
+        assert_eq!(num_events, 200);
+        assert_eq!(source.instructions(), 600);
+
+        // import into another trace
+        let output = tempfile::NamedTempFile::new().unwrap();
+        let mut encoder = TraceFileEncoder::open(output.as_file()).unwrap();
+        import_source(
+            &mut TraceFileSource::new(&decoder, 0, 0).unwrap(),
+            &mut encoder,
+        )
+        .unwrap();
+        encoder.finish().unwrap();
+        let content = std::fs::read(output.path()).unwrap();
+        let imported = TraceFileDecoder::open(&content).unwrap();
+        assert_eq!(imported.branches, decoder.branches);
+        assert_eq!(imported.num_entries, 200);
+        assert!(imported.has_inst_counts());
+        let mut source = TraceFileSource::new(&imported, 0, 0).unwrap();
+        while source.next_event().unwrap().is_some() {}
+        assert_eq!(source.instructions(), 600);
+    }
+
+    #[test]
+    fn test_branch_indexer() {
+        let mut indexer = BranchIndexer::default();
+        let cond = BranchType::ConditionalDirectJump;
+        // target of conditional branch is learned when taken
+        assert_eq!(indexer.index(0x1000, 0, cond, false), (0, 0));
+        assert_eq!(indexer.index(0x1000, 0x2000, cond, true), (0, 0x2000));
+        assert_eq!(indexer.index(0x1000, 0, cond, false), (0, 0x2000));
+        // other branches are keyed by target
+        let indirect = BranchType::IndirectJump;
+        assert_eq!(indexer.index(0x3000, 0x4000, indirect, true), (1, 0x4000));
+        assert_eq!(indexer.index(0x3000, 0x5000, indirect, true), (2, 0x5000));
+        assert_eq!(indexer.index(0x3000, 0x4000, indirect, true), (1, 0x4000));
+    }
+}
@@ -0,0 +1,301 @@
+use crate::{Image, ImageWithoutData};
+use object::{Object, ObjectKind, ObjectSegment, ObjectSymbol, SymbolKind};
+use std::{cell::OnceCell, collections::HashMap, fmt::Display};
+
+/// Function and source location of an address, possibly inlined into its caller
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SymbolFrame {
+    /// demangled function name
+    pub function: Option<String>,
+    pub file: Option<String>,
+    pub line: Option<u32>,
+}
+
+/// A runtime address resolved by Symbolizer, fields are None if unknown
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AddrInfo {
+    pub addr: u64,
+    /// file name of the image containing the address, and the offset from its start
+    pub image: Option<(String, u64)>,
+    /// symbol+0xoffset from the symbol table, or the outermost function from DWARF
+    pub symbol: Option<String>,
+    /// from the innermost inlined function to the outermost function
+    pub frames: Vec<SymbolFrame>,
+}
+
+impl AddrInfo {
+    /// image:0xoffset, or unknown:0xaddr if outside the images
+    pub fn image_offset(&self) -> String {
+        match &self.image {
+            Some((filename, offset)) => format!("{}:0x{:x}", filename, offset),
+            None => format!("unknown:0x{:x}", self.addr),
+        }
+    }
+
+    /// file:line of the innermost frame
+    pub fn source_location(&self) -> Option<String> {
+        let frame = self.frames.first()?;
+        match (&frame.file, frame.line) {
+            (Some(file), Some(line)) => Some(format!("{}:{}", file, line)),
+            (Some(file), None) => Some(file.clone()),
+            _ => None,
+        }
+    }
+
+    /// Functions from the outermost to the innermost, e.g. `main > foo > bar` if foo and bar are inlined
+    pub fn inline_chain(&self) -> Option<String> {
+        let functions: Vec<&str> = self
+            .frames
+            .iter()
+            .rev()
+            .filter_map(|frame| frame.function.as_deref())
+            .collect();
+        (!functions.is_empty()).then(|| functions.join(" > "))
+    }
+}
+
+/// Formats as image:0xoffset <symbol+0xoffset>
+impl Display for AddrInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.image_offset())?;
+        if let Some(symbol) = &self.symbol {
+            write!(f, " <{}>", symbol)?;
+        }
+        Ok(())
+    }
+}
+
+/// Symbols of a loaded image
+struct ImageSymbols {
+    filename: String,
+    start: u64,
+    len: u64,
+    /// subtracted from runtime address to get the address in ELF
+    load_bias: u64,
+    /// (address in ELF, size, name) sorted by address
+    symbols: Vec<(u64, u64, String)>,
+    /// whether DWARF of the image on disk can be used, e.g. it is the same file
+    has_dwarf: bool,
+    /// loaded on first use
+    loader: OnceCell<Option<addr2line::Loader>>,
+}
+
+impl ImageSymbols {
+    fn new(start: u64, len: u64, filename: &str, data: Option<&[u8]>) -> Self {
+        let mut load_bias = 0;
+        let mut symbols = vec![];
+        if let Some(file) = data.and_then(|data| object::File::parse(data).ok()) {
+            if file.kind() == ObjectKind::Dynamic {
+                // the first segment is mapped at the start of the image
+                let first_segment = file
+                    .segments()
+                    .map(|segment| segment.address())
+                    .min()
+                    .unwrap_or(0);
+                load_bias = start.wrapping_sub(first_segment & !0xfff);
+            }
+            for symbol in file.symbols().chain(file.dynamic_symbols()) {
+                if symbol.kind() == SymbolKind::Text
+                    && symbol.address() != 0
+                    && let Ok(name) = symbol.name()
+                {
+                    symbols.push((symbol.address(), symbol.size(), name.to_string()));
+                }
+            }
+        }
+        symbols.sort();
+        symbols.dedup_by_key(|(addr, _, _)| *addr);
+        Self {
+            filename: filename.to_string(),
+            start,
+            len,
+            load_bias,
+            symbols,
+            has_dwarf: std::fs::metadata(filename)
+                .is_ok_and(|metadata| data.is_none_or(|data| metadata.len() == data.len() as u64)),
+            loader: OnceCell::new(),
+        }
+    }
+
+    fn loader(&self) -> Option<&addr2line::Loader> {
+        self.loader
+            .get_or_init(|| {
+                self.has_dwarf
+                    .then(|| addr2line::Loader::new(&self.filename).ok())
+                    .flatten()
+            })
+            .as_ref()
+    }
+
+    fn resolve(&self, addr: u64) -> AddrInfo {
+        let probe = addr.wrapping_sub(self.load_bias);
+        let mut info = AddrInfo {
+            addr,
+            image: Some((self.filename.clone(), addr - self.start)),
+            ..Default::default()
+        };
+
+        let index = self
+            .symbols
+            .partition_point(|(start, _, _)| *start <= probe);
+        if index > 0 {
+            let (start, size, name) = &self.symbols[index - 1];
+            if *size == 0 || probe < start + size {
+                info.symbol = Some(format!("{}+0x{:x}", name, probe - start));
+            }
+        }
+
+        if let Some(loader) = self.loader()
+            && let Ok(mut frames) = loader.find_frames(probe)
+        {
+            while let Ok(Some(frame)) = frames.next() {
+                let function = frame
+                    .function
+                    .and_then(|function| function.demangle().ok().map(|name| name.to_string()));
+                let location = frame.location;
+                info.frames.push(SymbolFrame {
+                    function,
+                    file: location.as_ref().and_then(|l| l.file.map(str::to_string)),
+                    line: location.and_then(|l| l.line),
+                });
+            }
+        }
+        if info.symbol.is_none() {
+            // outermost function from DWARF
+            info.symbol = info
+                .frames
+                .iter()
+                .rev()
+                .find_map(|frame| frame.function.clone());
+        }
+        info
+    }
+}
+
+/// Resolve runtime addresses to symbol+offset, inlined functions and source locations,
+/// using the ELF symbol table of the images and the DWARF of the images on disk.
+/// Each image is loaded once and the lookups are cached, lookup failures leave fields unknown.
+pub struct Symbolizer {
+    images: Vec<ImageSymbols>,
+    cache: HashMap<u64, AddrInfo>,
+}
+
+impl Symbolizer {
+    /// Images that fail to parse have no symbols
+    pub fn new(images: &[Image]) -> Self {
+        Self::from_images(
+            images
+                .iter()
+                .map(|image| {
+                    ImageSymbols::new(image.start, image.len, &image.filename, Some(&image.data))
+                })
+                .collect(),
+        )
+    }
+
+    /// Read the images from disk, e.g. for simulation results without image data
+    pub fn from_disk(images: &[ImageWithoutData]) -> Self {
+        Self::from_images(
+            images
+                .iter()
+                .map(|image| {
+                    let data = std::fs::read(&image.filename).ok();
+                    ImageSymbols::new(image.start, image.len, &image.filename, data.as_deref())
+                })
+                .collect(),
+        )
+    }
+
+    fn from_images(images: Vec<ImageSymbols>) -> Self {
+        Self {
+            images,
+            cache: HashMap::new(),
+        }
+    }
+
+    /// Resolve the runtime address
+    pub fn lookup(&mut self, addr: u64) -> &AddrInfo {
+        let images = &self.images;
+        self.cache.entry(addr).or_insert_with(|| {
+            match images
+                .iter()
+                .find(|image| addr >= image.start && addr - image.start < image.len)
+            {
+                Some(image) => image.resolve(addr),
+                None => AddrInfo {
+                    addr,
+                    ..Default::default()
+                },
+            }
+        })
+    }
+
+    /// Format as symbol+0xoffset, None if unknown
+    pub fn symbolize(&mut self, addr: u64) -> Option<String> {
+        self.lookup(addr).symbol.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{ImageWithoutData, Symbolizer};
+
+    #[inline(never)]
+    fn symbolize_target() -> u64 {
+        std::hint::black_box(42)
+    }
+
+    #[test]
+    fn test_symbolize() {
+        // the mapping of the test executable
+        let exe = std::fs::canonicalize(std::env::current_exe().unwrap()).unwrap();
+        let maps = std::fs::read_to_string("/proc/self/maps").unwrap();
+        let mut start = u64::MAX;
+        let mut end = 0;
+        for line in maps.lines() {
+            if line.ends_with(exe.to_str().unwrap()) {
+                let (range, _) = line.split_once(' ').unwrap();
+                let (low, high) = range.split_once('-').unwrap();
+                start = start.min(u64::from_str_radix(low, 16).unwrap());
+                end = end.max(u64::from_str_radix(high, 16).unwrap());
+            }
+        }
+        assert!(start < end);
+        let mut symbolizer = Symbolizer::from_disk(&[
+            ImageWithoutData {
+                start,
+                len: end - start,
+                filename: exe.to_str().unwrap().to_string(),
+            },
+            ImageWithoutData {
+                start: 0x1000,
+                len: 0x1000,
+                filename: "/nonexistent".to_string(),
+            },
+        ]);
+
+        assert_eq!(symbolize_target(), 42);
+        let addr = symbolize_target as *const () as u64;
+        let info = symbolizer.lookup(addr).clone();
+        assert_eq!(info.image.as_ref().unwrap().1, addr - start);
+        let symbol = info.symbol.as_ref().unwrap();
+        assert!(symbol.contains("symbolize_target"), "{}", symbol);
+        assert!(symbol.ends_with("+0x0"), "{}", symbol);
+        assert!(
+            info.inline_chain()
+                .unwrap()
+                .ends_with("tests::symbolize_target")
+        );
+        assert!(info.source_location().unwrap().contains("symbolize.rs"));
+        // cached
+        assert_eq!(symbolizer.lookup(addr), &info);
+        assert_eq!(symbolizer.cache.len(), 1);
+
+        // unknown addresses
+        let info = symbolizer.lookup(0x1800);
+        assert_eq!(info.to_string(), "/nonexistent:0x800");
+        assert_eq!(info.symbol, None);
+        assert_eq!(info.source_location(), None);
+        assert_eq!(symbolizer.lookup(0x10).image_offset(), "unknown:0x10");
+    }
+}
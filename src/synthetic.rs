@@ -0,0 +1,450 @@
+//! Synthetic traces of small programs made of branch patterns, such as loops, correlated,
+//! random and indirect branches and nested calls, for testing predictors on known behaviour.
+//!
+//! The code begins at 0x400000 with fixed-width 4-byte instructions. Each basic block has
+//! block_size instructions ending with its branch. The blocks of the patterns are laid out
+//! in order, followed by one block jumping back to the first pattern, then the called
+//! functions of the patterns. No images are generated.
+use crate::{
+    BranchEvent, BranchIndexer, BranchType, METADATA_TRACER, TraceFileEncoder, TraceSource,
+    import_source,
+};
+use anyhow::{bail, ensure};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, VecDeque},
+    path::Path,
+};
+
+/// Base address of the generated code
+const SYNTHETIC_BASE_ADDR: u64 = 0x400000;
+/// All instructions are 4 bytes
+const SYNTHETIC_INST_LENGTH: u32 = 4;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum SyntheticPattern {
+    /// A loop whose backward conditional branch is taken trip_count - 1 times
+    Loop { trip_count: u64 },
+    /// A random branch, followed by distance - 1 unconditional jumps,
+    /// then a conditional branch in the same direction as the random branch
+    Correlated { distance: u64 },
+    /// A conditional branch taken with the given probability
+    Random { bias: f64 },
+    /// An indirect jump to one of the targets uniformly, each of them jumps back
+    Switch { targets: u64 },
+    /// Nested calls of the given depth, followed by the returns
+    Calls { depth: u64 },
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SyntheticConfig {
+    /// Seed of the pseudo random generator
+    #[serde(default)]
+    pub seed: u64,
+    /// Times the patterns are executed in sequence
+    pub iterations: u64,
+    /// Instructions in each basic block, including the branch ending it
+    #[serde(default = "default_block_size")]
+    pub block_size: u64,
+    /// Patterns laid out one after another, then jumping back to the first one
+    pub patterns: Vec<SyntheticPattern>,
+}
+
+fn default_block_size() -> u64 {
+    4
+}
+
+impl SyntheticConfig {
+    pub fn new<P: AsRef<Path>>(path: P) -> anyhow::Result<SyntheticConfig> {
+        Ok(toml::from_str(&std::fs::read_to_string(path)?)?)
+    }
+}
+
+impl SyntheticPattern {
+    /// Number of basic blocks in the code of the pattern
+    fn num_blocks(&self) -> u64 {
+        match self {
+            SyntheticPattern::Loop { .. }
+            | SyntheticPattern::Random { .. }
+            | SyntheticPattern::Calls { .. } => 1,
+            SyntheticPattern::Correlated { distance } => distance + 1,
+            SyntheticPattern::Switch { targets } => targets + 1,
+        }
+    }
+
+    /// Number of basic blocks placed after all patterns, i.e. the called functions
+    fn num_outlined_blocks(&self) -> u64 {
+        match self {
+            SyntheticPattern::Calls { depth } => depth * 2 - 1,
+            _ => 0,
+        }
+    }
+}
+
+/// xorshift64*, good enough for synthetic branches and reproducible across platforms
+struct SyntheticRng(u64);
+
+impl SyntheticRng {
+    fn new(seed: u64) -> Self {
+        // splitmix64 to avoid the all-zero state
+        let mut z = seed.wrapping_add(0x9e3779b97f4a7c15);
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        Self((z ^ (z >> 31)) | 1)
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545f4914f6cdd1d)
+    }
+
+    /// Uniform in [0, 1)
+    fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    /// Uniform in [0, n)
+    fn below(&mut self, n: u64) -> u64 {
+        self.next_u64() % n
+    }
+}
+
+/// Events of a synthetic program described by SyntheticConfig, with instruction counts
+pub struct SyntheticSource {
+    config: SyntheticConfig,
+    /// address of the first block of each pattern, and of the jump back at the end
+    bases: Vec<u64>,
+    /// address of the first outlined block of each pattern
+    outlined_bases: Vec<u64>,
+    rng: SyntheticRng,
+    indexer: BranchIndexer,
+    events: VecDeque<BranchEvent>,
+    iteration: u64,
+    pattern_index: usize,
+    // instructions since the last taken branch
+    pending: u64,
+    instructions: u64,
+}
+
+impl SyntheticSource {
+    pub fn new(config: SyntheticConfig) -> anyhow::Result<Self> {
+        ensure!(config.block_size >= 1, "Block size must be at least 1");
+        ensure!(!config.patterns.is_empty(), "No patterns are given");
+        for pattern in &config.patterns {
+            match pattern {
+                SyntheticPattern::Loop { trip_count } => {
+                    ensure!(*trip_count >= 1, "Loop trip count must be at least 1")
+                }
+                SyntheticPattern::Correlated { distance } => {
+                    ensure!(*distance >= 1, "Correlation distance must be at least 1")
+                }
+                SyntheticPattern::Random { bias } => {
+                    if !(0.0..=1.0).contains(bias) {
+                        bail!("Bias {} is not within [0, 1]", bias);
+                    }
+                }
+                SyntheticPattern::Switch { targets } => {
+                    ensure!(*targets >= 1, "Switch must have at least 1 target")
+                }
+                SyntheticPattern::Calls { depth } => {
+                    ensure!(*depth >= 1, "Call depth must be at least 1")
+                }
+            }
+        }
+
+        let block_bytes = config.block_size * SYNTHETIC_INST_LENGTH as u64;
+        let mut bases = vec![];
+        let mut addr = SYNTHETIC_BASE_ADDR;
+        for pattern in &config.patterns {
+            bases.push(addr);
+            addr += pattern.num_blocks() * block_bytes;
+        }
+        bases.push(addr);
+        addr += block_bytes;
+        let mut outlined_bases = vec![];
+        for pattern in &config.patterns {
+            outlined_bases.push(addr);
+            addr += pattern.num_outlined_blocks() * block_bytes;
+        }
+
+        Ok(Self {
+            rng: SyntheticRng::new(config.seed),
+            config,
+            bases,
+            outlined_bases,
+            indexer: BranchIndexer::default(),
+            events: VecDeque::new(),
+            iteration: 0,
+            pattern_index: 0,
+            pending: 0,
+            instructions: 0,
+        })
+    }
+
+    /// Address of the given block of the current pattern
+    fn block(&self, index: u64) -> u64 {
+        self.bases[self.pattern_index]
+            + index * self.config.block_size * SYNTHETIC_INST_LENGTH as u64
+    }
+
+    /// Address of the given outlined block of the current pattern
+    fn outlined_block(&self, index: u64) -> u64 {
+        self.outlined_bases[self.pattern_index]
+            + index * self.config.block_size * SYNTHETIC_INST_LENGTH as u64
+    }
+
+    /// Execute the block and the branch ending it
+    fn emit(&mut self, block_addr: u64, targ_addr: u64, branch_type: BranchType, taken: bool) {
+        let inst_addr = block_addr + (self.config.block_size - 1) * SYNTHETIC_INST_LENGTH as u64;
+        let (br_index, targ_addr) = self.indexer.index(inst_addr, targ_addr, branch_type, taken);
+        self.pending += self.config.block_size;
+        let mut new_instructions = 0;
+        if taken {
+            new_instructions = self.pending;
+            self.instructions += new_instructions;
+            self.pending = 0;
+        }
+        self.events.push_back(BranchEvent {
+            br_index,
+            inst_addr,
+            targ_addr,
+            inst_length: SYNTHETIC_INST_LENGTH,
+            branch_type,
+            taken,
+            new_instructions,
+            instructions: self.instructions,
+            thread_id: 0,
+        });
+    }
+
+    /// Generate the events of the current pattern
+    fn generate(&mut self) {
+        let cond = BranchType::ConditionalDirectJump;
+        // the block after the pattern
+        let next = self.bases[self.pattern_index + 1];
+        match self.config.patterns[self.pattern_index].clone() {
+            SyntheticPattern::Loop { trip_count } => {
+                for i in 0..trip_count {
+                    self.emit(self.block(0), self.block(0), cond, i + 1 < trip_count);
+                }
+            }
+            SyntheticPattern::Correlated { distance } => {
+                let taken = self.rng.below(2) == 1;
+                self.emit(self.block(0), self.block(1), cond, taken);
+                for i in 1..distance {
+                    self.emit(
+                        self.block(i),
+                        self.block(i + 1),
+                        BranchType::DirectJump,
+                        true,
+                    );
+                }
+                self.emit(self.block(distance), next, cond, taken);
+            }
+            SyntheticPattern::Random { bias } => {
+                let taken = self.rng.next_f64() < bias;
+                self.emit(self.block(0), next, cond, taken);
+            }
+            SyntheticPattern::Switch { targets } => {
+                let case = self.rng.below(targets) + 1;
+                self.emit(
+                    self.block(0),
+                    self.block(case),
+                    BranchType::IndirectJump,
+                    true,
+                );
+                self.emit(self.block(case), next, BranchType::DirectJump, true);
+            }
+            SyntheticPattern::Calls { depth } => {
+                // function i < depth calls function i + 1 and returns from the block after the call,
+                // function depth is a single block
+                let function = |i: u64| self.outlined_block(2 * i - 2);
+                let return_addr = |i: u64| {
+                    if i == 1 {
+                        next
+                    } else {
+                        function(i - 1) + self.config.block_size * SYNTHETIC_INST_LENGTH as u64
+                    }
+                };
+                let mut calls = vec![(self.block(0), function(1))];
+                let mut returns = vec![];
+                for i in 1..depth {
+                    calls.push((function(i), function(i + 1)));
+                }
+                for i in (1..=depth).rev() {
+                    let block_addr = if i == depth {
+                        function(depth)
+                    } else {
+                        return_addr(i + 1)
+                    };
+                    returns.push((block_addr, return_addr(i)));
+                }
+                for (block_addr, targ_addr) in calls {
+                    self.emit(block_addr, targ_addr, BranchType::DirectCall, true);
+                }
+                for (block_addr, targ_addr) in returns {
+                    self.emit(block_addr, targ_addr, BranchType::Return, true);
+                }
+            }
+        }
+    }
+}
+
+impl TraceSource for SyntheticSource {
+    fn next_event(&mut self) -> anyhow::Result<Option<BranchEvent>> {
+        while self.events.is_empty() {
+            if self.iteration == self.config.iterations {
+                return Ok(None);
+            }
+            if self.pattern_index == self.config.patterns.len() {
+                // jump back to the first pattern
+                let block_addr = self.bases[self.pattern_index];
+                self.emit(
+                    block_addr,
+                    SYNTHETIC_BASE_ADDR,
+                    BranchType::DirectJump,
+                    true,
+                );
+                self.pattern_index = 0;
+                self.iteration += 1;
+            } else {
+                self.generate();
+                self.pattern_index += 1;
+            }
+        }
+        Ok(self.events.pop_front())
+    }
+
+    fn has_inst_counts(&self) -> bool {
+        true
+    }
+
+    fn metadata(&self) -> BTreeMap<String, String> {
+        let mut metadata = BTreeMap::new();
+        metadata.insert(METADATA_TRACER.to_string(), "synthetic".to_string());
+        if let Ok(config) = serde_json::to_string(&self.config) {
+            metadata.insert("synthetic.config".to_string(), config);
+        }
+        metadata
+    }
+}
+
+/// Generate a trace with embedded instruction counts, see SyntheticSource
+pub fn generate_synthetic(
+    config: SyntheticConfig,
+    encoder: &mut TraceFileEncoder,
+) -> anyhow::Result<()> {
+    import_source(&mut SyntheticSource::new(config)?, encoder)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        BranchType, SyntheticConfig, SyntheticPattern, SyntheticSource, TraceFileDecoder,
+        TraceFileEncoder, TraceFileSource, TraceSource, generate_synthetic,
+    };
+    use std::collections::HashMap;
+
+    fn config(patterns: Vec<SyntheticPattern>, iterations: u64) -> SyntheticConfig {
+        SyntheticConfig {
+            seed: 1,
+            iterations,
+            block_size: 4,
+            patterns,
+        }
+    }
+
+    #[test]
+    fn test_patterns() {
+        let mut source = SyntheticSource::new(config(
+            vec![
+                SyntheticPattern::Loop { trip_count: 10 },
+                SyntheticPattern::Correlated { distance: 5 },
+                SyntheticPattern::Random { bias: 0.9 },
+                SyntheticPattern::Switch { targets: 4 },
+                SyntheticPattern::Calls { depth: 3 },
+            ],
+            1000,
+        ))
+        .unwrap();
+
+        let mut counts: HashMap<BranchType, u64> = HashMap::new();
+        let mut conditional_taken = vec![];
+        let mut call_stack = vec![];
+        let mut switch_targets = HashMap::new();
+        let mut num_events = 0;
+        let mut instructions = 0;
+        while let Some(event) = source.next_event().unwrap() {
+            num_events += 1;
+            *counts.entry(event.branch_type).or_default() += 1;
+            instructions += event.new_instructions;
+            assert_eq!(event.instructions, instructions);
+            match event.branch_type {
+                BranchType::ConditionalDirectJump => conditional_taken.push(event.taken),
+                BranchType::DirectCall => call_stack.push(event.inst_addr + 4),
+                BranchType::Return => assert_eq!(Some(event.targ_addr), call_stack.pop()),
+                BranchType::IndirectJump => {
+                    *switch_targets.entry(event.targ_addr).or_insert(0) += 1
+                }
+                _ => {}
+            }
+        }
+        assert!(call_stack.is_empty());
+        assert_eq!(switch_targets.len(), 4);
+
+        // per iteration: 10 loop, 2 + 4 correlated, 1 random, 2 switch, 6 calls and 1 jump back
+        assert_eq!(num_events, 1000 * 26);
+        assert_eq!(counts[&BranchType::ConditionalDirectJump], 1000 * 13);
+        assert_eq!(counts[&BranchType::DirectJump], 1000 * 6);
+        assert_eq!(counts[&BranchType::DirectCall], 1000 * 3);
+        assert_eq!(counts[&BranchType::Return], 1000 * 3);
+        // each event ends a block of 4 instructions
+        assert_eq!(instructions, 1000 * 26 * 4);
+
+        for iteration in conditional_taken.chunks(13) {
+            // loop
+            assert!(iteration[..9].iter().all(|taken| *taken));
+            assert!(!iteration[9]);
+            // correlated
+            assert_eq!(iteration[10], iteration[11]);
+        }
+        let random_taken = conditional_taken
+            .chunks(13)
+            .filter(|iteration| iteration[12])
+            .count();
+        assert!((850..950).contains(&random_taken), "{}", random_taken);
+    }
+
+    #[test]
+    fn test_generate() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let mut encoder = TraceFileEncoder::open(file.as_file()).unwrap();
+        generate_synthetic(
+            config(
+                vec![
+                    SyntheticPattern::Loop { trip_count: 3 },
+                    SyntheticPattern::Random { bias: 0.5 },
+                ],
+                100,
+            ),
+            &mut encoder,
+        )
+        .unwrap();
+        encoder.finish().unwrap();
+
+        let content = std::fs::read(file.path()).unwrap();
+        let decoder = TraceFileDecoder::open(&content).unwrap();
+        decoder.verify().unwrap();
+        assert_eq!(decoder.metadata["tracer"], "synthetic");
+        assert_eq!(decoder.num_branches, 3);
+        assert_eq!(decoder.num_entries, 500);
+
+        // no images are required to count the instructions
+        let mut source = TraceFileSource::new(&decoder, 0, 0).unwrap();
+        assert!(source.has_inst_counts());
+        while source.next_event().unwrap().is_some() {}
+        assert_eq!(source.instructions(), 500 * 4);
+    }
+}
@@ -532,8 +532,9 @@ impl ConditionalBranchPredictor for Tage {
 #[cfg(test)]
 mod tests {
     use crate::{
-        BranchType, ConditionalBranchPredictor, Tage, TageBaseTableConfig, TageConfig,
-        TageHistoryRegisterConfig, TagePHRConfig, TagePHRXorConfig, TageTableConfig, TageXorConfig,
+        BranchType, ConditionalBranchPredictor, SyntheticConfig, SyntheticPattern, SyntheticSource,
+        Tage, TageBaseTableConfig, TageConfig, TageHistoryRegisterConfig, TagePHRConfig,
+        TagePHRXorConfig, TageTableConfig, TageXorConfig, TraceSource,
     };
 
     #[test]
@@ -565,10 +566,11 @@ mod tests {
                 let mut max: Option<usize> = None;
                 for bit in config.index_bits.iter().chain(config.tag_bits.iter()) {
                     for element in bit {
-                        if let TageXorConfig::HR(index, bit) = element {
-                            if i == *index && (max.is_none() || max < Some(*bit + 1)) {
-                                max = Some(*bit + 1);
-                            }
+                        if let TageXorConfig::HR(index, bit) = element
+                            && i == *index
+                            && (max.is_none() || max < Some(*bit + 1))
+                        {
+                            max = Some(*bit + 1);
                         }
                     }
                 }
@@ -674,7 +676,7 @@ mod tests {
         // branch 2: branch from 0x8 to 0x0
         for i in 0..count {
             // branch 1
-            let resolve_direction = if i % 3 == 0 { true } else { false };
+            let resolve_direction = i % 3 == 0;
             let predict_direction = tage.predict(0x4, resolve_direction);
             if resolve_direction == predict_direction {
                 correct += 1;
@@ -694,4 +696,61 @@ mod tests {
         }
         assert!(correct >= 990, "{}/{}", correct, count);
     }
+
+    /// Accuracy of the branch correlated with a random branch at the given distance,
+    /// measured after warmup
+    fn correlated_accuracy(distance: u64) -> f64 {
+        let mut tage = Tage::new("configs/firestorm.toml").unwrap();
+        let iterations = 2000;
+        let mut source = SyntheticSource::new(SyntheticConfig {
+            seed: 0,
+            iterations,
+            block_size: 4,
+            patterns: vec![SyntheticPattern::Correlated { distance }],
+        })
+        .unwrap();
+
+        // the first conditional branch is the random one
+        let mut random_pc = None;
+        let mut correct = 0;
+        let mut count = 0;
+        let mut iteration = 0;
+        while let Some(event) = source.next_event().unwrap() {
+            if event.branch_type != BranchType::ConditionalDirectJump {
+                tage.update_others(
+                    event.inst_addr,
+                    event.branch_type,
+                    event.taken,
+                    event.targ_addr,
+                );
+                continue;
+            }
+            let predict = tage.predict(event.inst_addr, event.taken);
+            tage.update(
+                event.inst_addr,
+                event.branch_type,
+                event.taken,
+                predict,
+                event.targ_addr,
+            );
+            if *random_pc.get_or_insert(event.inst_addr) != event.inst_addr {
+                iteration += 1;
+                if iteration > iterations / 2 {
+                    correct += (predict == event.taken) as u64;
+                    count += 1;
+                }
+            }
+        }
+        correct as f64 / count as f64
+    }
+
+    #[test]
+    fn test_correlated() {
+        // correlation at distance 40 is learned by the 100-bit PHR
+        let accuracy = correlated_accuracy(40);
+        assert!(accuracy > 0.95, "{}", accuracy);
+        // but not at distance 120
+        let accuracy = correlated_accuracy(120);
+        assert!(accuracy < 0.6, "{}", accuracy);
+    }
 }
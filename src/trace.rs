@@ -1,14 +1,79 @@
-use crate::BranchType;
+use crate::{
+    BranchType, ImageInstCounter, ImageRef, InstCountFallbacks, InstIndexMapping,
+    create_inst_index_mapping_from_images, default_inst_index_cache, image_store_from_env,
+    load_image, store_image,
+};
+use anyhow::{Context, bail};
+use memmap::{Mmap, MmapOptions};
 use serde::{Deserialize, Serialize};
 use std::{
-    collections::HashMap,
+    borrow::Cow,
+    collections::{BTreeMap, HashMap},
     fs::File,
     io::{BufReader, BufWriter, Cursor, Read, Seek, Write},
+    ops::Deref,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, mpsc},
+    thread::JoinHandle,
 };
 use zstd::{Encoder, stream::read::Decoder};
 
 // follow definitions in common.h
 
+pub const TRACE_MAGIC: u64 = 0x2121505845504243;
+/// single zstd stream of entries, written by the tracers
+pub const TRACE_VERSION_STREAM: u64 = 0;
+/// entries are split into independent zstd frames with a chunk index
+pub const TRACE_VERSION_CHUNKED: u64 = 1;
+/// chunked, with a table of optional sections
+pub const TRACE_VERSION_SECTIONS: u64 = 2;
+/// same as TRACE_VERSION_SECTIONS, but written append-only to a stream:
+/// the header at the beginning only contains magic and version,
+/// the complete header is written as a footer at the end of file
+pub const TRACE_VERSION_FOOTER: u64 = 3;
+/// same as TRACE_VERSION_SECTIONS, but with variable-width entries, see EntryEncoding::Varint
+pub const TRACE_VERSION_VARINT_SECTIONS: u64 = 4;
+/// same as TRACE_VERSION_FOOTER, but with variable-width entries, see EntryEncoding::Varint
+pub const TRACE_VERSION_VARINT_FOOTER: u64 = 5;
+
+/// optional section: key/value metadata stored as a json object
+pub const OPTIONAL_SECTION_METADATA: u64 = 0;
+/// optional section: instructions executed at each taken branch as u32,
+/// in one zstd frame per chunk
+pub const OPTIONAL_SECTION_INST_COUNTS: u64 = 1;
+/// optional section: array of InstCountChunk, locating the zstd frames of instruction counts
+pub const OPTIONAL_SECTION_INST_COUNT_CHUNKS: u64 = 2;
+/// optional section: array of ImageRef, one per image, for images kept in an image store
+pub const OPTIONAL_SECTION_IMAGE_REFS: u64 = 3;
+/// optional section: array of ThreadSegment, the thread of each run of entries
+pub const OPTIONAL_SECTION_THREAD_SEGMENTS: u64 = 4;
+
+// well-known metadata keys
+/// tracer that captured the trace, e.g. pin, dynamorio or intel-pt
+pub const METADATA_TRACER: &str = "tracer";
+/// command line of the traced program
+pub const METADATA_COMMAND: &str = "command";
+/// working directory of the traced program
+pub const METADATA_CWD: &str = "cwd";
+/// when the capture started, in RFC 3339
+pub const METADATA_TIMESTAMP: &str = "timestamp";
+pub const METADATA_HOSTNAME: &str = "hostname";
+pub const METADATA_HOST_CPU: &str = "host_cpu";
+pub const METADATA_KERNEL: &str = "kernel";
+/// instruction range [start, end) of the original trace, for slices
+pub const METADATA_SLICE_INSTRUCTIONS: &str = "slice_instructions";
+/// instructions at the beginning of the slice for warmup, preceding the slice range
+pub const METADATA_WARMUP_INSTRUCTIONS: &str = "warmup_instructions";
+/// InstCountFallbacks in JSON, taken branches whose instruction counts embedded
+/// from the images are not exact or need disassembly outside the text sections
+pub const METADATA_INST_COUNT_FALLBACKS: &str = "inst_count_fallbacks";
+/// directory of the image store holding the images by content hash,
+/// relative to the directory of the trace file unless absolute, e.g. ../../images
+pub const METADATA_IMAGE_STORE: &str = "image_store";
+
+/// number of entries in each independent zstd frame of chunked traces
+pub const CHUNK_NUM_ENTRIES: usize = 1024 * 1024;
+
 #[repr(C)]
 #[derive(Debug, Clone, Copy, Serialize, Deserialize, Hash, PartialEq, Eq)]
 pub struct Branch {
@@ -39,417 +104,2220 @@ impl RawImage {
     }
 }
 
+/// Index of an independent zstd frame in chunked traces
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq)]
+pub struct Chunk {
+    /// index of the first entry in the chunk
+    pub entry_offset: u64,
+    pub num_entries: u64,
+    /// instructions executed before the first entry in the chunk, u64::MAX if unknown
+    pub instruction_offset: u64,
+    /// the last taken branch before the chunk, u64::MAX if none
+    /// it is required to resume instruction counting from the chunk
+    pub last_taken_br_index: u64,
+    /// offset of the zstd frame from the beginning of file
+    pub data_offset: u64,
+    pub data_size: u64,
+}
+
+impl Chunk {
+    pub fn get_instruction_offset(&self) -> Option<u64> {
+        if self.instruction_offset == u64::MAX {
+            None
+        } else {
+            Some(self.instruction_offset)
+        }
+    }
+
+    pub fn get_last_taken_br_index(&self) -> Option<usize> {
+        if self.last_taken_br_index == u64::MAX {
+            None
+        } else {
+            Some(self.last_taken_br_index as usize)
+        }
+    }
+}
+
+/// Location of an optional section, unknown kinds are ignored by the decoder
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq)]
+pub struct OptionalSection {
+    pub kind: u64,
+    /// offset of the section from the beginning of file
+    pub offset: u64,
+    pub size: u64,
+}
+
+/// Location of the instruction counts of a chunk
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq)]
+pub struct InstCountChunk {
+    /// offset of the zstd frame from the beginning of file
+    pub data_offset: u64,
+    pub data_size: u64,
+}
+
+/// Entries from entry_offset until the next segment belong to the thread
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq)]
+pub struct ThreadSegment {
+    pub thread_id: u64,
+    /// index of the first entry of the segment
+    pub entry_offset: u64,
+}
+
+/// Collect metadata of the current host, for recording the provenance of traces
+pub fn get_host_metadata() -> BTreeMap<String, String> {
+    let mut metadata = BTreeMap::new();
+    metadata.insert(
+        METADATA_TIMESTAMP.to_string(),
+        chrono::Local::now().to_rfc3339(),
+    );
+    if let Ok(hostname) = std::fs::read_to_string("/proc/sys/kernel/hostname") {
+        metadata.insert(METADATA_HOSTNAME.to_string(), hostname.trim().to_string());
+    }
+    if let Ok(kernel) = std::fs::read_to_string("/proc/sys/kernel/osrelease") {
+        metadata.insert(METADATA_KERNEL.to_string(), kernel.trim().to_string());
+    }
+    if let Ok(cpuinfo) = std::fs::read_to_string("/proc/cpuinfo")
+        && let Some(model_name) = cpuinfo
+            .lines()
+            .find_map(|line| line.strip_prefix("model name"))
+            .and_then(|line| line.split_once(':'))
+    {
+        metadata.insert(
+            METADATA_HOST_CPU.to_string(),
+            model_name.1.trim().to_string(),
+        );
+    }
+    metadata
+}
+
+/// Decoded entry, the branch index in the lower 63 bits and taken in the MSB.
+/// The encoding in trace files is decided by EntryEncoding.
+/// Decoded batches take 8 bytes per entry even for fixed-width traces, i.e. 8 MiB per chunk,
+/// which is bounded by the chunks decoded ahead since no reader keeps a whole trace decoded.
 #[repr(C)]
 #[derive(Default, Clone, Copy)]
-pub struct Entry(pub u32);
+pub struct Entry(pub u64);
 
 impl Entry {
     pub fn get_br_index(&self) -> usize {
-        (self.0 & 0x7FFFFFFF) as usize
+        (self.0 & 0x7FFFFFFFFFFFFFFF) as usize
     }
 
     pub fn get_taken(&self) -> bool {
-        (self.0 & 0x80000000) != 0
+        (self.0 & 0x8000000000000000) != 0
     }
 
     pub fn from(br_index: usize, taken: bool) -> Self {
         // must not overflow to taken bit
-        assert!(br_index < 0x80000000);
-        Self(br_index as u32 | ((taken as u32) << 31))
+        assert!((br_index as u64) < 0x8000000000000000);
+        Self(br_index as u64 | ((taken as u64) << 63))
+    }
+}
+
+/// Encoding of entries in the zstd frames, decided by the trace version
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EntryEncoding {
+    /// u32 per entry, the branch index in the lower 31 bits and taken in the MSB,
+    /// i.e. struct entry in common.h
+    Fixed,
+    /// LEB128 of (branch index << 1 | taken), without limit on the branch index
+    Varint,
+}
+
+impl EntryEncoding {
+    pub fn from_version(version: u64) -> Self {
+        match version {
+            TRACE_VERSION_VARINT_SECTIONS | TRACE_VERSION_VARINT_FOOTER => EntryEncoding::Varint,
+            _ => EntryEncoding::Fixed,
+        }
+    }
+
+    /// Append the encoded entry, fails if the branch index does not fit
+    pub fn encode(self, entry: Entry, buf: &mut Vec<u8>) -> anyhow::Result<()> {
+        let br_index = entry.get_br_index() as u64;
+        match self {
+            EntryEncoding::Fixed => {
+                if br_index >= 0x80000000 {
+                    bail!(
+                        "Branch index {} does not fit in fixed-width entries, use variable-width entries instead",
+                        br_index
+                    );
+                }
+                let value = br_index as u32 | ((entry.get_taken() as u32) << 31);
+                buf.extend_from_slice(&value.to_le_bytes());
+            }
+            EntryEncoding::Varint => {
+                let mut value = (br_index << 1) | entry.get_taken() as u64;
+                while value >= 0x80 {
+                    buf.push((value as u8) | 0x80);
+                    value >>= 7;
+                }
+                buf.push(value as u8);
+            }
+        }
+        Ok(())
+    }
+
+    /// Decode the complete entries at the beginning of bytes and append them,
+    /// returns the number of bytes consumed, the remaining bytes begin an incomplete entry
+    pub fn decode(self, bytes: &[u8], entries: &mut Vec<Entry>) -> anyhow::Result<usize> {
+        match self {
+            EntryEncoding::Fixed => {
+                let complete_size = bytes.len() - bytes.len() % 4;
+                entries.extend(bytes[..complete_size].chunks_exact(4).map(|bytes| {
+                    let value = u32::from_le_bytes(bytes.try_into().unwrap());
+                    Entry::from((value & 0x7FFFFFFF) as usize, (value & 0x80000000) != 0)
+                }));
+                Ok(complete_size)
+            }
+            EntryEncoding::Varint => {
+                let mut consumed = 0;
+                let mut value = 0u64;
+                let mut shift = 0;
+                for (i, byte) in bytes.iter().enumerate() {
+                    if shift > 63 || (shift == 63 && (*byte & 0x7F) > 1) {
+                        bail!("Variable-width entry at byte {} overflows", consumed);
+                    }
+                    value |= ((*byte & 0x7F) as u64) << shift;
+                    shift += 7;
+                    if *byte & 0x80 == 0 {
+                        entries.push(Entry::from((value >> 1) as usize, value & 1 != 0));
+                        consumed = i + 1;
+                        value = 0;
+                        shift = 0;
+                    }
+                }
+                Ok(consumed)
+            }
+        }
+    }
+}
+
+/// Compressed size of entries in an encoding, in chunks like TraceFileEncoder,
+/// for comparing the encodings without writing the trace
+pub struct EntrySizeEstimator {
+    encoding: EntryEncoding,
+    // encoded entries of the current chunk
+    buf: Vec<u8>,
+    num_entries: usize,
+    size: u64,
+}
+
+impl EntrySizeEstimator {
+    pub fn new(encoding: EntryEncoding) -> Self {
+        Self {
+            encoding,
+            buf: vec![],
+            num_entries: 0,
+            size: 0,
+        }
+    }
+
+    pub fn push(&mut self, entry: Entry) -> anyhow::Result<()> {
+        self.encoding.encode(entry, &mut self.buf)?;
+        self.num_entries += 1;
+        if self.num_entries.is_multiple_of(CHUNK_NUM_ENTRIES) {
+            self.end_chunk()?;
+        }
+        Ok(())
+    }
+
+    fn end_chunk(&mut self) -> anyhow::Result<()> {
+        self.size += zstd::bulk::compress(&self.buf, 0)?.len() as u64;
+        self.buf.clear();
+        Ok(())
+    }
+
+    /// Returns the compressed size in bytes
+    pub fn finish(mut self) -> anyhow::Result<u64> {
+        if !self.buf.is_empty() {
+            self.end_chunk()?;
+        }
+        Ok(self.size)
     }
 }
 
 pub struct TraceEntryIterator<'a> {
     pub compressed_entries: &'a [u8],
     pub num_entries: usize,
-    pub buf: [u8; 1024 * 256],
+    pub encoding: EntryEncoding,
+    pub buf: Box<[u8]>,
     pub decoder: Decoder<'a, BufReader<Cursor<&'a [u8]>>>,
+    // bytes of incomplete entry at the beginning of buf
+    remaining: usize,
 }
 
-impl<'a> Iterator for TraceEntryIterator<'a> {
-    type Item = &'a [Entry];
+impl Iterator for TraceEntryIterator<'_> {
+    type Item = Vec<Entry>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        // ask for more data from decoder
-        match self.decoder.read(&mut self.buf) {
-            Ok(size) => {
-                assert!(size % std::mem::size_of::<Entry>() == 0);
-                if size == 0 {
-                    None
-                } else {
-                    let entries: &[Entry] = unsafe {
-                        std::slice::from_raw_parts(
-                            &self.buf[0] as *const u8 as *const Entry,
-                            size / std::mem::size_of::<Entry>(),
-                        )
-                    };
-                    Some(entries)
+        let mut entries = vec![];
+        while entries.is_empty() {
+            // ask for more data from decoder
+            let size = match self.decoder.read(&mut self.buf[self.remaining..]) {
+                Ok(size) => size,
+                Err(err) => {
+                    panic!(
+                        "Unexpected error to read data from zstd compressed stream: {:?}",
+                        err
+                    );
                 }
+            };
+            if size == 0 {
+                assert!(self.remaining == 0);
+                return None;
             }
-            Err(err) => {
-                panic!(
-                    "Unexpected error to read data from zstd compressed stream: {:?}",
-                    err
-                );
-            }
+            let size = self.remaining + size;
+            let consumed = match self.encoding.decode(&self.buf[..size], &mut entries) {
+                Ok(consumed) => consumed,
+                Err(err) => panic!("Unexpected error to decode entries: {:?}", err),
+            };
+            self.buf.copy_within(consumed..size, 0);
+            self.remaining = size - consumed;
         }
+        Some(entries)
     }
 }
 
 impl<'a> TraceEntryIterator<'a> {
     pub fn from(file: &TraceFileDecoder<'a>) -> anyhow::Result<TraceEntryIterator<'a>> {
-        let compressed_entries = file.compressed_entries;
+        Self::new(
+            file.compressed_entries,
+            file.num_entries,
+            file.entry_encoding,
+        )
+    }
+
+    /// Decode entries from one or more concatenated zstd frames
+    pub fn new(
+        compressed_entries: &'a [u8],
+        num_entries: usize,
+        encoding: EntryEncoding,
+    ) -> anyhow::Result<TraceEntryIterator<'a>> {
         let cursor = Cursor::new(compressed_entries);
         let decoder = zstd::stream::read::Decoder::new(cursor)?;
         Ok(TraceEntryIterator {
-            compressed_entries: file.compressed_entries,
-            num_entries: file.num_entries,
-            buf: [0u8; 1024 * 256],
+            compressed_entries,
+            num_entries,
+            encoding,
+            buf: vec![0u8; 1024 * 256].into_boxed_slice(),
             decoder,
+            remaining: 0,
         })
     }
 }
 
-pub struct TraceFileDecoder<'a> {
-    // raw trace file content
-    pub content: &'a [u8],
-
-    // parse content
-    pub num_entries: usize,
-    pub num_branches: usize,
-    pub num_images: usize,
-    pub compressed_entries: &'a [u8],
-    pub branches: &'a [Branch],
-    pub images: &'a [RawImage],
+/// Decode the entries of a chunk from its zstd frame
+fn decode_chunk(
+    compressed_entries: &[u8],
+    encoding: EntryEncoding,
+    num_entries: u64,
+) -> anyhow::Result<Vec<Entry>> {
+    let bytes = zstd::decode_all(compressed_entries)?;
+    let mut entries = Vec::with_capacity(num_entries as usize);
+    let consumed = encoding.decode(&bytes, &mut entries)?;
+    if consumed != bytes.len() || entries.len() as u64 != num_entries {
+        bail!(
+            "Decoded {} entries in {} bytes, expected {} entries in {} bytes",
+            entries.len(),
+            consumed,
+            num_entries,
+            bytes.len()
+        );
+    }
+    Ok(entries)
 }
 
-impl<'a> TraceFileDecoder<'a> {
-    pub fn open(content: &'a [u8]) -> TraceFileDecoder<'a> {
-        let mut tmp_u64 = [0u8; 8];
-
-        // read header
-        tmp_u64.copy_from_slice(&content[0..8]);
-        let magic = u64::from_le_bytes(tmp_u64) as usize;
-        assert_eq!(magic, 0x2121505845504243);
-
-        tmp_u64.copy_from_slice(&content[8..16]);
-        let version = u64::from_le_bytes(tmp_u64) as usize;
-        assert_eq!(version, 0);
-
-        tmp_u64.copy_from_slice(&content[16..24]);
-        let num_entries = u64::from_le_bytes(tmp_u64) as usize;
-
-        tmp_u64.copy_from_slice(&content[24..32]);
-        let entries_offset = u64::from_le_bytes(tmp_u64) as usize;
-
-        tmp_u64.copy_from_slice(&content[32..40]);
-        let entries_size = u64::from_le_bytes(tmp_u64) as usize;
-
-        tmp_u64.copy_from_slice(&content[40..48]);
-        let num_branches = u64::from_le_bytes(tmp_u64) as usize;
-
-        tmp_u64.copy_from_slice(&content[48..56]);
-        let branches_offset = u64::from_le_bytes(tmp_u64) as usize;
-
-        tmp_u64.copy_from_slice(&content[56..64]);
-        let num_images = u64::from_le_bytes(tmp_u64) as usize;
-
-        tmp_u64.copy_from_slice(&content[64..72]);
-        let images_offset = u64::from_le_bytes(tmp_u64) as usize;
+/// A chunk to decode: its index, compressed entries and number of entries
+type ChunkJob = (usize, Vec<u8>, u64);
 
-        let images: &[RawImage] = unsafe {
-            std::slice::from_raw_parts(
-                &content[images_offset] as *const u8 as *const RawImage,
-                num_images,
-            )
-        };
+type DecodedChunk = (usize, anyhow::Result<Vec<Entry>>);
 
-        let branches: &[Branch] = unsafe {
-            std::slice::from_raw_parts(
-                &content[branches_offset] as *const u8 as *const Branch,
-                num_branches,
-            )
-        };
+/// Iterate entries like TraceEntryIterator, but decode the upcoming chunks on background threads.
+/// Each chunk is yielded as one batch. Traces without chunks are decoded on the current thread.
+pub struct ParallelEntryIterator<'a> {
+    content: &'a [u8],
+    chunks: Cow<'a, [Chunk]>,
+    /// next chunk to submit for decoding
+    next_chunk: usize,
+    /// next chunk to yield
+    next_result: usize,
+    num_threads: usize,
+    /// chunks for the workers, shared through one bounded channel
+    jobs: Option<mpsc::SyncSender<ChunkJob>>,
+    results: mpsc::Receiver<DecodedChunk>,
+    workers: Vec<JoinHandle<()>>,
+    /// chunks decoded ahead of the next one to yield
+    ready: HashMap<usize, anyhow::Result<Vec<Entry>>>,
+    sequential: Option<TraceEntryIterator<'a>>,
+}
 
-        let entries: &[u8] = unsafe {
-            std::slice::from_raw_parts(&content[entries_offset] as *const u8, entries_size)
+impl<'a> ParallelEntryIterator<'a> {
+    fn new(
+        content: &'a [u8],
+        encoding: EntryEncoding,
+        chunks: Cow<'a, [Chunk]>,
+        next_chunk: usize,
+        num_threads: usize,
+        sequential: Option<TraceEntryIterator<'a>>,
+    ) -> Self {
+        let (job_sender, job_receiver) = mpsc::sync_channel::<ChunkJob>(num_threads);
+        let (result_sender, results) = mpsc::channel();
+        let job_receiver = Arc::new(Mutex::new(job_receiver));
+        let num_workers = match sequential {
+            Some(_) => 0,
+            None => num_threads.min(chunks.len() - next_chunk),
         };
-
+        // the workers own the compressed bytes of their jobs, so they never outlive the content
+        let workers = (0..num_workers)
+            .map(|_| {
+                let job_receiver = job_receiver.clone();
+                let result_sender = result_sender.clone();
+                std::thread::spawn(move || {
+                    loop {
+                        let job = job_receiver.lock().unwrap().recv();
+                        let Ok((index, compressed_entries, num_entries)) = job else {
+                            break;
+                        };
+                        let result = decode_chunk(&compressed_entries, encoding, num_entries);
+                        if result_sender.send((index, result)).is_err() {
+                            break;
+                        }
+                    }
+                })
+            })
+            .collect();
         Self {
             content,
-            num_entries,
-            num_branches,
-            num_images,
-            branches,
-            images,
-            compressed_entries: entries,
+            chunks,
+            next_chunk,
+            next_result: next_chunk,
+            num_threads,
+            jobs: Some(job_sender),
+            results,
+            workers,
+            ready: HashMap::new(),
+            sequential,
         }
     }
 
-    pub fn entries(&self) -> anyhow::Result<TraceEntryIterator<'a>> {
-        TraceEntryIterator::from(self)
+    fn submit(&mut self) {
+        let Some(jobs) = &self.jobs else {
+            return;
+        };
+        // up to num_threads chunks are decoded ahead, so the bounded channel never blocks
+        while self.next_chunk - self.next_result < self.num_threads
+            && self.next_chunk < self.chunks.len()
+        {
+            let chunk = &self.chunks[self.next_chunk];
+            let compressed_entries = self.content
+                [chunk.data_offset as usize..(chunk.data_offset + chunk.data_size) as usize]
+                .to_vec();
+            jobs.send((self.next_chunk, compressed_entries, chunk.num_entries))
+                .expect("Decoder threads exit unexpectedly");
+            self.next_chunk += 1;
+        }
     }
+}
 
-    pub fn get_image_data(&self, image: &RawImage) -> &[u8] {
-        &self.content[image.data_offset as usize..(image.data_offset + image.data_size) as usize]
+impl Iterator for ParallelEntryIterator<'_> {
+    type Item = Vec<Entry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(sequential) = &mut self.sequential {
+            return sequential.next();
+        }
+        if self.next_result >= self.chunks.len() {
+            return None;
+        }
+        self.submit();
+        let result = loop {
+            if let Some(result) = self.ready.remove(&self.next_result) {
+                break result;
+            }
+            match self.results.recv() {
+                Ok((index, result)) => {
+                    self.ready.insert(index, result);
+                }
+                Err(err) => panic!("Decoder thread exits unexpectedly: {:?}", err),
+            }
+        };
+        let entries = match result {
+            Ok(entries) => entries,
+            Err(err) => panic!(
+                "Unexpected error to read data from zstd compressed stream: {:?}",
+                err
+            ),
+        };
+        self.next_result += 1;
+        // keep the decoder threads busy while the caller consumes the chunk
+        self.submit();
+        Some(entries)
     }
+}
 
-    pub fn get_images(&self) -> anyhow::Result<Vec<Image>> {
-        let mut res = vec![];
-        for image in self.images {
-            res.push(Image::from(image, self)?);
+impl Drop for ParallelEntryIterator<'_> {
+    fn drop(&mut self) {
+        // the workers exit once the job channel is closed
+        self.jobs = None;
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
         }
-        Ok(res)
     }
+}
 
-    // find corresponding image & offset
-    // NOTE: this reports the file offset, instead of virtual address
-    // for statically linked executables, it differs by 0x400000
-    pub fn get_addr_location(&self, addr: u64) -> anyhow::Result<String> {
-        for image in self.images {
-            if addr >= image.start && addr < image.start + image.len {
-                return Ok(format!(
-                    "{}:0x{:x}",
-                    image.get_filename()?,
-                    addr - image.start
-                ));
+/// Iterate the embedded instruction counts, one for each taken branch
+pub struct InstCountIterator<'a> {
+    buf: Box<[u8]>,
+    // valid bytes in buf[pos..len]
+    pos: usize,
+    len: usize,
+    decoder: Decoder<'a, BufReader<Cursor<&'a [u8]>>>,
+}
+
+impl<'a> InstCountIterator<'a> {
+    /// Decode instruction counts from one or more concatenated zstd frames
+    pub fn new(compressed_inst_counts: &'a [u8]) -> anyhow::Result<InstCountIterator<'a>> {
+        Ok(InstCountIterator {
+            buf: vec![0u8; 1024 * 64].into_boxed_slice(),
+            pos: 0,
+            len: 0,
+            decoder: Decoder::new(Cursor::new(compressed_inst_counts))?,
+        })
+    }
+}
+
+impl Iterator for InstCountIterator<'_> {
+    type Item = u64;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.len - self.pos < 4 {
+            // ask for more data from decoder
+            self.buf.copy_within(self.pos..self.len, 0);
+            self.len -= self.pos;
+            self.pos = 0;
+            while self.len < 4 {
+                match self.decoder.read(&mut self.buf[self.len..]) {
+                    Ok(0) => return None,
+                    Ok(size) => self.len += size,
+                    Err(err) => panic!(
+                        "Unexpected error to read data from zstd compressed stream: {:?}",
+                        err
+                    ),
+                }
             }
         }
-        Ok(format!("unknown:0x{:x}", addr))
+        let count = u32::from_le_bytes(self.buf[self.pos..self.pos + 4].try_into().unwrap());
+        self.pos += 4;
+        Some(count as u64)
     }
 }
 
-const BUFFER_SIZE: usize = 16384;
+/// Count instructions executed at each taken branch,
+/// using the embedded instruction counts if available, otherwise the images
+pub enum TraceInstCounter<'a> {
+    Embedded(InstCountIterator<'a>),
+    Images(ImageInstCounter),
+}
 
-pub struct TraceFileEncoder<'a> {
-    // trace file
-    pub file: &'a File,
-    pub encoder: Encoder<'a, BufWriter<&'a File>>,
+impl<'a> TraceInstCounter<'a> {
+    /// Continue counting from another seek result, reusing the instruction indices
+    pub fn resume(&mut self, seek: &mut TraceSeek<'a>) {
+        match self {
+            TraceInstCounter::Embedded(inst_counts) => {
+                *inst_counts = seek
+                    .inst_counts
+                    .take()
+                    .expect("Instruction counts are embedded in the trace");
+            }
+            TraceInstCounter::Images(counter) => counter.resume(seek.last_taken_br_index),
+        }
+    }
 
-    // content
-    pub num_entries: usize,
-    pub branches: Vec<Branch>,
-    pub images: Vec<Image>,
+    /// Returns instructions executed since the last taken branch, including the branch itself
+    pub fn count_taken(&mut self, br_index: usize) -> u64 {
+        match self {
+            TraceInstCounter::Embedded(inst_counts) => inst_counts
+                .next()
+                .expect("Instruction counts end before the entries"),
+            TraceInstCounter::Images(counter) => counter.count_taken(br_index),
+        }
+    }
 
-    // maintain mapping from (inst_addr, targ_addr) to branch index
-    pub mapping: HashMap<(u64, u64), usize>,
+    /// Taken branches counted so far without exact instruction indices, none if embedded
+    pub fn fallbacks(&self) -> InstCountFallbacks {
+        match self {
+            TraceInstCounter::Embedded(_) => InstCountFallbacks::default(),
+            TraceInstCounter::Images(counter) => counter.fallbacks,
+        }
+    }
+}
 
-    // output buffer
-    pub buffer: [Entry; BUFFER_SIZE],
-    pub buffer_size: usize,
+/// Result of seeking in the trace: entries starting from a chunk boundary,
+/// along with the state to resume instruction counting
+pub struct TraceSeek<'a> {
+    /// index of the first entry returned by the iterator
+    pub entry_offset: usize,
+    /// instructions executed before the first entry
+    pub instruction_offset: u64,
+    /// the last taken branch before the first entry
+    pub last_taken_br_index: Option<usize>,
+    pub entries: TraceEntryIterator<'a>,
+    /// embedded instruction counts from the first entry, if available
+    pub inst_counts: Option<InstCountIterator<'a>>,
 }
 
-impl<'a> TraceFileEncoder<'a> {
-    pub fn open(file: &'a File) -> anyhow::Result<Self> {
-        let mut writer = BufWriter::new(file);
-        // leave space for file_header, which is 72 bytes in size
-        writer.seek(std::io::SeekFrom::Start(72))?;
-        Ok(Self {
-            file,
-            encoder: Encoder::new(writer, 0)?,
-            num_entries: 0,
-            branches: vec![],
-            mapping: HashMap::new(),
-            buffer: [Entry::default(); BUFFER_SIZE],
-            buffer_size: 0,
-            images: vec![],
-        })
-    }
+/// An entry along with its thread
+#[derive(Clone, Copy)]
+pub struct ThreadEntry {
+    pub thread_id: u64,
+    pub entry: Entry,
+    /// instructions executed since the last taken branch of the same thread,
+    /// including the branch itself, zero if not taken
+    pub instructions: u64,
+}
 
-    /// Returns internal branch index for optimization
-    pub fn record_event(
-        &mut self,
-        inst_addr: u64,
-        targ_addr: u64,
-        inst_length: u32,
-        branch_type: BranchType,
-        taken: bool,
-    ) -> anyhow::Result<usize> {
-        let br_index = match self.mapping.get(&(inst_addr, targ_addr)) {
-            Some(index) => *index,
-            None => {
-                let index = self.branches.len();
-                self.branches.push(Branch {
-                    inst_addr,
-                    targ_addr,
-                    inst_length,
-                    branch_type,
-                });
-                self.mapping.insert((inst_addr, targ_addr), index);
-                index
-            }
-        };
+/// Iterate entries in recorded order along with their threads,
+/// skipping the entries of other threads if filtered
+pub struct ThreadEntryIterator<'a> {
+    entries: Box<dyn Iterator<Item = Vec<Entry>> + 'a>,
+    /// None if the instructions cannot be counted
+    inst_counter: Option<TraceInstCounter<'a>>,
+    decode_threads: usize,
+    thread_segments: Cow<'a, [ThreadSegment]>,
+    /// segment of the next entry
+    segment_index: usize,
+    thread_filter: Option<u64>,
+    // decoded entries in buf[pos..]
+    buf: Vec<Entry>,
+    pos: usize,
+    /// index of the next entry in the trace
+    entry_index: usize,
+}
 
-        self.record_event_with_branch_index(br_index, taken)?;
+impl<'a> ThreadEntryIterator<'a> {
+    /// Whether the instructions are counted, otherwise the instruction counts are zero
+    pub fn has_inst_counts(&self) -> bool {
+        self.inst_counter.is_some()
+    }
 
-        Ok(br_index)
+    /// Taken branches counted so far without exact instruction indices
+    pub fn inst_count_fallbacks(&self) -> InstCountFallbacks {
+        self.inst_counter
+            .as_ref()
+            .map_or_else(InstCountFallbacks::default, |counter| counter.fallbacks())
     }
 
-    /// If the caller already knows the branch index, use this
-    pub fn record_event_with_branch_index(
+    /// Continue from another seek result of the same trace, reusing the instruction counter
+    pub fn resume(
         &mut self,
-        br_index: usize,
-        taken: bool,
+        decoder: &TraceFileDecoder<'a>,
+        mut seek: TraceSeek<'a>,
     ) -> anyhow::Result<()> {
-        let entry = Entry::from(br_index, taken);
-        self.buffer[self.buffer_size] = entry;
-        self.buffer_size += 1;
-
-        if self.buffer_size == BUFFER_SIZE {
-            // flush
-            self.encoder.write_all(unsafe {
-                std::slice::from_raw_parts(
-                    self.buffer.as_ptr() as *const u8,
-                    std::mem::size_of::<Entry>() * self.buffer_size,
-                )
-            })?;
-            self.buffer_size = 0;
+        if let Some(inst_counter) = &mut self.inst_counter {
+            inst_counter.resume(&mut seek);
         }
-
-        self.num_entries += 1;
+        self.segment_index = decoder.thread_segment_index(seek.entry_offset);
+        self.entry_index = seek.entry_offset;
+        self.buf.clear();
+        self.pos = 0;
+        self.entries = decoder.entry_batches(seek, self.decode_threads)?;
         Ok(())
     }
+}
 
-    pub fn finish(mut self) -> anyhow::Result<()> {
-        if self.buffer_size > 0 {
-            // flush
-            self.encoder.write_all(unsafe {
-                std::slice::from_raw_parts(
-                    self.buffer.as_ptr() as *const u8,
-                    std::mem::size_of::<Entry>() * self.buffer_size,
-                )
-            })?;
-            self.buffer_size = 0;
-        }
+impl Iterator for ThreadEntryIterator<'_> {
+    type Item = ThreadEntry;
 
-        let mut writer = self.encoder.finish()?;
-        let entries_offset = 72; // sizeof(file_header)
-        let entries_size = writer.stream_position()? - entries_offset;
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if self.pos == self.buf.len() {
+                self.buf = self.entries.next()?;
+                self.pos = 0;
+                continue;
+            }
+            let entry = self.buf[self.pos];
+            self.pos += 1;
 
-        // write branches
-        let branches_offset = writer.stream_position()?;
-        writer.write_all(unsafe {
-            std::slice::from_raw_parts(
-                self.branches.as_ptr() as *const u8,
-                self.branches.len() * std::mem::size_of::<Branch>(),
-            )
-        })?;
+            while self
+                .thread_segments
+                .get(self.segment_index + 1)
+                .is_some_and(|segment| segment.entry_offset <= self.entry_index as u64)
+            {
+                self.segment_index += 1;
+            }
+            self.entry_index += 1;
+            let thread_id = self
+                .thread_segments
+                .get(self.segment_index)
+                .map_or(0, |segment| segment.thread_id);
 
-        // write image content
-        let mut raw_images = vec![];
-        for image in &self.images {
-            let data_offset = writer.stream_position()?;
-            writer.write_all(&image.data)?;
-            let mut filename = [0u8; 256];
-            let filename_bytes = image.filename.as_bytes();
-            filename[0..filename_bytes.len()].copy_from_slice(filename_bytes);
-            raw_images.push(RawImage {
-                start: image.start,
-                len: image.len,
-                data_size: image.data.len() as u64,
-                data_offset,
-                filename,
-            });
+            // instruction counts of all threads are interleaved
+            let instructions = match &mut self.inst_counter {
+                Some(inst_counter) if entry.get_taken() => {
+                    inst_counter.count_taken(entry.get_br_index())
+                }
+                _ => 0,
+            };
+            if self.thread_filter.is_none_or(|filter| filter == thread_id) {
+                return Some(ThreadEntry {
+                    thread_id,
+                    entry,
+                    instructions,
+                });
+            }
+        }
+    }
+}
+
+pub struct TraceFileDecoder<'a> {
+    // raw trace file content
+    pub content: &'a [u8],
+
+    // parse content
+    pub version: u64,
+    pub entry_encoding: EntryEncoding,
+    pub num_entries: usize,
+    pub num_branches: usize,
+    pub num_images: usize,
+    pub num_chunks: usize,
+    pub compressed_entries: &'a [u8],
+    // arrays are borrowed from content, unless they are misaligned
+    pub branches: Cow<'a, [Branch]>,
+    pub images: Cow<'a, [RawImage]>,
+    /// chunk index, empty if the trace is not chunked
+    pub chunks: Cow<'a, [Chunk]>,
+    pub optional_sections: Cow<'a, [OptionalSection]>,
+    /// provenance of the trace, empty if not recorded
+    pub metadata: BTreeMap<String, String>,
+    /// index of embedded instruction counts, one for each chunk, empty if not available
+    pub inst_count_chunks: Cow<'a, [InstCountChunk]>,
+    /// references of images kept in the image store, empty if all images are embedded
+    pub image_refs: Cow<'a, [ImageRef]>,
+    /// where get_images() finds the referenced images,
+    /// from $CBP_IMAGE_STORE or the metadata by default
+    pub image_store: Option<PathBuf>,
+    /// where the disassembly of images is cached for instruction counting,
+    /// default_inst_index_cache() by default
+    pub inst_index_cache: Option<PathBuf>,
+    /// thread of each run of entries, empty if all entries belong to thread 0
+    pub thread_segments: Cow<'a, [ThreadSegment]>,
+}
+
+/// Byte range of a section in trace file
+struct Section {
+    name: &'static str,
+    offset: usize,
+    size: usize,
+}
+
+fn read_u64(content: &[u8], offset: usize) -> u64 {
+    let mut tmp_u64 = [0u8; 8];
+    tmp_u64.copy_from_slice(&content[offset..offset + 8]);
+    u64::from_le_bytes(tmp_u64)
+}
+
+/// Compute the byte range of an array section and check that it is within the file
+fn array_section<T>(
+    name: &'static str,
+    content: &[u8],
+    offset: u64,
+    count: u64,
+) -> anyhow::Result<Section> {
+    let size = count
+        .checked_mul(std::mem::size_of::<T>() as u64)
+        .with_context(|| format!("Size of {} section overflows with {} elements", name, count))?;
+    byte_section(name, content, offset, size)
+}
+
+fn byte_section(
+    name: &'static str,
+    content: &[u8],
+    offset: u64,
+    size: u64,
+) -> anyhow::Result<Section> {
+    match offset.checked_add(size) {
+        Some(end) if end <= content.len() as u64 => Ok(Section {
+            name,
+            offset: offset as usize,
+            size: size as usize,
+        }),
+        _ => bail!(
+            "{} section at 0x{:x} with size {} is out of range of file size {}",
+            name,
+            offset,
+            size,
+            content.len()
+        ),
+    }
+}
+
+/// Reinterpret the section as an array of T,
+/// misaligned array is copied if allowed
+fn section_array<'a, T: Copy>(
+    content: &'a [u8],
+    section: &Section,
+    allow_misaligned: bool,
+) -> anyhow::Result<Cow<'a, [T]>> {
+    let count = section.size / std::mem::size_of::<T>();
+    if count == 0 {
+        return Ok(Cow::Borrowed(&[]));
+    }
+    if !section.offset.is_multiple_of(std::mem::align_of::<T>()) && !allow_misaligned {
+        bail!(
+            "{} section at 0x{:x} is misaligned",
+            section.name,
+            section.offset
+        );
+    }
+
+    let ptr = content[section.offset..].as_ptr();
+    if (ptr as usize).is_multiple_of(std::mem::align_of::<T>()) {
+        Ok(Cow::Borrowed(unsafe {
+            std::slice::from_raw_parts(ptr as *const T, count)
+        }))
+    } else {
+        // copy to aligned memory
+        Ok(Cow::Owned(
+            (0..count)
+                .map(|i| unsafe { std::ptr::read_unaligned((ptr as *const T).add(i)) })
+                .collect(),
+        ))
+    }
+}
+
+impl<'a> TraceFileDecoder<'a> {
+    pub fn open(content: &'a [u8]) -> anyhow::Result<TraceFileDecoder<'a>> {
+        // read header
+        if content.len() < 16 {
+            bail!("Trace file is truncated: got {} bytes", content.len());
+        }
+
+        let magic = read_u64(content, 0);
+        if magic != TRACE_MAGIC {
+            bail!("Bad magic 0x{:x}, expected 0x{:x}", magic, TRACE_MAGIC);
+        }
+
+        let version = read_u64(content, 8);
+        let header_size = match version {
+            TRACE_VERSION_STREAM => 72,
+            TRACE_VERSION_CHUNKED => 88,
+            TRACE_VERSION_SECTIONS
+            | TRACE_VERSION_FOOTER
+            | TRACE_VERSION_VARINT_SECTIONS
+            | TRACE_VERSION_VARINT_FOOTER => HEADER_SIZE as usize,
+            _ => bail!("Unsupported trace version {}", version),
+        };
+        let footer_size =
+            if version == TRACE_VERSION_FOOTER || version == TRACE_VERSION_VARINT_FOOTER {
+                HEADER_SIZE as usize
+            } else {
+                0
+            };
+        if content.len() < header_size + footer_size {
+            bail!(
+                "Trace file is truncated: got {} bytes, expected at least {} bytes of header",
+                content.len(),
+                header_size + footer_size
+            );
+        }
+
+        // the complete header is at the end of file for streamed traces
+        let header_offset = if footer_size > 0 {
+            let footer_offset = content.len() - footer_size;
+            let footer_magic = read_u64(content, footer_offset);
+            let footer_version = read_u64(content, footer_offset + 8);
+            if footer_magic != TRACE_MAGIC || footer_version != version {
+                bail!(
+                    "Bad footer with magic 0x{:x} and version {}, the trace may be incomplete",
+                    footer_magic,
+                    footer_version
+                );
+            }
+            footer_offset
+        } else {
+            0
+        };
+
+        let num_entries = read_u64(content, header_offset + 16);
+        let entries_offset = read_u64(content, header_offset + 24);
+        let entries_size = read_u64(content, header_offset + 32);
+        let num_branches = read_u64(content, header_offset + 40);
+        let branches_offset = read_u64(content, header_offset + 48);
+        let num_images = read_u64(content, header_offset + 56);
+        let images_offset = read_u64(content, header_offset + 64);
+
+        let mut num_chunks = 0;
+        let mut chunks_offset = 0;
+        if version >= TRACE_VERSION_CHUNKED {
+            num_chunks = read_u64(content, header_offset + 72);
+            chunks_offset = read_u64(content, header_offset + 80);
+        }
+
+        let mut num_optional_sections = 0;
+        let mut optional_sections_offset = 0;
+        if version >= TRACE_VERSION_SECTIONS {
+            num_optional_sections = read_u64(content, header_offset + 88);
+            optional_sections_offset = read_u64(content, header_offset + 96);
+        }
+
+        // validate sections
+        let entries_section = byte_section("Entries", content, entries_offset, entries_size)?;
+        let branches_section =
+            array_section::<Branch>("Branches", content, branches_offset, num_branches)?;
+        let images_section =
+            array_section::<RawImage>("Images", content, images_offset, num_images)?;
+        let chunks_section = array_section::<Chunk>("Chunks", content, chunks_offset, num_chunks)?;
+        let optional_sections_section = array_section::<OptionalSection>(
+            "Optional sections",
+            content,
+            optional_sections_offset,
+            num_optional_sections,
+        )?;
+
+        // tracers do not align the arrays
+        let allow_misaligned = version == TRACE_VERSION_STREAM;
+        let optional_sections = section_array::<OptionalSection>(
+            content,
+            &optional_sections_section,
+            allow_misaligned,
+        )?;
+        let mut extra_sections = vec![];
+        for optional_section in optional_sections.iter() {
+            let name = match optional_section.kind {
+                OPTIONAL_SECTION_METADATA => "Metadata",
+                OPTIONAL_SECTION_INST_COUNTS => "Instruction counts",
+                OPTIONAL_SECTION_INST_COUNT_CHUNKS => "Instruction count chunks",
+                OPTIONAL_SECTION_IMAGE_REFS => "Image references",
+                OPTIONAL_SECTION_THREAD_SEGMENTS => "Thread segments",
+                _ => "Unknown optional",
+            };
+            extra_sections.push(byte_section(
+                name,
+                content,
+                optional_section.offset,
+                optional_section.size,
+            )?);
+        }
+
+        let mut sections: Vec<&Section> = [
+            &entries_section,
+            &branches_section,
+            &images_section,
+            &chunks_section,
+            &optional_sections_section,
+        ]
+        .into_iter()
+        .chain(extra_sections.iter())
+        .filter(|section| section.size > 0)
+        .collect();
+        sections.sort_by_key(|section| section.offset);
+        for (i, section) in sections.iter().enumerate() {
+            if section.offset < header_size {
+                bail!(
+                    "{} section at 0x{:x} overlaps with file header",
+                    section.name,
+                    section.offset
+                );
+            }
+            if footer_size > 0 && section.offset + section.size > header_offset {
+                bail!(
+                    "{} section at 0x{:x} overlaps with file footer",
+                    section.name,
+                    section.offset
+                );
+            }
+            if let Some(next) = sections.get(i + 1)
+                && section.offset + section.size > next.offset
+            {
+                bail!(
+                    "{} section at 0x{:x} overlaps with {} section at 0x{:x}",
+                    section.name,
+                    section.offset,
+                    next.name,
+                    next.offset
+                );
+            }
+        }
+
+        let branches = section_array::<Branch>(content, &branches_section, allow_misaligned)?;
+        let images = section_array::<RawImage>(content, &images_section, allow_misaligned)?;
+        let chunks = section_array::<Chunk>(content, &chunks_section, allow_misaligned)?;
+
+        for (i, image) in images.iter().enumerate() {
+            if image
+                .data_offset
+                .checked_add(image.data_size)
+                .is_none_or(|end| end > content.len() as u64)
+            {
+                bail!(
+                    "Image #{} data at 0x{:x} with size {} is past the end of file size {}",
+                    i,
+                    image.data_offset,
+                    image.data_size,
+                    content.len()
+                );
+            }
+        }
+
+        // chunks must be contiguous and cover all entries
+        let mut entry_offset = 0;
+        let mut data_offset = entries_offset;
+        for (i, chunk) in chunks.iter().enumerate() {
+            if chunk.entry_offset != entry_offset {
+                bail!(
+                    "Chunk #{} begins at entry {}, expected {}",
+                    i,
+                    chunk.entry_offset,
+                    entry_offset
+                );
+            }
+            if chunk.data_offset != data_offset {
+                bail!(
+                    "Chunk #{} data begins at 0x{:x}, expected 0x{:x}",
+                    i,
+                    chunk.data_offset,
+                    data_offset
+                );
+            }
+            if chunk.last_taken_br_index != u64::MAX && chunk.last_taken_br_index >= num_branches {
+                bail!(
+                    "Chunk #{} refers to branch {} out of {} branches",
+                    i,
+                    chunk.last_taken_br_index,
+                    num_branches
+                );
+            }
+            entry_offset = entry_offset.saturating_add(chunk.num_entries);
+            data_offset = data_offset.saturating_add(chunk.data_size);
+        }
+        if !chunks.is_empty()
+            && (entry_offset != num_entries || data_offset != entries_offset + entries_size)
+        {
+            bail!(
+                "Chunks cover {} entries in {} bytes, expected {} entries in {} bytes",
+                entry_offset,
+                data_offset - entries_offset,
+                num_entries,
+                entries_size
+            );
+        }
+
+        let mut metadata = BTreeMap::new();
+        let mut inst_counts_section = None;
+        let mut inst_count_chunks: Cow<[InstCountChunk]> = Cow::Borrowed(&[]);
+        let mut image_refs: Cow<[ImageRef]> = Cow::Borrowed(&[]);
+        let mut thread_segments: Cow<[ThreadSegment]> = Cow::Borrowed(&[]);
+        for (optional_section, section) in optional_sections.iter().zip(extra_sections.iter()) {
+            match optional_section.kind {
+                OPTIONAL_SECTION_METADATA => {
+                    let data = &content[section.offset..section.offset + section.size];
+                    metadata = serde_json::from_slice(data).context("Failed to parse metadata")?;
+                }
+                OPTIONAL_SECTION_INST_COUNTS => inst_counts_section = Some(section),
+                OPTIONAL_SECTION_INST_COUNT_CHUNKS => {
+                    inst_count_chunks = section_array(content, section, allow_misaligned)?;
+                }
+                OPTIONAL_SECTION_IMAGE_REFS => {
+                    image_refs = section_array(content, section, allow_misaligned)?;
+                }
+                OPTIONAL_SECTION_THREAD_SEGMENTS => {
+                    thread_segments = section_array(content, section, allow_misaligned)?;
+                }
+                _ => {}
+            }
+        }
+
+        // instruction counts must be contiguous and cover all chunks
+        if !inst_count_chunks.is_empty() || inst_counts_section.is_some() {
+            let Some(inst_counts_section) = inst_counts_section else {
+                bail!("Instruction count chunks are present without instruction counts");
+            };
+            if inst_count_chunks.len() != chunks.len() {
+                bail!(
+                    "Got {} instruction count chunks, expected {}",
+                    inst_count_chunks.len(),
+                    chunks.len()
+                );
+            }
+            let mut data_offset = inst_counts_section.offset as u64;
+            for (i, inst_count_chunk) in inst_count_chunks.iter().enumerate() {
+                if inst_count_chunk.data_offset != data_offset {
+                    bail!(
+                        "Instruction counts of chunk #{} begin at 0x{:x}, expected 0x{:x}",
+                        i,
+                        inst_count_chunk.data_offset,
+                        data_offset
+                    );
+                }
+                data_offset = data_offset.saturating_add(inst_count_chunk.data_size);
+            }
+            if data_offset != (inst_counts_section.offset + inst_counts_section.size) as u64 {
+                bail!(
+                    "Instruction count chunks cover {} bytes, expected {} bytes",
+                    data_offset - inst_counts_section.offset as u64,
+                    inst_counts_section.size
+                );
+            }
+        }
+
+        if !image_refs.is_empty() && image_refs.len() as u64 != num_images {
+            bail!(
+                "Got {} image references, expected {}",
+                image_refs.len(),
+                num_images
+            );
+        }
+        let image_store = image_store_from_env()
+            .or_else(|| metadata.get(METADATA_IMAGE_STORE).map(PathBuf::from));
+
+        // thread segments must begin at the first entry and be in order
+        let mut last_entry_offset = None;
+        for (i, segment) in thread_segments.iter().enumerate() {
+            let valid = match last_entry_offset {
+                None => segment.entry_offset == 0,
+                Some(last) => segment.entry_offset > last && segment.entry_offset <= num_entries,
+            };
+            if !valid {
+                bail!(
+                    "Thread segment #{} begins at entry {}, after entry {:?} of {} entries",
+                    i,
+                    segment.entry_offset,
+                    last_entry_offset,
+                    num_entries
+                );
+            }
+            last_entry_offset = Some(segment.entry_offset);
+        }
+
+        Ok(Self {
+            content,
+            version,
+            entry_encoding: EntryEncoding::from_version(version),
+            optional_sections,
+            metadata,
+            inst_count_chunks,
+            image_refs,
+            image_store,
+            inst_index_cache: default_inst_index_cache(),
+            thread_segments,
+            num_entries: num_entries as usize,
+            num_branches: num_branches as usize,
+            num_images: num_images as usize,
+            num_chunks: num_chunks as usize,
+            branches,
+            images,
+            chunks,
+            compressed_entries: &content
+                [entries_section.offset..entries_section.offset + entries_section.size],
+        })
+    }
+
+    /// Open the content of the trace file at the path,
+    /// resolving the recorded image store relative to the directory of the trace file
+    pub fn open_with_path(
+        content: &'a [u8],
+        trace_path: &Path,
+    ) -> anyhow::Result<TraceFileDecoder<'a>> {
+        let mut decoder = Self::open(content)?;
+        if image_store_from_env().is_none()
+            && let Some(image_store) = &decoder.image_store
+            && image_store.is_relative()
+            && let Some(trace_dir) = trace_path.parent()
+        {
+            decoder.image_store = Some(trace_dir.join(image_store));
+        }
+        Ok(decoder)
+    }
+
+    pub fn entries(&self) -> anyhow::Result<TraceEntryIterator<'a>> {
+        TraceEntryIterator::from(self)
+    }
+
+    /// Same as the entries from entry_offset, which is 0 or the beginning of a chunk,
+    /// but the chunks are decoded ahead on up to num_threads background threads
+    pub fn parallel_entries(
+        &self,
+        entry_offset: usize,
+        num_threads: usize,
+    ) -> anyhow::Result<ParallelEntryIterator<'a>> {
+        let index = self
+            .chunks
+            .partition_point(|chunk| (chunk.entry_offset as usize) < entry_offset);
+        let sequential = if self.chunks.is_empty() {
+            if entry_offset != 0 {
+                bail!("Trace without chunks must be decoded from the beginning");
+            }
+            Some(self.entries()?)
+        } else {
+            if self
+                .chunks
+                .get(index)
+                .is_some_and(|chunk| chunk.entry_offset as usize != entry_offset)
+            {
+                bail!("Entry {} is not at the beginning of a chunk", entry_offset);
+            }
+            None
+        };
+        Ok(ParallelEntryIterator::new(
+            self.content,
+            self.entry_encoding,
+            self.chunks.clone(),
+            index,
+            num_threads.max(1),
+            sequential,
+        ))
+    }
+
+    /// Fully decode the entries and validate them, it takes a full pass over the trace
+    pub fn verify(&self) -> anyhow::Result<()> {
+        if self.chunks.is_empty() {
+            let (num_entries, _) = self
+                .verify_entries(self.compressed_entries)
+                .context("Failed to decode entries")?;
+            if num_entries != self.num_entries {
+                bail!(
+                    "Decoded {} entries, expected {}",
+                    num_entries,
+                    self.num_entries
+                );
+            }
+        } else {
+            // each chunk must be decodable on its own
+            for (i, chunk) in self.chunks.iter().enumerate() {
+                let compressed_entries = &self.content
+                    [chunk.data_offset as usize..(chunk.data_offset + chunk.data_size) as usize];
+                let (num_entries, num_taken) = self
+                    .verify_entries(compressed_entries)
+                    .with_context(|| format!("Failed to decode chunk #{}", i))?;
+                if num_entries as u64 != chunk.num_entries {
+                    bail!(
+                        "Decoded {} entries in chunk #{}, expected {}",
+                        num_entries,
+                        i,
+                        chunk.num_entries
+                    );
+                }
+
+                // one instruction count for each taken branch
+                if let Some(inst_count_chunk) = self.inst_count_chunks.get(i) {
+                    let compressed_inst_counts = &self.content[inst_count_chunk.data_offset as usize
+                        ..(inst_count_chunk.data_offset + inst_count_chunk.data_size) as usize];
+                    let inst_counts = zstd::decode_all(Cursor::new(compressed_inst_counts))
+                        .with_context(|| {
+                            format!("Failed to decode instruction counts of chunk #{}", i)
+                        })?;
+                    if inst_counts.len() != num_taken * 4 {
+                        bail!(
+                            "Decoded {} bytes of instruction counts in chunk #{}, expected {} taken branches",
+                            inst_counts.len(),
+                            i,
+                            num_taken
+                        );
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Decode entries from zstd frames and check branch indices,
+    /// returns the number of entries and taken entries
+    fn verify_entries(&self, compressed_entries: &[u8]) -> anyhow::Result<(usize, usize)> {
+        let mut decoder = zstd::stream::read::Decoder::new(Cursor::new(compressed_entries))?;
+        let mut buf = vec![0u8; 1024 * 256];
+        let mut entries = vec![];
+        let mut num_entries = 0;
+        let mut num_taken = 0;
+        // bytes of incomplete entry at the beginning of buf
+        let mut remaining = 0;
+        loop {
+            let size = decoder.read(&mut buf[remaining..])?;
+            if size == 0 {
+                break;
+            }
+            let size = remaining + size;
+            entries.clear();
+            let consumed = self.entry_encoding.decode(&buf[..size], &mut entries)?;
+            for entry in &entries {
+                if entry.get_br_index() >= self.num_branches {
+                    bail!(
+                        "Entry {} refers to branch {} out of {} branches",
+                        num_entries,
+                        entry.get_br_index(),
+                        self.num_branches
+                    );
+                }
+                num_entries += 1;
+                num_taken += entry.get_taken() as usize;
+            }
+            buf.copy_within(consumed..size, 0);
+            remaining = size - consumed;
+        }
+        if remaining != 0 {
+            bail!("Entries end with {} trailing bytes", remaining);
+        }
+        Ok((num_entries, num_taken))
+    }
+
+    /// Whether the trace has embedded instruction counts
+    pub fn has_inst_counts(&self) -> bool {
+        !self.inst_count_chunks.is_empty()
+    }
+
+    /// Instruction counter to use with the entries of the seek result,
+    /// it disassembles the images if the instruction counts are not embedded
+    pub fn inst_counter(&self, seek: &mut TraceSeek<'a>) -> anyhow::Result<TraceInstCounter<'a>> {
+        if let Some(inst_counts) = seek.inst_counts.take() {
+            return Ok(TraceInstCounter::Embedded(inst_counts));
+        }
+        if !self.thread_segments.is_empty() {
+            // the images only tell the instructions within a single thread
+            bail!(
+                "Instruction counting of multi-threaded traces requires embedded instruction counts"
+            );
+        }
+
+        // create a mapping from instruction address to instruction index for instruction counting
+        let images = self.get_images()?;
+        let mut mapping =
+            create_inst_index_mapping_from_images(&images, self.inst_index_cache.as_deref())?;
+        let mut counter = ImageInstCounter::new(&mut mapping, &images, &self.branches);
+        counter.resume(seek.last_taken_br_index);
+        Ok(TraceInstCounter::Images(counter))
+    }
+
+    /// Threads in the trace in ascending order, thread 0 if single-threaded
+    pub fn thread_ids(&self) -> Vec<u64> {
+        if self.thread_segments.is_empty() {
+            return vec![0];
+        }
+        let mut thread_ids: Vec<u64> = self
+            .thread_segments
+            .iter()
+            .map(|segment| segment.thread_id)
+            .collect();
+        thread_ids.sort();
+        thread_ids.dedup();
+        thread_ids
+    }
+
+    /// Iterate the entries of the seek result along with their threads and instruction counts,
+    /// only the entries of the given thread if specified.
+    /// If decode_threads is not zero, the entries are decoded ahead on background threads.
+    pub fn thread_entries(
+        &self,
+        mut seek: TraceSeek<'a>,
+        thread_id: Option<u64>,
+        decode_threads: usize,
+    ) -> anyhow::Result<ThreadEntryIterator<'a>> {
+        let inst_counter = if self.can_count_instructions() {
+            Some(self.inst_counter(&mut seek)?)
+        } else {
+            None
+        };
+        let entry_index = seek.entry_offset;
+        Ok(ThreadEntryIterator {
+            entries: self.entry_batches(seek, decode_threads)?,
+            inst_counter,
+            decode_threads,
+            thread_segments: self.thread_segments.clone(),
+            segment_index: self.thread_segment_index(entry_index),
+            thread_filter: thread_id,
+            buf: vec![],
+            pos: 0,
+            entry_index,
+        })
+    }
+
+    /// Whether inst_counter() works: with embedded instruction counts,
+    /// or with images if single-threaded
+    pub fn can_count_instructions(&self) -> bool {
+        self.has_inst_counts() || (self.thread_segments.is_empty() && !self.images.is_empty())
+    }
+
+    /// Index of the thread segment containing the entry
+    fn thread_segment_index(&self, entry_index: usize) -> usize {
+        self.thread_segments
+            .partition_point(|segment| segment.entry_offset <= entry_index as u64)
+            .saturating_sub(1)
+    }
+
+    /// Entries of the seek result, decoded on background threads if decode_threads is not zero
+    fn entry_batches(
+        &self,
+        seek: TraceSeek<'a>,
+        decode_threads: usize,
+    ) -> anyhow::Result<Box<dyn Iterator<Item = Vec<Entry>> + 'a>> {
+        Ok(if decode_threads > 0 {
+            Box::new(self.parallel_entries(seek.entry_offset, decode_threads)?)
+        } else {
+            Box::new(seek.entries)
+        })
+    }
+
+    /// Embedded instruction counts from the given chunk to the end
+    fn inst_counts_from_chunk(
+        &self,
+        index: usize,
+    ) -> anyhow::Result<Option<InstCountIterator<'a>>> {
+        if !self.has_inst_counts() {
+            return Ok(None);
+        }
+        let first = &self.inst_count_chunks[index];
+        let last = &self.inst_count_chunks[self.inst_count_chunks.len() - 1];
+        Ok(Some(InstCountIterator::new(
+            &self.content[first.data_offset as usize..(last.data_offset + last.data_size) as usize],
+        )?))
+    }
+
+    /// Whether the trace supports seeking by instruction count
+    pub fn is_seekable(&self) -> bool {
+        !self.chunks.is_empty()
+            && self
+                .chunks
+                .iter()
+                .all(|chunk| chunk.get_instruction_offset().is_some())
+    }
+
+    /// Seek to the chunk that contains the given instruction.
+    /// The caller continues instruction counting from the returned state,
+    /// and the target instruction is reached within the first chunk.
+    /// If the trace is not seekable, it starts from the beginning.
+    pub fn seek_to_instruction(&self, instruction: u64) -> anyhow::Result<TraceSeek<'a>> {
+        if !self.is_seekable() {
+            return Ok(TraceSeek {
+                entry_offset: 0,
+                instruction_offset: 0,
+                last_taken_br_index: None,
+                entries: self.entries()?,
+                inst_counts: self.inst_counts_from_chunk(0)?,
+            });
+        }
+
+        // find the last chunk that begins before or at the instruction
+        let index = self
+            .chunks
+            .partition_point(|chunk| chunk.instruction_offset <= instruction)
+            .saturating_sub(1);
+        let chunk = &self.chunks[index];
+        let last_chunk = &self.chunks[self.chunks.len() - 1];
+
+        // chunks are stored contiguously, so we can decode from the chunk to the end
+        let compressed_entries = &self.content
+            [chunk.data_offset as usize..(last_chunk.data_offset + last_chunk.data_size) as usize];
+        Ok(TraceSeek {
+            entry_offset: chunk.entry_offset as usize,
+            instruction_offset: chunk.instruction_offset,
+            last_taken_br_index: chunk.get_last_taken_br_index(),
+            entries: TraceEntryIterator::new(
+                compressed_entries,
+                self.num_entries - chunk.entry_offset as usize,
+                self.entry_encoding,
+            )?,
+            inst_counts: self.inst_counts_from_chunk(index)?,
+        })
+    }
+
+    pub fn get_image_data(&self, image: &RawImage) -> &[u8] {
+        &self.content[image.data_offset as usize..(image.data_offset + image.data_size) as usize]
+    }
+
+    /// Whether the image content is kept in the image store instead of the trace file
+    pub fn is_image_stored(&self, index: usize) -> bool {
+        self.images[index].data_size == 0 && index < self.image_refs.len()
+    }
+
+    /// Images with content, the referenced ones are read from the image store
+    pub fn get_images(&self) -> anyhow::Result<Vec<Image>> {
+        let mut res = vec![];
+        for (i, image) in self.images.iter().enumerate() {
+            if self.is_image_stored(i) {
+                let Some(image_store) = &self.image_store else {
+                    bail!(
+                        "Image {} is kept in an image store, but the image store is unknown",
+                        image.get_filename()?
+                    );
+                };
+                res.push(Image {
+                    start: image.start,
+                    len: image.len,
+                    data: load_image(image_store, &self.image_refs[i])?,
+                    filename: image.get_filename()?,
+                });
+            } else {
+                res.push(Image::from(image, self)?);
+            }
+        }
+        Ok(res)
+    }
+
+    // find corresponding image & offset
+    // NOTE: this reports the file offset, instead of virtual address
+    // for statically linked executables, it differs by 0x400000
+    pub fn get_addr_location(&self, addr: u64) -> anyhow::Result<String> {
+        for image in self.images.iter() {
+            if addr >= image.start && addr < image.start + image.len {
+                return Ok(format!(
+                    "{}:0x{:x}",
+                    image.get_filename()?,
+                    addr - image.start
+                ));
+            }
+        }
+        Ok(format!("unknown:0x{:x}", addr))
+    }
+}
+
+const BUFFER_SIZE: usize = 16384;
+
+/// sizeof(file_header) of traces written by TraceFileEncoder
+const HEADER_SIZE: u64 = 104;
+
+/// Append-only output of TraceFileEncoder, tracking the offset from the beginning of file
+struct TraceWriter<'a> {
+    inner: BufWriter<Box<dyn Write + 'a>>,
+    position: u64,
+}
+
+impl Write for TraceWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let size = self.inner.write(buf)?;
+        self.position += size as u64;
+        Ok(size)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Pad with zeros so that the next section is aligned to 8 bytes
+fn align_section(writer: &mut TraceWriter) -> anyhow::Result<()> {
+    let offset = writer.position;
+    let padding = offset.next_multiple_of(8) - offset;
+    writer.write_all(&[0u8; 8][..padding as usize])?;
+    Ok(())
+}
+
+/// Instruction counting while recording events,
+/// for the chunk index and the embedded instruction counts
+enum InstCounting {
+    /// not started yet, the images may be added later
+    Pending,
+    /// computed from the images, with fallbacks for branches outside the text sections
+    Images {
+        mapping: InstIndexMapping,
+        counter: ImageInstCounter,
+    },
+    /// provided by the caller
+    Explicit,
+    /// no images, or the caller stops providing the counts
+    Disabled,
+}
+
+pub struct TraceFileEncoder<'a> {
+    // trace file, None if writing to a stream
+    pub file: Option<&'a File>,
+    // the zstd frame of the current chunk
+    encoder: Option<Encoder<'a, TraceWriter<'a>>>,
+    // writer between chunks
+    writer: Option<TraceWriter<'a>>,
+
+    // content
+    pub num_entries: usize,
+    pub branches: Vec<Branch>,
+    pub images: Vec<Image>,
+    pub chunks: Vec<Chunk>,
+    /// provenance of the trace, see METADATA_* for well-known keys
+    pub metadata: BTreeMap<String, String>,
+    /// keep the images in this image store and reference them by content hash
+    pub image_store: Option<PathBuf>,
+    // trace file, the image store is recorded relative to its directory
+    trace_path: Option<PathBuf>,
+
+    // maintain mapping from (inst_addr, targ_addr) to branch index
+    pub mapping: HashMap<(u64, u64), usize>,
+
+    // output buffer
+    pub buffer: Box<[Entry]>,
+    pub buffer_size: usize,
+    // encoding of the entries, decides the trace version
+    entry_encoding: EntryEncoding,
+    // encoded bytes of the output buffer
+    encoded: Vec<u8>,
+
+    // state for chunk index
+    last_taken_br_index: Option<usize>,
+    inst_counting: InstCounting,
+    instructions: u64,
+    // instruction counts of taken branches in the current chunk
+    inst_counts: Vec<u32>,
+    // compressed instruction counts of each chunk
+    inst_count_frames: Vec<Vec<u8>>,
+
+    // thread of the following events
+    thread_id: u64,
+    thread_segments: Vec<ThreadSegment>,
+    // last taken branch counted for threads other than the current one
+    thread_last_taken_br_index: HashMap<u64, Option<usize>>,
+}
+
+impl<'a> TraceFileEncoder<'a> {
+    /// Falls back to the append-only layout if the file is not seekable, e.g. a named pipe
+    pub fn open(file: &'a File) -> anyhow::Result<Self> {
+        if !file.metadata()?.is_file() {
+            return Self::open_stream(file);
+        }
+        let mut encoder = Self::new(Box::new(file))?;
+        encoder.file = Some(file);
+        Ok(encoder)
+    }
+
+    /// Write to a non-seekable stream, e.g. a pipe or stdout, in the append-only layout
+    pub fn open_stream(stream: impl Write + 'a) -> anyhow::Result<Self> {
+        Self::new(Box::new(stream))
+    }
+
+    fn new(output: Box<dyn Write + 'a>) -> anyhow::Result<Self> {
+        // the header is written before the first chunk, when the version is decided
+        let writer = TraceWriter {
+            inner: BufWriter::new(output),
+            position: 0,
+        };
+        Ok(Self {
+            file: None,
+            encoder: None,
+            writer: Some(writer),
+            num_entries: 0,
+            branches: vec![],
+            mapping: HashMap::new(),
+            buffer: vec![Entry::default(); BUFFER_SIZE].into_boxed_slice(),
+            buffer_size: 0,
+            entry_encoding: EntryEncoding::Fixed,
+            encoded: vec![],
+            images: vec![],
+            chunks: vec![],
+            metadata: BTreeMap::new(),
+            image_store: None,
+            trace_path: None,
+            last_taken_br_index: None,
+            inst_counting: InstCounting::Pending,
+            instructions: 0,
+            inst_counts: vec![],
+            inst_count_frames: vec![],
+            thread_id: 0,
+            thread_segments: vec![],
+            thread_last_taken_br_index: HashMap::new(),
+        })
+    }
+
+    /// Path of the trace file, so that the image store is recorded relative to it
+    /// and found after moving both. Otherwise the absolute path of the image store is recorded.
+    pub fn set_trace_path(&mut self, trace_path: &Path) {
+        self.trace_path = Some(trace_path.to_path_buf());
+    }
+
+    /// Encode entries in the given encoding, instead of fixed-width entries
+    /// that limit the branch index to 2^31. It must be called before the first event.
+    pub fn set_entry_encoding(&mut self, encoding: EntryEncoding) -> anyhow::Result<()> {
+        if self.num_entries > 0 {
+            bail!("Entry encoding must be set before the first event");
+        }
+        self.entry_encoding = encoding;
+        Ok(())
+    }
+
+    /// Trace version written, decided by the output and the entry encoding
+    fn version(&self) -> u64 {
+        match (self.file.is_some(), self.entry_encoding) {
+            (true, EntryEncoding::Fixed) => TRACE_VERSION_SECTIONS,
+            (false, EntryEncoding::Fixed) => TRACE_VERSION_FOOTER,
+            (true, EntryEncoding::Varint) => TRACE_VERSION_VARINT_SECTIONS,
+            (false, EntryEncoding::Varint) => TRACE_VERSION_VARINT_FOOTER,
+        }
+    }
+
+    /// Leave space for file_header at the beginning of file, with only magic and version set.
+    /// The complete header is written in finish()
+    fn begin_file(&mut self) -> anyhow::Result<()> {
+        let version = self.version();
+        let writer = self.writer.as_mut().unwrap();
+        if writer.position == 0 {
+            let mut header = [0u8; HEADER_SIZE as usize];
+            header[0..8].copy_from_slice(&TRACE_MAGIC.to_le_bytes());
+            header[8..16].copy_from_slice(&version.to_le_bytes());
+            writer.write_all(&header)?;
+        }
+        Ok(())
+    }
+
+    /// Following events belong to the given thread, otherwise all events belong to thread 0.
+    /// Instructions are counted separately for each thread.
+    pub fn set_thread(&mut self, thread_id: u64) {
+        if thread_id == self.thread_id {
+            return;
+        }
+        if let InstCounting::Images { counter, .. } = &mut self.inst_counting {
+            self.thread_last_taken_br_index
+                .insert(self.thread_id, counter.last_taken_br_index());
+            counter.resume(self.thread_last_taken_br_index.remove(&thread_id).flatten());
+        }
+
+        if self.thread_segments.is_empty() {
+            self.thread_segments.push(ThreadSegment {
+                thread_id: self.thread_id,
+                entry_offset: 0,
+            });
+        }
+        let last = self.thread_segments.last_mut().unwrap();
+        if last.entry_offset == self.num_entries as u64 {
+            // no events since the last switch
+            last.thread_id = thread_id;
+            let len = self.thread_segments.len();
+            if len >= 2 && self.thread_segments[len - 2].thread_id == thread_id {
+                self.thread_segments.pop();
+            }
+        } else {
+            self.thread_segments.push(ThreadSegment {
+                thread_id,
+                entry_offset: self.num_entries as u64,
+            });
+        }
+        self.thread_id = thread_id;
+    }
+
+    /// Returns internal branch index for optimization
+    pub fn record_event(
+        &mut self,
+        inst_addr: u64,
+        targ_addr: u64,
+        inst_length: u32,
+        branch_type: BranchType,
+        taken: bool,
+    ) -> anyhow::Result<usize> {
+        let br_index = self.get_branch_index(inst_addr, targ_addr, inst_length, branch_type);
+
+        self.record_event_with_branch_index(br_index, taken)?;
+
+        Ok(br_index)
+    }
+
+    /// Find the branch index of (inst_addr, targ_addr), add the branch if not found
+    pub fn get_branch_index(
+        &mut self,
+        inst_addr: u64,
+        targ_addr: u64,
+        inst_length: u32,
+        branch_type: BranchType,
+    ) -> usize {
+        match self.mapping.get(&(inst_addr, targ_addr)) {
+            Some(index) => *index,
+            None => {
+                let index = self.branches.len();
+                self.branches.push(Branch {
+                    inst_addr,
+                    targ_addr,
+                    inst_length,
+                    branch_type,
+                });
+                self.mapping.insert((inst_addr, targ_addr), index);
+                index
+            }
+        }
+    }
+
+    /// If the caller already knows the branch index, use this
+    pub fn record_event_with_branch_index(
+        &mut self,
+        br_index: usize,
+        taken: bool,
+    ) -> anyhow::Result<()> {
+        self.record(br_index, taken, None)
+    }
+
+    /// If the caller knows the instructions executed at each taken branch,
+    /// i.e. since the target of the last taken branch, including the branch itself,
+    /// use this for all events instead of disassembling the images.
+    /// The instruction count is ignored for not taken branches.
+    pub fn record_event_with_inst_count(
+        &mut self,
+        br_index: usize,
+        taken: bool,
+        instructions: u64,
+    ) -> anyhow::Result<()> {
+        self.record(br_index, taken, Some(instructions))
+    }
+
+    fn record(
+        &mut self,
+        br_index: usize,
+        taken: bool,
+        instructions: Option<u64>,
+    ) -> anyhow::Result<()> {
+        if self.num_entries.is_multiple_of(CHUNK_NUM_ENTRIES) {
+            self.begin_chunk()?;
         }
 
-        let images_offset = writer.stream_position()?;
+        let entry = Entry::from(br_index, taken);
+        self.buffer[self.buffer_size] = entry;
+        self.buffer_size += 1;
 
-        // write images
-        writer.write_all(unsafe {
-            std::slice::from_raw_parts(
-                raw_images.as_ptr() as *const u8,
-                raw_images.len() * std::mem::size_of::<RawImage>(),
-            )
-        })?;
+        if taken {
+            self.count_instructions(br_index, instructions);
+            self.last_taken_br_index = Some(br_index);
+        }
 
-        // write header
-        writer.seek(std::io::SeekFrom::Start(0))?;
-        // magic
-        let val_u64 = 0x2121505845504243_u64;
-        writer.write_all(unsafe {
-            std::slice::from_raw_parts(
-                &val_u64 as *const u64 as *const u8,
-                std::mem::size_of::<u64>(),
-            )
-        })?;
+        self.num_entries += 1;
 
-        // version
-        let val_u64 = 0_u64;
-        writer.write_all(unsafe {
-            std::slice::from_raw_parts(
-                &val_u64 as *const u64 as *const u8,
-                std::mem::size_of::<u64>(),
-            )
-        })?;
+        if self.buffer_size == BUFFER_SIZE {
+            self.flush()?;
+        }
 
-        // num_entries
-        let val_u64 = self.num_entries as u64;
-        writer.write_all(unsafe {
-            std::slice::from_raw_parts(
-                &val_u64 as *const u64 as *const u8,
-                std::mem::size_of::<u64>(),
-            )
-        })?;
+        if self.num_entries.is_multiple_of(CHUNK_NUM_ENTRIES) {
+            self.end_chunk()?;
+        }
+        Ok(())
+    }
 
-        // entries_offset
-        let val_u64 = entries_offset;
-        writer.write_all(unsafe {
-            std::slice::from_raw_parts(
-                &val_u64 as *const u64 as *const u8,
-                std::mem::size_of::<u64>(),
-            )
-        })?;
+    /// Reuse the instruction index mapping of the images,
+    /// instead of creating it again upon the first event
+    pub fn set_inst_index_mapping(&mut self, mut mapping: InstIndexMapping) {
+        assert_eq!(self.num_entries, 0);
+        let counter = ImageInstCounter::new(&mut mapping, &self.images, &[]);
+        self.inst_counting = InstCounting::Images { mapping, counter };
+    }
 
-        // entries_size
-        let val_u64 = entries_size;
-        writer.write_all(unsafe {
-            std::slice::from_raw_parts(
-                &val_u64 as *const u64 as *const u8,
-                std::mem::size_of::<u64>(),
-            )
-        })?;
+    fn count_instructions(&mut self, br_index: usize, explicit: Option<u64>) {
+        if let InstCounting::Pending = self.inst_counting {
+            // all images should have been added before the first event
+            self.inst_counting = if explicit.is_some() {
+                InstCounting::Explicit
+            } else if self.images.is_empty() {
+                InstCounting::Disabled
+            } else {
+                match create_inst_index_mapping_from_images(
+                    &self.images,
+                    default_inst_index_cache().as_deref(),
+                ) {
+                    Ok(mut mapping) => {
+                        let counter = ImageInstCounter::new(&mut mapping, &self.images, &[]);
+                        InstCounting::Images { mapping, counter }
+                    }
+                    Err(err) => {
+                        println!("Instruction counting is disabled: {:?}", err);
+                        InstCounting::Disabled
+                    }
+                }
+            };
+        }
+
+        let instructions = match (&mut self.inst_counting, explicit) {
+            (InstCounting::Images { mapping, counter }, None) => {
+                // branches are added to the counter as they are discovered
+                for branch in &self.branches[counter.num_branches()..] {
+                    counter.add_branch(mapping, &self.images, branch);
+                }
+                Some(counter.count_taken(br_index))
+            }
+            (InstCounting::Explicit, Some(instructions)) => Some(instructions),
+            (InstCounting::Disabled, _) => return,
+            // mixed explicit and implicit counting
+            _ => None,
+        };
+        match instructions.and_then(|instructions| u32::try_from(instructions).ok()) {
+            Some(instructions) => {
+                self.instructions += instructions as u64;
+                self.inst_counts.push(instructions);
+            }
+            None => {
+                println!(
+                    "Instruction counting is disabled: missing or oversized count at branch 0x{:x}",
+                    self.branches[br_index].inst_addr
+                );
+                self.inst_counting = InstCounting::Disabled;
+                self.inst_counts = vec![];
+                self.inst_count_frames = vec![];
+            }
+        }
+    }
+
+    fn begin_chunk(&mut self) -> anyhow::Result<()> {
+        self.begin_file()?;
+        let writer = self.writer.take().unwrap();
+        let data_offset = writer.position;
+        self.chunks.push(Chunk {
+            entry_offset: self.num_entries as u64,
+            num_entries: 0,
+            instruction_offset: match &self.inst_counting {
+                InstCounting::Disabled => u64::MAX,
+                _ => self.instructions,
+            },
+            last_taken_br_index: self
+                .last_taken_br_index
+                .map(|index| index as u64)
+                .unwrap_or(u64::MAX),
+            data_offset,
+            data_size: 0,
+        });
+        self.encoder = Some(Encoder::new(writer, 0)?);
+        Ok(())
+    }
+
+    fn end_chunk(&mut self) -> anyhow::Result<()> {
+        self.flush()?;
+        let writer = self.encoder.take().unwrap().finish()?;
+        let chunk = self.chunks.last_mut().unwrap();
+        chunk.num_entries = self.num_entries as u64 - chunk.entry_offset;
+        chunk.data_size = writer.position - chunk.data_offset;
+        self.writer = Some(writer);
+
+        if !matches!(self.inst_counting, InstCounting::Disabled) {
+            let inst_counts: Vec<u8> = self
+                .inst_counts
+                .iter()
+                .flat_map(|instructions| instructions.to_le_bytes())
+                .collect();
+            self.inst_count_frames
+                .push(zstd::bulk::compress(&inst_counts, 0)?);
+            self.inst_counts.clear();
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> anyhow::Result<()> {
+        if self.buffer_size > 0 {
+            self.encoded.clear();
+            for entry in &self.buffer[..self.buffer_size] {
+                self.entry_encoding.encode(*entry, &mut self.encoded)?;
+            }
+            self.encoder.as_mut().unwrap().write_all(&self.encoded)?;
+            self.buffer_size = 0;
+        }
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> anyhow::Result<()> {
+        if self.encoder.is_some() {
+            self.end_chunk()?;
+        }
+
+        // chunks with unknown instruction counts are not seekable
+        if let InstCounting::Disabled = self.inst_counting {
+            for chunk in &mut self.chunks {
+                chunk.instruction_offset = u64::MAX;
+            }
+        }
+        match &self.inst_counting {
+            InstCounting::Images { counter, .. } => self.metadata.insert(
+                METADATA_INST_COUNT_FALLBACKS.to_string(),
+                serde_json::to_string(&counter.fallbacks)?,
+            ),
+            _ => self.metadata.remove(METADATA_INST_COUNT_FALLBACKS),
+        };
 
-        // num_branches
-        let val_u64 = self.branches.len() as u64;
+        self.begin_file()?;
+        let mut writer = self.writer.take().unwrap();
+        let entries_offset = HEADER_SIZE;
+        let entries_size = writer.position - entries_offset;
+
+        // write branches
+        align_section(&mut writer)?;
+        let branches_offset = writer.position;
         writer.write_all(unsafe {
             std::slice::from_raw_parts(
-                &val_u64 as *const u64 as *const u8,
-                std::mem::size_of::<u64>(),
+                self.branches.as_ptr() as *const u8,
+                self.branches.len() * std::mem::size_of::<Branch>(),
             )
         })?;
 
-        // branches_offset
-        let val_u64 = branches_offset;
+        // write image content, or add it to the image store
+        let mut raw_images = vec![];
+        let mut image_refs = vec![];
+        for image in &self.images {
+            let data_offset = writer.position;
+            let data_size = match &self.image_store {
+                Some(image_store) => {
+                    image_refs.push(store_image(image_store, &image.data)?);
+                    0
+                }
+                None => {
+                    writer.write_all(&image.data)?;
+                    image.data.len() as u64
+                }
+            };
+            let mut filename = [0u8; 256];
+            let filename_bytes = image.filename.as_bytes();
+            filename[0..filename_bytes.len()].copy_from_slice(filename_bytes);
+            raw_images.push(RawImage {
+                start: image.start,
+                len: image.len,
+                data_size,
+                data_offset,
+                filename,
+            });
+        }
+        match &self.image_store {
+            Some(image_store) => {
+                let image_store = std::path::absolute(image_store)?;
+                let trace_path = match &self.trace_path {
+                    Some(trace_path) => Some(std::path::absolute(trace_path)?),
+                    None => None,
+                };
+                let recorded = match trace_path.as_deref().and_then(Path::parent) {
+                    Some(trace_dir) => {
+                        pathdiff::diff_paths(&image_store, trace_dir).unwrap_or(image_store)
+                    }
+                    None => image_store,
+                };
+                let recorded = recorded.to_str().with_context(|| {
+                    format!("Image store {} is not valid UTF-8", recorded.display())
+                })?;
+                self.metadata
+                    .insert(METADATA_IMAGE_STORE.to_string(), recorded.to_string())
+            }
+            None => self.metadata.remove(METADATA_IMAGE_STORE),
+        };
+
+        align_section(&mut writer)?;
+        let images_offset = writer.position;
+
+        // write images
         writer.write_all(unsafe {
             std::slice::from_raw_parts(
-                &val_u64 as *const u64 as *const u8,
-                std::mem::size_of::<u64>(),
+                raw_images.as_ptr() as *const u8,
+                raw_images.len() * std::mem::size_of::<RawImage>(),
             )
         })?;
 
-        // num_images
-        let val_u64 = self.images.len() as u64;
+        // write chunk index
+        align_section(&mut writer)?;
+        let chunks_offset = writer.position;
         writer.write_all(unsafe {
             std::slice::from_raw_parts(
-                &val_u64 as *const u64 as *const u8,
-                std::mem::size_of::<u64>(),
+                self.chunks.as_ptr() as *const u8,
+                self.chunks.len() * std::mem::size_of::<Chunk>(),
             )
         })?;
 
-        // images_offset
-        let val_u64 = images_offset;
+        // write optional sections
+        let mut optional_sections = vec![];
+        let metadata = serde_json::to_vec(&self.metadata)?;
+        optional_sections.push(OptionalSection {
+            kind: OPTIONAL_SECTION_METADATA,
+            offset: writer.position,
+            size: metadata.len() as u64,
+        });
+        writer.write_all(&metadata)?;
+
+        // write embedded instruction counts
+        if !matches!(self.inst_counting, InstCounting::Disabled) && !self.chunks.is_empty() {
+            let mut inst_count_chunks = vec![];
+            let inst_counts_offset = writer.position;
+            for frame in &self.inst_count_frames {
+                inst_count_chunks.push(InstCountChunk {
+                    data_offset: writer.position,
+                    data_size: frame.len() as u64,
+                });
+                writer.write_all(frame)?;
+            }
+            optional_sections.push(OptionalSection {
+                kind: OPTIONAL_SECTION_INST_COUNTS,
+                offset: inst_counts_offset,
+                size: writer.position - inst_counts_offset,
+            });
+
+            align_section(&mut writer)?;
+            optional_sections.push(OptionalSection {
+                kind: OPTIONAL_SECTION_INST_COUNT_CHUNKS,
+                offset: writer.position,
+                size: (inst_count_chunks.len() * std::mem::size_of::<InstCountChunk>()) as u64,
+            });
+            writer.write_all(unsafe {
+                std::slice::from_raw_parts(
+                    inst_count_chunks.as_ptr() as *const u8,
+                    inst_count_chunks.len() * std::mem::size_of::<InstCountChunk>(),
+                )
+            })?;
+        }
+
+        // write thread segments, without the trailing empty one
+        if self.thread_segments.len() > 1
+            && self.thread_segments.last().unwrap().entry_offset == self.num_entries as u64
+        {
+            self.thread_segments.pop();
+        }
+        if !self.thread_segments.is_empty() {
+            align_section(&mut writer)?;
+            optional_sections.push(OptionalSection {
+                kind: OPTIONAL_SECTION_THREAD_SEGMENTS,
+                offset: writer.position,
+                size: (self.thread_segments.len() * std::mem::size_of::<ThreadSegment>()) as u64,
+            });
+            writer.write_all(unsafe {
+                std::slice::from_raw_parts(
+                    self.thread_segments.as_ptr() as *const u8,
+                    self.thread_segments.len() * std::mem::size_of::<ThreadSegment>(),
+                )
+            })?;
+        }
+
+        // write image references
+        if !image_refs.is_empty() {
+            align_section(&mut writer)?;
+            optional_sections.push(OptionalSection {
+                kind: OPTIONAL_SECTION_IMAGE_REFS,
+                offset: writer.position,
+                size: (image_refs.len() * std::mem::size_of::<ImageRef>()) as u64,
+            });
+            writer.write_all(unsafe {
+                std::slice::from_raw_parts(
+                    image_refs.as_ptr() as *const u8,
+                    image_refs.len() * std::mem::size_of::<ImageRef>(),
+                )
+            })?;
+        }
+
+        align_section(&mut writer)?;
+        let optional_sections_offset = writer.position;
         writer.write_all(unsafe {
             std::slice::from_raw_parts(
-                &val_u64 as *const u64 as *const u8,
-                std::mem::size_of::<u64>(),
+                optional_sections.as_ptr() as *const u8,
+                optional_sections.len() * std::mem::size_of::<OptionalSection>(),
             )
         })?;
 
-        writer.flush()?;
+        // write header, or footer if writing to a stream
+        let mut header = vec![];
+        for val_u64 in [
+            TRACE_MAGIC,
+            self.version(),
+            self.num_entries as u64,
+            entries_offset,
+            entries_size,
+            self.branches.len() as u64,
+            branches_offset,
+            self.images.len() as u64,
+            images_offset,
+            self.chunks.len() as u64,
+            chunks_offset,
+            optional_sections.len() as u64,
+            optional_sections_offset,
+        ] {
+            header.extend_from_slice(&val_u64.to_le_bytes());
+        }
+
+        match self.file {
+            Some(mut file) => {
+                writer.flush()?;
+                drop(writer);
+                file.seek(std::io::SeekFrom::Start(0))?;
+                file.write_all(&header)?;
+            }
+            None => {
+                writer.write_all(&header)?;
+                writer.flush()?;
+            }
+        }
         Ok(())
     }
 }
 
+/// Content of a trace file, either memory mapped or read from a stream
+pub enum TraceContent {
+    Mmap(Mmap),
+    Buffer(Vec<u8>),
+}
+
+impl Deref for TraceContent {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            TraceContent::Mmap(mmap) => mmap,
+            TraceContent::Buffer(buffer) => buffer,
+        }
+    }
+}
+
+/// Map the trace file into memory, or read it fully if it is not a regular file,
+/// e.g. a named pipe or /dev/stdin
+pub fn read_trace_file(path: &Path) -> anyhow::Result<TraceContent> {
+    let mut file =
+        File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+    if file.metadata()?.is_file() {
+        Ok(TraceContent::Mmap(unsafe {
+            MmapOptions::new().map(&file)?
+        }))
+    } else {
+        let mut buffer = vec![];
+        file.read_to_end(&mut buffer)?;
+        Ok(TraceContent::Buffer(buffer))
+    }
+}
+
+/// Re-encode a trace file in the latest format, e.g. traces written by the tracers,
+/// and add the given metadata to the existing metadata.
+/// The images are kept in the image store if given, otherwise embedded.
+pub fn convert_trace_file(
+    input_path: &Path,
+    output_path: &Path,
+    metadata: &BTreeMap<String, String>,
+    image_store: Option<&Path>,
+) -> anyhow::Result<()> {
+    let content = read_trace_file(input_path)?;
+    let decoder = TraceFileDecoder::open_with_path(&content, input_path)?;
+
+    let output_file = File::create(output_path)?;
+    let mut encoder = TraceFileEncoder::open(&output_file)?;
+    encoder.set_trace_path(output_path);
+    encoder.set_entry_encoding(decoder.entry_encoding)?;
+    encoder.branches = decoder.branches.to_vec();
+    encoder.images = decoder.get_images()?;
+    encoder.metadata = decoder.metadata.clone();
+    encoder.metadata.extend(metadata.clone());
+    encoder.image_store = image_store.map(Path::to_path_buf);
+    let seek = decoder.seek_to_instruction(0)?;
+    // keep the embedded instruction counts, otherwise the encoder computes them from the images
+    let mut inst_counts = seek.inst_counts;
+    let mut thread_segments = decoder.thread_segments.iter().peekable();
+    let mut entry_index = 0;
+    for entries in seek.entries {
+        for entry in entries {
+            while let Some(segment) =
+                thread_segments.next_if(|segment| segment.entry_offset <= entry_index)
+            {
+                encoder.set_thread(segment.thread_id);
+            }
+            entry_index += 1;
+            match &mut inst_counts {
+                Some(inst_counts) => {
+                    let instructions = if entry.get_taken() {
+                        inst_counts.next().context("Instruction counts end early")?
+                    } else {
+                        0
+                    };
+                    encoder.record_event_with_inst_count(
+                        entry.get_br_index(),
+                        entry.get_taken(),
+                        instructions,
+                    )?;
+                }
+                None => {
+                    encoder
+                        .record_event_with_branch_index(entry.get_br_index(), entry.get_taken())?;
+                }
+            }
+        }
+    }
+    encoder.finish()?;
+    Ok(())
+}
+
 #[derive(Debug, Clone, Hash, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Image {
     pub start: u64,
@@ -468,3 +2336,590 @@ impl Image {
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        BranchType, CHUNK_NUM_ENTRIES, Entry, EntryEncoding, EntrySizeEstimator, Image,
+        ImageInstIndex, InstCountFallbacks, METADATA_IMAGE_STORE, METADATA_INST_COUNT_FALLBACKS,
+        METADATA_TRACER, TRACE_VERSION_FOOTER, TRACE_VERSION_SECTIONS, TRACE_VERSION_VARINT_FOOTER,
+        ThreadSegment, TraceFileDecoder, TraceFileEncoder, create_inst_index_mapping_from_images,
+    };
+
+    #[test]
+    fn test_chunked_roundtrip() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let mut encoder = TraceFileEncoder::open(file.as_file()).unwrap();
+        encoder
+            .metadata
+            .insert(METADATA_TRACER.to_string(), "test".to_string());
+        let count = CHUNK_NUM_ENTRIES * 2 + 100;
+        for i in 0..count {
+            encoder
+                .record_event(
+                    0x1000 + (i % 7) as u64 * 4,
+                    0x2000,
+                    4,
+                    BranchType::ConditionalDirectJump,
+                    i % 3 == 0,
+                )
+                .unwrap();
+        }
+        encoder.finish().unwrap();
+
+        let content = std::fs::read(file.path()).unwrap();
+        let decoder = TraceFileDecoder::open(&content).unwrap();
+        assert_eq!(decoder.version, TRACE_VERSION_SECTIONS);
+        assert_eq!(decoder.metadata[METADATA_TRACER], "test");
+        assert_eq!(decoder.num_entries, count);
+        assert_eq!(decoder.num_branches, 7);
+        assert_eq!(decoder.num_chunks, 3);
+        assert_eq!(decoder.chunks[2].entry_offset, CHUNK_NUM_ENTRIES as u64 * 2);
+        assert_eq!(decoder.chunks[2].num_entries, 100);
+
+        // no images: instruction counts are unknown
+        assert!(!decoder.is_seekable());
+
+        decoder.verify().unwrap();
+
+        let mut i = 0;
+        for entries in decoder.entries().unwrap() {
+            for entry in entries {
+                assert_eq!(entry.get_br_index(), i % 7);
+                assert_eq!(entry.get_taken(), i % 3 == 0);
+                i += 1;
+            }
+        }
+        assert_eq!(i, count);
+
+        // batches stay valid after later batches are decoded
+        let batches = decoder.entries().unwrap().collect::<Vec<_>>();
+        let entries = batches.concat();
+        assert_eq!(entries.len(), count);
+        assert!(
+            entries.iter().enumerate().all(
+                |(i, entry)| entry.get_br_index() == i % 7 && entry.get_taken() == (i % 3 == 0)
+            )
+        );
+
+        // parallel decoding yields the same entries, from any chunk
+        for (entry_offset, num_threads) in [(0, 1), (0, 3), (CHUNK_NUM_ENTRIES, 2)] {
+            let mut i = entry_offset;
+            for entries in decoder.parallel_entries(entry_offset, num_threads).unwrap() {
+                for entry in entries {
+                    assert_eq!(entry.get_br_index(), i % 7);
+                    assert_eq!(entry.get_taken(), i % 3 == 0);
+                    i += 1;
+                }
+            }
+            assert_eq!(i, count);
+        }
+        assert!(decoder.parallel_entries(1, 2).is_err());
+
+        // batches are owned, so they outlive the iterator
+        let batches = decoder.parallel_entries(0, 2).unwrap().collect::<Vec<_>>();
+        assert_eq!(batches.len(), decoder.num_chunks);
+        assert_eq!(batches.iter().map(Vec::len).sum::<usize>(), count);
+        assert_eq!(batches[1][0].get_br_index(), CHUNK_NUM_ENTRIES % 7);
+
+        // the workers do not borrow the content
+        let mut entries = decoder.parallel_entries(0, 2).unwrap();
+        assert_eq!(entries.next().unwrap().len(), CHUNK_NUM_ENTRIES);
+        std::mem::forget(entries);
+    }
+
+    #[test]
+    fn test_entry_encoding() {
+        let entries = [
+            Entry::from(0, false),
+            Entry::from(63, true),
+            Entry::from(0x7FFFFFFF, true),
+            Entry::from(1 << 40, false),
+            Entry::from(0x7FFFFFFFFFFFFFFF, true),
+        ];
+        let mut buf = vec![];
+        for entry in entries {
+            EntryEncoding::Varint.encode(entry, &mut buf).unwrap();
+        }
+        assert_eq!(buf.len(), 1 + 1 + 5 + 6 + 10);
+
+        // incomplete entry at the end is left for the next call
+        let mut decoded = vec![];
+        let consumed = EntryEncoding::Varint
+            .decode(&buf[..buf.len() - 1], &mut decoded)
+            .unwrap();
+        assert_eq!(consumed, buf.len() - 10);
+        assert_eq!(decoded.len(), 4);
+        let mut decoded = vec![];
+        assert_eq!(
+            EntryEncoding::Varint.decode(&buf, &mut decoded).unwrap(),
+            buf.len()
+        );
+        for (entry, decoded) in entries.iter().zip(decoded.iter()) {
+            assert_eq!(entry.0, decoded.0);
+        }
+        // longer than 64 bits
+        assert!(
+            EntryEncoding::Varint
+                .decode(&[0xFF; 11], &mut vec![])
+                .is_err()
+        );
+
+        // fixed-width entries are limited to 2^31 branches
+        let mut buf = vec![];
+        EntryEncoding::Fixed
+            .encode(Entry::from(0x7FFFFFFF, true), &mut buf)
+            .unwrap();
+        assert_eq!(buf, [0xFF; 4]);
+        assert!(
+            EntryEncoding::Fixed
+                .encode(Entry::from(0x80000000, false), &mut buf)
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_varint_roundtrip() {
+        let mut content = vec![];
+        let mut encoder = TraceFileEncoder::open_stream(&mut content).unwrap();
+        encoder.set_entry_encoding(EntryEncoding::Varint).unwrap();
+        // multi-byte entries
+        let num_branches = 300;
+        let count = CHUNK_NUM_ENTRIES + 100;
+        for i in 0..count {
+            let br_index = encoder.get_branch_index(
+                0x1000 + (i % num_branches) as u64 * 4,
+                0x2000,
+                4,
+                BranchType::DirectJump,
+            );
+            encoder
+                .record_event_with_inst_count(br_index, i % 3 == 0, 5)
+                .unwrap();
+        }
+        assert!(encoder.set_entry_encoding(EntryEncoding::Fixed).is_err());
+        encoder.finish().unwrap();
+
+        let decoder = TraceFileDecoder::open(&content).unwrap();
+        assert_eq!(decoder.version, TRACE_VERSION_VARINT_FOOTER);
+        assert_eq!(decoder.entry_encoding, EntryEncoding::Varint);
+        assert_eq!(decoder.num_chunks, 2);
+        decoder.verify().unwrap();
+
+        let mut i = 0;
+        for entries in decoder.entries().unwrap() {
+            for entry in entries {
+                assert_eq!(entry.get_br_index(), i % num_branches);
+                assert_eq!(entry.get_taken(), i % 3 == 0);
+                i += 1;
+            }
+        }
+        assert_eq!(i, count);
+
+        let mut i = CHUNK_NUM_ENTRIES;
+        for entries in decoder.parallel_entries(CHUNK_NUM_ENTRIES, 2).unwrap() {
+            for entry in entries {
+                assert_eq!(entry.get_br_index(), i % num_branches);
+                i += 1;
+            }
+        }
+        assert_eq!(i, count);
+
+        // seek to the second chunk
+        let seek = decoder
+            .seek_to_instruction((CHUNK_NUM_ENTRIES as u64).div_ceil(3) * 5)
+            .unwrap();
+        assert_eq!(seek.entry_offset, CHUNK_NUM_ENTRIES);
+        let mut i = CHUNK_NUM_ENTRIES;
+        for entries in seek.entries {
+            for entry in entries {
+                assert_eq!(entry.get_br_index(), i % num_branches);
+                i += 1;
+            }
+        }
+        assert_eq!(i, count);
+
+        // the estimated size of the current encoding matches the trace
+        let mut estimator = EntrySizeEstimator::new(EntryEncoding::Varint);
+        for entries in decoder.entries().unwrap() {
+            for entry in entries {
+                estimator.push(entry).unwrap();
+            }
+        }
+        let size = estimator.finish().unwrap();
+        let actual = decoder.compressed_entries.len() as u64;
+        assert!(size.abs_diff(actual) < actual / 10, "{} {}", size, actual);
+    }
+
+    #[test]
+    fn test_inst_counts() {
+        let mut content = vec![];
+        let mut encoder = TraceFileEncoder::open_stream(&mut content).unwrap();
+        let count = CHUNK_NUM_ENTRIES * 2 + 10;
+        for i in 0..count {
+            encoder
+                .record_event(
+                    0x1000,
+                    0x2000,
+                    4,
+                    BranchType::ConditionalDirectJump,
+                    i % 2 == 0,
+                )
+                .unwrap();
+        }
+        encoder.finish().unwrap();
+
+        // without images, instruction counts are unavailable
+        let decoder = TraceFileDecoder::open(&content).unwrap();
+        assert!(!decoder.has_inst_counts());
+
+        let mut content = vec![];
+        let mut encoder = TraceFileEncoder::open_stream(&mut content).unwrap();
+        encoder.branches = decoder.branches.to_vec();
+        // every other branch is taken, executing (i % 10) + 1 instructions
+        let mut total = 0;
+        for i in 0..count {
+            encoder
+                .record_event_with_inst_count(0, i % 2 == 0, (i % 10) as u64 + 1)
+                .unwrap();
+            if i % 2 == 0 {
+                total += (i % 10) as u64 + 1;
+            }
+        }
+        encoder.finish().unwrap();
+
+        let decoder = TraceFileDecoder::open(&content).unwrap();
+        assert!(decoder.has_inst_counts());
+        assert!(decoder.is_seekable());
+        decoder.verify().unwrap();
+
+        // seek and count to the end
+        let target = total / 2;
+        let mut seek = decoder.seek_to_instruction(target).unwrap();
+        assert!(seek.entry_offset > 0);
+        assert!(seek.instruction_offset <= target);
+        let mut inst_counter = decoder.inst_counter(&mut seek).unwrap();
+        let mut instructions = seek.instruction_offset;
+        let mut num_entries = seek.entry_offset;
+        for entries in seek.entries {
+            for entry in entries {
+                if entry.get_taken() {
+                    instructions += inst_counter.count_taken(entry.get_br_index());
+                }
+                num_entries += 1;
+            }
+        }
+        assert_eq!(num_entries, count);
+        assert_eq!(instructions, total);
+    }
+
+    #[test]
+    fn test_inst_counts_from_images() {
+        let data = std::fs::read("/bin/true").unwrap();
+        let index = ImageInstIndex::from_data(&data).unwrap();
+        let addr = |i: usize| 0x7000_0000 + index.base + index.offsets[i] as u64;
+        let images = vec![Image {
+            start: 0x7000_0000,
+            len: data.len() as u64,
+            data,
+            filename: "/bin/true".to_string(),
+        }];
+
+        let mut content = vec![];
+        let mut encoder = TraceFileEncoder::open_stream(&mut content).unwrap();
+        encoder.images = images.clone();
+        encoder
+            .set_inst_index_mapping(create_inst_index_mapping_from_images(&images, None).unwrap());
+        let count = CHUNK_NUM_ENTRIES + 10;
+        for i in 0..count {
+            // a loop of two instructions
+            encoder
+                .record_event(addr(1), addr(0), 4, BranchType::DirectJump, true)
+                .unwrap();
+            // a branch outside the images only costs its own count
+            if i == 100 {
+                encoder
+                    .record_event(0x10_0010, addr(0), 4, BranchType::DirectJump, true)
+                    .unwrap();
+            }
+        }
+        encoder.finish().unwrap();
+
+        let decoder = TraceFileDecoder::open(&content).unwrap();
+        assert!(decoder.has_inst_counts());
+        assert!(decoder.is_seekable());
+        let mut seek = decoder.seek_to_instruction(0).unwrap();
+        let inst_counts: Vec<u64> = seek.inst_counts.take().unwrap().collect();
+        assert_eq!(inst_counts.len(), count + 1);
+        assert_eq!(inst_counts[..3], [0, 2, 2]);
+        assert_eq!(inst_counts[101..104], [1, 2, 2]);
+        assert_eq!(inst_counts.iter().sum::<u64>(), (count as u64 - 1) * 2 + 1);
+        let fallbacks: InstCountFallbacks =
+            serde_json::from_str(&decoder.metadata[METADATA_INST_COUNT_FALLBACKS]).unwrap();
+        assert_eq!(
+            fallbacks,
+            InstCountFallbacks {
+                unknown: 1,
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn test_stream_roundtrip() {
+        let mut content = vec![];
+        let mut encoder = TraceFileEncoder::open_stream(&mut content).unwrap();
+        encoder
+            .metadata
+            .insert(METADATA_TRACER.to_string(), "test".to_string());
+        let count = CHUNK_NUM_ENTRIES + 100;
+        for i in 0..count {
+            encoder
+                .record_event(
+                    0x1000 + (i % 5) as u64 * 4,
+                    0x2000,
+                    4,
+                    BranchType::DirectJump,
+                    true,
+                )
+                .unwrap();
+        }
+        encoder.finish().unwrap();
+
+        let decoder = TraceFileDecoder::open(&content).unwrap();
+        assert_eq!(decoder.version, TRACE_VERSION_FOOTER);
+        assert_eq!(decoder.metadata[METADATA_TRACER], "test");
+        assert_eq!(decoder.num_entries, count);
+        assert_eq!(decoder.num_branches, 5);
+        assert_eq!(decoder.num_chunks, 2);
+        decoder.verify().unwrap();
+
+        // a stream cut short has no footer
+        let error = TraceFileDecoder::open(&content[..content.len() - 8]).err();
+        assert!(format!("{}", error.unwrap()).starts_with("Bad footer"));
+    }
+
+    #[test]
+    fn test_corrupted() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let mut encoder = TraceFileEncoder::open(file.as_file()).unwrap();
+        for i in 0..1000 {
+            encoder
+                .record_event(0x1000, 0x2000, 4, BranchType::DirectJump, i % 2 == 0)
+                .unwrap();
+        }
+        encoder.finish().unwrap();
+        let content = std::fs::read(file.path()).unwrap();
+        TraceFileDecoder::open(&content).unwrap().verify().unwrap();
+
+        let error = |content: &[u8]| match TraceFileDecoder::open(content) {
+            Ok(_) => panic!("Corrupted trace is accepted"),
+            Err(err) => format!("{}", err),
+        };
+
+        // bad magic
+        let mut corrupted = content.clone();
+        corrupted[0] = 0;
+        assert!(error(&corrupted).starts_with("Bad magic"));
+
+        // bad version
+        let mut corrupted = content.clone();
+        corrupted[8] = 100;
+        assert!(error(&corrupted).starts_with("Unsupported trace version"));
+
+        // truncated
+        assert!(error(&content[..content.len() - 8]).contains("out of range"));
+        assert!(error(&content[..40]).starts_with("Trace file is truncated"));
+
+        // branches overlap with entries
+        let mut corrupted = content.clone();
+        corrupted[48..56].copy_from_slice(&104u64.to_le_bytes());
+        assert!(error(&corrupted).contains("overlaps with"));
+
+        // misaligned branches
+        let mut corrupted = content.clone();
+        let branches_offset = corrupted.len() as u64 + 1;
+        corrupted.extend_from_slice(&[0u8; 32]);
+        corrupted[48..56].copy_from_slice(&branches_offset.to_le_bytes());
+        assert!(error(&corrupted).contains("misaligned"));
+
+        // branch index out of range
+        let mut corrupted = content.clone();
+        corrupted[40..48].copy_from_slice(&0u64.to_le_bytes());
+        let decoder = TraceFileDecoder::open(&corrupted).unwrap();
+        assert!(
+            format!("{:#}", decoder.verify().unwrap_err()).contains("refers to branch 0 out of 0")
+        );
+    }
+
+    #[test]
+    fn test_image_store() {
+        let store = tempfile::tempdir().unwrap();
+        let image = Image {
+            start: 0x1000,
+            len: 0x1000,
+            data: vec![0x90; 4096],
+            filename: "/lib/test.so".to_string(),
+        };
+
+        // two traces of the same image share one copy
+        let mut contents = vec![];
+        for _ in 0..2 {
+            let mut content = vec![];
+            let mut encoder = TraceFileEncoder::open_stream(&mut content).unwrap();
+            encoder.image_store = Some(store.path().to_path_buf());
+            encoder.images.push(image.clone());
+            let br_index = encoder.get_branch_index(0x1010, 0x1000, 2, BranchType::DirectJump);
+            for _ in 0..100 {
+                encoder
+                    .record_event_with_inst_count(br_index, true, 10)
+                    .unwrap();
+            }
+            encoder.finish().unwrap();
+            contents.push(content);
+        }
+        assert_eq!(std::fs::read_dir(store.path()).unwrap().count(), 1);
+
+        let decoder = TraceFileDecoder::open(&contents[0]).unwrap();
+        assert!(decoder.is_image_stored(0));
+        assert!(contents[0].len() < image.data.len());
+        assert_eq!(
+            decoder.metadata[METADATA_IMAGE_STORE],
+            store.path().display().to_string()
+        );
+        assert_eq!(decoder.get_images().unwrap(), vec![image.clone()]);
+
+        // unknown image store
+        let mut decoder = TraceFileDecoder::open(&contents[1]).unwrap();
+        decoder.image_store = None;
+        assert!(decoder.get_images().is_err());
+    }
+
+    #[test]
+    fn test_image_store_relative() {
+        let root = tempfile::tempdir().unwrap();
+        let config_dir = root.path().join("config");
+        let trace_path = config_dir.join("traces").join("pin").join("test.log");
+        std::fs::create_dir_all(trace_path.parent().unwrap()).unwrap();
+        let image = Image {
+            start: 0x1000,
+            len: 0x1000,
+            data: vec![0x90; 4096],
+            filename: "/lib/test.so".to_string(),
+        };
+
+        let file = std::fs::File::create(&trace_path).unwrap();
+        let mut encoder = TraceFileEncoder::open(&file).unwrap();
+        encoder.image_store = Some(config_dir.join("images"));
+        encoder.set_trace_path(&trace_path);
+        encoder.images.push(image.clone());
+        encoder
+            .record_event(0x1010, 0x1000, 2, BranchType::DirectJump, true)
+            .unwrap();
+        encoder.finish().unwrap();
+
+        // the trace and the image store move together
+        let moved_dir = root.path().join("moved");
+        std::fs::rename(&config_dir, &moved_dir).unwrap();
+        let trace_path = moved_dir.join("traces").join("pin").join("test.log");
+        let content = std::fs::read(&trace_path).unwrap();
+        let decoder = TraceFileDecoder::open_with_path(&content, &trace_path).unwrap();
+        assert_eq!(decoder.metadata[METADATA_IMAGE_STORE], "../../images");
+        assert_eq!(decoder.get_images().unwrap(), vec![image]);
+    }
+
+    #[test]
+    fn test_threads() {
+        let mut content = vec![];
+        let mut encoder = TraceFileEncoder::open_stream(&mut content).unwrap();
+        let br_a = encoder.get_branch_index(0x1000, 0x2000, 2, BranchType::DirectJump);
+        let br_b = encoder.get_branch_index(0x3000, 0x4000, 2, BranchType::DirectJump);
+        // thread 1: 3 events, thread 2: 2 + 1 events, thread 1: 1 event
+        encoder.set_thread(1);
+        for _ in 0..3 {
+            encoder
+                .record_event_with_inst_count(br_a, true, 10)
+                .unwrap();
+        }
+        encoder.set_thread(2);
+        for _ in 0..2 {
+            encoder
+                .record_event_with_inst_count(br_b, true, 20)
+                .unwrap();
+        }
+        // switching back and forth without events keeps the segment
+        encoder.set_thread(1);
+        encoder.set_thread(2);
+        encoder
+            .record_event_with_inst_count(br_b, true, 20)
+            .unwrap();
+        encoder.set_thread(1);
+        encoder
+            .record_event_with_inst_count(br_a, true, 10)
+            .unwrap();
+        encoder.set_thread(3);
+        encoder.finish().unwrap();
+
+        let decoder = TraceFileDecoder::open(&content).unwrap();
+        assert_eq!(
+            decoder.thread_segments.to_vec(),
+            vec![
+                ThreadSegment {
+                    thread_id: 1,
+                    entry_offset: 0
+                },
+                ThreadSegment {
+                    thread_id: 2,
+                    entry_offset: 3
+                },
+                ThreadSegment {
+                    thread_id: 1,
+                    entry_offset: 6
+                },
+            ]
+        );
+        assert_eq!(decoder.thread_ids(), vec![1, 2]);
+
+        // all threads in recorded order
+        let seek = decoder.seek_to_instruction(0).unwrap();
+        let thread_ids: Vec<u64> = decoder
+            .thread_entries(seek, None, 0)
+            .unwrap()
+            .map(|thread_entry| thread_entry.thread_id)
+            .collect();
+        assert_eq!(thread_ids, vec![1, 1, 1, 2, 2, 2, 1]);
+
+        // each thread on its own
+        for (thread_id, br_index, instructions) in [(1, br_a, 40), (2, br_b, 60)] {
+            let seek = decoder.seek_to_instruction(0).unwrap();
+            let entries: Vec<_> = decoder
+                .thread_entries(seek, Some(thread_id), 2)
+                .unwrap()
+                .collect();
+            assert!(
+                entries
+                    .iter()
+                    .all(|thread_entry| thread_entry.entry.get_br_index() == br_index)
+            );
+            assert_eq!(
+                entries
+                    .iter()
+                    .map(|thread_entry| thread_entry.instructions)
+                    .sum::<u64>(),
+                instructions
+            );
+        }
+
+        // single-threaded traces have no segments
+        let mut content = vec![];
+        let mut encoder = TraceFileEncoder::open_stream(&mut content).unwrap();
+        let br_index = encoder.get_branch_index(0x1000, 0x2000, 2, BranchType::DirectJump);
+        encoder
+            .record_event_with_inst_count(br_index, true, 10)
+            .unwrap();
+        encoder.finish().unwrap();
+        let decoder = TraceFileDecoder::open(&content).unwrap();
+        assert!(decoder.thread_segments.is_empty());
+        assert_eq!(decoder.thread_ids(), vec![0]);
+    }
+}
@@ -1,14 +1,15 @@
-use crate::{BranchType, Image};
-use anyhow::Context;
+use crate::BranchType;
+use anyhow::bail;
 use capstone::{
+    Insn,
     arch::{
         ArchOperand,
+        arm64::{Arm64Operand, Arm64OperandType},
         x86::{X86Operand, X86OperandType},
     },
     prelude::*,
 };
-use object::{Architecture, Object, ObjectKind, ObjectSection, SectionKind};
-use std::collections::HashMap;
+use object::{Architecture, Object, ObjectSection, SectionKind};
 
 pub fn get_tqdm_style() -> indicatif::ProgressStyle {
     indicatif::ProgressStyle::with_template(
@@ -21,85 +22,6 @@ pub fn get_tqdm_style() -> indicatif::ProgressStyle {
         ).progress_chars("██ ")
 }
 
-/// create a mapping from instruction address to instruction index for instruction counting
-pub fn create_inst_index_mapping_from_images(
-    images: &[Image],
-) -> anyhow::Result<HashMap<u64, u64>> {
-    let mut addrs = vec![];
-    for image in images {
-        let binary_data = image.data.as_slice();
-        let file = object::File::parse(binary_data).with_context(|| {
-            format!(
-                "{} loaded to 0x{:x} with len {} (file len {})",
-                image.filename,
-                image.start,
-                image.len,
-                binary_data.len()
-            )
-        })?;
-        let load_base = match file.kind() {
-            ObjectKind::Executable => 0,
-            ObjectKind::Dynamic => image.start,
-            _ => unimplemented!("Unsupported file kind"),
-        };
-        let mut cs = match file.architecture() {
-            Architecture::X86_64 => Capstone::new()
-                .x86()
-                .mode(arch::x86::ArchMode::Mode64)
-                .syntax(arch::x86::ArchSyntax::Att)
-                .detail(true)
-                .build()?,
-            Architecture::Aarch64 => Capstone::new()
-                .arm64()
-                .mode(arch::arm64::ArchMode::Arm)
-                .detail(true)
-                .build()?,
-            _ => unimplemented!("Unsupported architecture"),
-        };
-        // handle unsupported instructions
-        cs.set_skipdata(true)?;
-
-        for section in file.sections() {
-            if section.kind() == SectionKind::Text {
-                let content = section.data()?;
-                let insns = cs.disasm_all(content, section.address())?;
-                for insn in insns.as_ref() {
-                    let addr = insn.address() + load_base;
-                    addrs.push(addr);
-                }
-            }
-        }
-    }
-
-    // assign index from low to high address
-    addrs.sort();
-
-    let mut mapping: HashMap<u64, u64> = HashMap::new();
-    let mut i = 0;
-    for addr in addrs {
-        assert_eq!(mapping.insert(addr, i), None);
-        i += 1;
-    }
-    println!(
-        "Found {} static instructions from {} images",
-        i,
-        images.len()
-    );
-    Ok(mapping)
-}
-
-pub fn get_inst_index(mapping: &HashMap<u64, u64>, addr: u64) -> u64 {
-    match mapping.get(&addr) {
-        Some(index) => {
-            // found
-            *index
-        }
-        None => {
-            panic!("Failed to get instruction index for pc 0x{:x}", addr);
-        }
-    }
-}
-
 /// Static branches parsed from ELF
 #[derive(Debug, Clone, Copy)]
 pub struct StaticBranch {
@@ -109,112 +31,650 @@ pub struct StaticBranch {
     pub branch_type: BranchType,
 }
 
-/// Find all branches by parsing ELF
+/// Find all branches by parsing ELF,
+/// except far, system and trapping transfers that branch predictors do not handle
 pub fn find_branches(binary_data: &[u8], load_base: u64) -> anyhow::Result<Vec<StaticBranch>> {
+    let file = object::File::parse(binary_data)?;
+    let architecture = file.architecture();
     let mut branches = vec![];
+    for section in file.sections() {
+        if section.kind() == SectionKind::Text {
+            let content = section.data()?;
+            branches.extend(find_branches_in_code(
+                architecture,
+                content,
+                section.address(),
+                load_base,
+            )?);
+        }
+    }
+    Ok(branches)
+}
 
-    let cs = Capstone::new()
+/// Find all branches in the code linked at the address
+fn find_branches_in_code(
+    architecture: Architecture,
+    code: &[u8],
+    address: u64,
+    load_base: u64,
+) -> anyhow::Result<Vec<StaticBranch>> {
+    match architecture {
+        Architecture::X86_64 => find_branches_x86_64(code, address, load_base),
+        Architecture::Aarch64 => find_branches_aarch64(code, address, load_base),
+        Architecture::Riscv64 => Ok(find_branches_riscv64(code, address, load_base)),
+        _ => bail!("Unsupported architecture {:?}", architecture),
+    }
+}
+
+fn find_branches_x86_64(
+    code: &[u8],
+    address: u64,
+    load_base: u64,
+) -> anyhow::Result<Vec<StaticBranch>> {
+    let mut branches = vec![];
+    let mut cs = x86_64_disassembler()?;
+    // keep going after data in text sections
+    cs.set_skipdata(true)?;
+    let insns = cs.disasm_all(code, address)?;
+    for insn in insns.as_ref() {
+        // far, system and trapping transfers are not predicted
+        let Some(X86Transfer::Branch(branch_type, targ_addr)) = classify_x86_64(&cs, insn) else {
+            continue;
+        };
+        branches.push(StaticBranch {
+            branch_type,
+            // add runtime load offset
+            inst_addr: insn.address() + load_base,
+            inst_length: insn.len() as u32,
+            targ_addr: targ_addr.map(|addr| addr + load_base),
+        });
+    }
+    Ok(branches)
+}
+
+fn x86_64_disassembler() -> CsResult<Capstone> {
+    Capstone::new()
         .x86()
         .mode(arch::x86::ArchMode::Mode64)
         .syntax(arch::x86::ArchSyntax::Att)
         .detail(true)
+        .build()
+}
+
+/// Control transfer of an x86-64 instruction
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum X86Transfer {
+    /// branch handled by branch predictors, with the target as linked if direct
+    Branch(BranchType, Option<u64>),
+    /// far, system, trapping and transactional transfers, not handled by branch predictors
+    NonPredicted,
+}
+
+/// Classify the instruction by its canonical name, which does not depend on
+/// the syntax or the prefixes such as bnd and notrack. None if it is not a control transfer.
+fn classify_x86_64(cs: &Capstone, insn: &Insn) -> Option<X86Transfer> {
+    let detail = cs.insn_detail(insn).ok()?;
+    let name = cs.insn_name(insn.id())?;
+    // ljmp and lcall share the canonical name with near jumps and calls
+    let far = insn
+        .mnemonic()
+        .and_then(|mnemonic| mnemonic.split_whitespace().last())
+        .is_some_and(|mnemonic| mnemonic.starts_with("ljmp") || mnemonic.starts_with("lcall"));
+    let ops = detail.arch_detail().operands();
+    let imm = match ops.first() {
+        Some(ArchOperand::X86Operand(X86Operand {
+            op_type: X86OperandType::Imm(imm),
+            ..
+        })) => Some(*imm as u64),
+        _ => None,
+    };
+
+    let branch_type = match name.as_str() {
+        "jmp" | "call" if far => return Some(X86Transfer::NonPredicted),
+        "jmp" if imm.is_some() => BranchType::DirectJump,
+        "jmp" => BranchType::IndirectJump,
+        "call" if imm.is_some() => BranchType::DirectCall,
+        "call" => BranchType::IndirectCall,
+        // including ret imm16
+        "ret" => BranchType::Return,
+        "ja" | "jae" | "jb" | "jbe" | "je" | "jg" | "jge" | "jl" | "jle" | "jne" | "jno"
+        | "jnp" | "jns" | "jo" | "jp" | "js" | "jcxz" | "jecxz" | "jrcxz" | "loop" | "loope"
+        | "loopne"
+            if imm.is_some() =>
+        {
+            BranchType::ConditionalDirectJump
+        }
+        "retf" | "retfq" | "iret" | "iretd" | "iretq" | "syscall" | "sysret" | "sysretq"
+        | "sysenter" | "sysexit" | "sysexitq" | "int" | "int1" | "int3" | "into" | "ud0"
+        | "ud1" | "ud2" | "xbegin" | "xabort" | "vmcall" | "vmmcall" | "vmlaunch" | "vmresume" => {
+            return Some(X86Transfer::NonPredicted);
+        }
+        _ => {
+            // any other transfer known to capstone
+            let transfer = detail.groups().iter().any(|group| {
+                matches!(
+                    cs.group_name(*group).as_deref(),
+                    Some("jump" | "call" | "ret" | "iret" | "int" | "branch_relative")
+                )
+            });
+            return transfer.then_some(X86Transfer::NonPredicted);
+        }
+    };
+    let targ_addr = match branch_type {
+        BranchType::ConditionalDirectJump | BranchType::DirectCall | BranchType::DirectJump => imm,
+        _ => None,
+    };
+    Some(X86Transfer::Branch(branch_type, targ_addr))
+}
+
+fn find_branches_aarch64(
+    code: &[u8],
+    address: u64,
+    load_base: u64,
+) -> anyhow::Result<Vec<StaticBranch>> {
+    let mut branches = vec![];
+
+    let mut cs = Capstone::new()
+        .arm64()
+        .mode(arch::arm64::ArchMode::Arm)
+        .detail(true)
         .build()?;
+    // handle data in text sections, e.g. literal pools
+    cs.set_skipdata(true)?;
 
-    let file = object::File::parse(binary_data)?;
-    let jump = Some("jump".to_string());
-    let branch_relative = Some("branch_relative".to_string());
-    let call = Some("call".to_string());
-    let ret = Some("ret".to_string());
+    let insns = cs.disasm_all(code, address)?;
+    for insn in insns.as_ref() {
+        let Some(branch_type) = classify_aarch64(insn) else {
+            continue;
+        };
 
-    for section in file.sections() {
-        if section.kind() == SectionKind::Text {
-            let content = section.data()?;
-            let insns = cs.disasm_all(content, section.address())?;
-            for insn in insns.as_ref() {
+        let targ_addr = match branch_type {
+            BranchType::ConditionalDirectJump | BranchType::DirectCall | BranchType::DirectJump => {
+                // the target is the last operand, after the register and bit tested if any
                 let detail: InsnDetail = cs.insn_detail(insn)?;
-                let groups: Vec<Option<String>> = detail
-                    .groups()
-                    .iter()
-                    .map(|id| cs.group_name(*id))
-                    .collect();
-                let has_jump = groups.contains(&jump);
-                let has_branch_relative = groups.contains(&branch_relative);
-                let has_call = groups.contains(&call);
-                let has_ret = groups.contains(&ret);
-                if has_jump || has_branch_relative || has_call || has_ret {
-                    // classify
-                    let mnemonic = insn.mnemonic().unwrap();
-                    let branch_type = match (has_jump, has_branch_relative, has_call, has_ret) {
-                        // direct jump, possible conditional
-                        (true, true, false, false) => match mnemonic {
-                            "jmp" => BranchType::DirectJump,
-                            "ja" | "jae" | "jb" | "jbe" | "jc" | "jcxz" | "jecxz" | "jrcxz"
-                            | "je" | "jg" | "jge" | "jl" | "jle" | "jna" | "jnae" | "jnb"
-                            | "jnbe" | "jnc" | "jne" | "jng" | "jnge" | "jnl" | "jnle" | "jno"
-                            | "jnp" | "jns" | "jnz" | "jo" | "jp" | "jpe" | "jpo" | "js" | "jz" => {
-                                BranchType::ConditionalDirectJump
-                            }
-                            "xbegin" => continue,
-                            _ => unimplemented!("Unhandled mnemonic {}", mnemonic),
-                        },
-                        // indirect jump
-                        (true, false, false, false) => {
-                            assert!(["jmpq"].contains(&mnemonic));
-                            BranchType::IndirectJump
-                        }
-                        // direct call
-                        (false, true, true, false) => {
-                            assert_eq!(mnemonic, "callq");
-                            BranchType::DirectCall
-                        }
-                        // indirect call
-                        (false, false, true, false) => {
-                            assert_eq!(mnemonic, "callq");
-                            BranchType::IndirectCall
-                        }
-                        // return
-                        (false, false, false, true) => {
-                            assert!(["retq"].contains(&mnemonic));
-                            BranchType::Return
-                        }
-                        _ => unimplemented!("Unhandled insn {} with groups {:?}", insn, groups),
-                    };
+                let ops = detail.arch_detail().operands();
+                match ops.last() {
+                    Some(ArchOperand::Arm64Operand(Arm64Operand {
+                        op_type: Arm64OperandType::Imm(imm),
+                        ..
+                    })) => {
+                        // add runtime load offset
+                        Some(*imm as u64 + load_base)
+                    }
+                    op => bail!("Unhandled operand {:?} of {}", op, insn),
+                }
+            }
+            _ => None,
+        };
 
-                    let ops = detail.arch_detail().operands();
-                    let targ_addr = match branch_type {
-                        BranchType::ConditionalDirectJump
-                        | BranchType::DirectCall
-                        | BranchType::DirectJump => {
-                            assert_eq!(ops.len(), 1);
-                            Some(match ops[0] {
-                                ArchOperand::X86Operand(X86Operand {
-                                    op_type: X86OperandType::Imm(imm),
-                                    size: _,
-                                    access: _,
-                                    avx_bcast: _,
-                                    avx_zero_opmask: _,
-                                }) => {
-                                    // add runtime load offset
-                                    imm as u64 + load_base
-                                }
-                                _ => unimplemented!("Unhandled operand {:?}", ops[0]),
-                            })
-                        }
-                        _ => None,
-                    };
+        branches.push(StaticBranch {
+            branch_type,
+            // add runtime load offset
+            inst_addr: insn.address() + load_base,
+            inst_length: insn.len() as u32,
+            targ_addr,
+        });
+    }
+    Ok(branches)
+}
 
-                    // add runtime load offset
-                    let inst_addr = insn.address() + load_base;
+/// Branch type of an AArch64 instruction, None if it is not a branch
+fn classify_aarch64(insn: &Insn) -> Option<BranchType> {
+    let mnemonic = insn.mnemonic()?;
+    Some(match mnemonic {
+        "b" => BranchType::DirectJump,
+        "cbz" | "cbnz" | "tbz" | "tbnz" => BranchType::ConditionalDirectJump,
+        // b.cond and bc.cond
+        _ if mnemonic.starts_with("b.") || mnemonic.starts_with("bc.") => {
+            BranchType::ConditionalDirectJump
+        }
+        "bl" => BranchType::DirectCall,
+        // with pointer authentication: blraa, blrab, blraaz and blrabz
+        "blr" | "blraa" | "blrab" | "blraaz" | "blrabz" => BranchType::IndirectCall,
+        "br" | "braa" | "brab" | "braaz" | "brabz" => BranchType::IndirectJump,
+        "ret" | "retaa" | "retab" => BranchType::Return,
+        _ => return None,
+    })
+}
 
-                    branches.push(StaticBranch {
-                        branch_type,
-                        inst_addr,
-                        inst_length: insn.len() as u32,
-                        targ_addr,
-                    });
+fn find_branches_riscv64(code: &[u8], address: u64, load_base: u64) -> Vec<StaticBranch> {
+    riscv_instructions(code, address)
+        .filter_map(|(inst_addr, insn)| {
+            let (branch_type, targ_addr) = classify_riscv(insn, inst_addr)?;
+            // add runtime load offset
+            Some(StaticBranch {
+                inst_addr: inst_addr + load_base,
+                targ_addr: targ_addr.map(|addr| addr + load_base),
+                inst_length: insn.len() as u32,
+                branch_type,
+            })
+        })
+        .collect()
+}
+
+/// Decode RISC-V instructions linearly, yielding the address and encoding of each.
+/// The length is encoded in the low bits of each instruction,
+/// so unknown extensions do not desynchronize the decoding.
+pub(crate) fn riscv_instructions(code: &[u8], address: u64) -> impl Iterator<Item = (u64, &[u8])> {
+    let mut offset = 0;
+    std::iter::from_fn(move || {
+        let low = *code.get(offset)?;
+        let len = if low & 0b11 != 0b11 {
+            2
+        } else if low & 0b11100 != 0b11100 {
+            4
+        } else if low & 0b111111 == 0b011111 {
+            6
+        } else if low & 0b1111111 == 0b0111111 {
+            8
+        } else {
+            // reserved longer encodings
+            2
+        };
+        let insn = code.get(offset..offset + len)?;
+        let inst_addr = address + offset as u64;
+        offset += len;
+        Some((inst_addr, insn))
+    })
+}
+
+/// Registers hinting a call or return in RISC-V jumps: ra (x1) and t0 (x5)
+fn is_riscv_link(reg: u32) -> bool {
+    reg == 1 || reg == 5
+}
+
+/// Sign-extend the low bits of the value
+fn sign_extend(value: u32, bits: u32) -> i64 {
+    ((value as i64) << (64 - bits)) >> (64 - bits)
+}
+
+/// Branch type and target of a RISC-V instruction at the address, None if it is not a branch.
+/// Jumps are classified into calls and returns by the link register hints of rd and rs1.
+fn classify_riscv(insn: &[u8], address: u64) -> Option<(BranchType, Option<u64>)> {
+    let target = |offset: i64| Some(address.wrapping_add(offset as u64));
+    match *insn {
+        [b0, b1] => {
+            let insn = u16::from_le_bytes([b0, b1]) as u32;
+            let bit = |from: u32, to: u32| ((insn >> from) & 1) << to;
+            match (insn & 0b11, insn >> 13) {
+                // c.j
+                (0b01, 0b101) => {
+                    let offset = bit(12, 11)
+                        | bit(11, 4)
+                        | bit(10, 9)
+                        | bit(9, 8)
+                        | bit(8, 10)
+                        | bit(7, 6)
+                        | bit(6, 7)
+                        | bit(5, 3)
+                        | bit(4, 2)
+                        | bit(3, 1)
+                        | bit(2, 5);
+                    Some((BranchType::DirectJump, target(sign_extend(offset, 12))))
+                }
+                // c.beqz and c.bnez
+                (0b01, 0b110 | 0b111) => {
+                    let offset = bit(12, 8)
+                        | bit(11, 4)
+                        | bit(10, 3)
+                        | bit(6, 7)
+                        | bit(5, 6)
+                        | bit(4, 2)
+                        | bit(3, 1)
+                        | bit(2, 5);
+                    Some((
+                        BranchType::ConditionalDirectJump,
+                        target(sign_extend(offset, 9)),
+                    ))
+                }
+                // c.jr and c.jalr, other encodings are c.mv, c.add and c.ebreak
+                (0b10, 0b100) => {
+                    let rs1 = (insn >> 7) & 0x1f;
+                    let rs2 = (insn >> 2) & 0x1f;
+                    if rs1 == 0 || rs2 != 0 {
+                        return None;
+                    }
+                    let branch_type = if insn & (1 << 12) != 0 {
+                        // links to ra
+                        BranchType::IndirectCall
+                    } else if is_riscv_link(rs1) {
+                        BranchType::Return
+                    } else {
+                        BranchType::IndirectJump
+                    };
+                    Some((branch_type, None))
+                }
+                _ => None,
+            }
+        }
+        [b0, b1, b2, b3] => {
+            let insn = u32::from_le_bytes([b0, b1, b2, b3]);
+            let rd = (insn >> 7) & 0x1f;
+            let funct3 = (insn >> 12) & 0b111;
+            let rs1 = (insn >> 15) & 0x1f;
+            match insn & 0x7f {
+                // beq, bne, blt, bge, bltu and bgeu
+                0b1100011 if funct3 != 0b010 && funct3 != 0b011 => {
+                    let offset = ((insn >> 31) & 1) << 12
+                        | ((insn >> 7) & 1) << 11
+                        | ((insn >> 25) & 0x3f) << 5
+                        | ((insn >> 8) & 0xf) << 1;
+                    Some((
+                        BranchType::ConditionalDirectJump,
+                        target(sign_extend(offset, 13)),
+                    ))
+                }
+                // jal
+                0b1101111 => {
+                    let offset = ((insn >> 31) & 1) << 20
+                        | ((insn >> 12) & 0xff) << 12
+                        | ((insn >> 20) & 1) << 11
+                        | ((insn >> 21) & 0x3ff) << 1;
+                    let branch_type = if is_riscv_link(rd) {
+                        BranchType::DirectCall
+                    } else {
+                        BranchType::DirectJump
+                    };
+                    Some((branch_type, target(sign_extend(offset, 21))))
                 }
+                // jalr
+                0b1100111 if funct3 == 0 => {
+                    let branch_type = match (is_riscv_link(rd), is_riscv_link(rs1)) {
+                        // including coroutine swaps with both links
+                        (true, _) => BranchType::IndirectCall,
+                        (false, true) => BranchType::Return,
+                        (false, false) => BranchType::IndirectJump,
+                    };
+                    Some((branch_type, None))
+                }
+                _ => None,
             }
         }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        BranchType,
+        utils::{
+            X86Transfer, classify_x86_64, find_branches_in_code, riscv_instructions,
+            x86_64_disassembler,
+        },
+    };
+    use object::Architecture;
+
+    #[test]
+    fn test_aarch64() {
+        let insns: [(u32, BranchType, Option<u64>); 12] = [
+            // b #+8
+            (0x14000002, BranchType::DirectJump, Some(0x1008)),
+            // bl #+16
+            (0x94000004, BranchType::DirectCall, Some(0x1018)),
+            // b.eq #+8
+            (0x54000040, BranchType::ConditionalDirectJump, Some(0x1018)),
+            // cbz x0, #+8
+            (0xb4000040, BranchType::ConditionalDirectJump, Some(0x1020)),
+            // cbnz w1, #+12
+            (0x35000061, BranchType::ConditionalDirectJump, Some(0x102c)),
+            // tbz w0, #3, #+8
+            (0x36180040, BranchType::ConditionalDirectJump, Some(0x1030)),
+            // tbnz w2, #1, #+4
+            (0x37080022, BranchType::ConditionalDirectJump, Some(0x1034)),
+            // br x16
+            (0xd61f0200, BranchType::IndirectJump, None),
+            // blr x8
+            (0xd63f0100, BranchType::IndirectCall, None),
+            // blraaz x8
+            (0xd63f091f, BranchType::IndirectCall, None),
+            // ret
+            (0xd65f03c0, BranchType::Return, None),
+            // retaa
+            (0xd65f0bff, BranchType::Return, None),
+        ];
+        let mut code = vec![];
+        for (insn, _, _) in &insns {
+            code.extend_from_slice(&insn.to_le_bytes());
+            // nop
+            code.extend_from_slice(&0xd503201fu32.to_le_bytes());
+        }
+        let load_base = 0x7000_0000;
+        let branches =
+            find_branches_in_code(Architecture::Aarch64, &code, 0x1000, load_base).unwrap();
+        assert_eq!(branches.len(), insns.len());
+        for (i, (branch, (_, branch_type, targ_addr))) in branches.iter().zip(insns).enumerate() {
+            assert_eq!(branch.inst_addr, load_base + 0x1000 + i as u64 * 8);
+            assert_eq!(branch.inst_length, 4);
+            assert_eq!(branch.branch_type, branch_type, "{:x?}", branch);
+            assert_eq!(branch.targ_addr, targ_addr.map(|addr| addr + load_base));
+        }
+    }
+
+    #[test]
+    fn test_riscv() {
+        // branch type and target if it is a branch
+        type Expected = Option<(BranchType, Option<u64>)>;
+        let insns: [(&[u8], Expected); 16] = [
+            // beq a0, a1, +16
+            (
+                &0x00b50863u32.to_le_bytes(),
+                Some((BranchType::ConditionalDirectJump, Some(0x1010))),
+            ),
+            // bne a0, zero, -8
+            (
+                &0xfe051ce3u32.to_le_bytes(),
+                Some((BranchType::ConditionalDirectJump, Some(0xffc))),
+            ),
+            // jal ra, +256
+            (
+                &0x100000efu32.to_le_bytes(),
+                Some((BranchType::DirectCall, Some(0x1108))),
+            ),
+            // jal zero, +8
+            (
+                &0x0080006fu32.to_le_bytes(),
+                Some((BranchType::DirectJump, Some(0x1014))),
+            ),
+            // jalr zero, 0(ra)
+            (
+                &0x00008067u32.to_le_bytes(),
+                Some((BranchType::Return, None)),
+            ),
+            // jalr ra, 0(a5)
+            (
+                &0x000780e7u32.to_le_bytes(),
+                Some((BranchType::IndirectCall, None)),
+            ),
+            // jalr zero, 0(a5)
+            (
+                &0x00078067u32.to_le_bytes(),
+                Some((BranchType::IndirectJump, None)),
+            ),
+            // addi a0, a0, 1
+            (&0x00150513u32.to_le_bytes(), None),
+            // c.beqz a0, +4
+            (
+                &0xc111u16.to_le_bytes(),
+                Some((BranchType::ConditionalDirectJump, Some(0x1024))),
+            ),
+            // c.bnez a0, +4
+            (
+                &0xe111u16.to_le_bytes(),
+                Some((BranchType::ConditionalDirectJump, Some(0x1026))),
+            ),
+            // c.j +8
+            (
+                &0xa021u16.to_le_bytes(),
+                Some((BranchType::DirectJump, Some(0x102c))),
+            ),
+            // c.jr ra
+            (&0x8082u16.to_le_bytes(), Some((BranchType::Return, None))),
+            // c.jr a5
+            (
+                &0x8782u16.to_le_bytes(),
+                Some((BranchType::IndirectJump, None)),
+            ),
+            // c.jalr a5
+            (
+                &0x9782u16.to_le_bytes(),
+                Some((BranchType::IndirectCall, None)),
+            ),
+            // c.mv a0, a5
+            (&0x853eu16.to_le_bytes(), None),
+            // c.nop
+            (&0x0001u16.to_le_bytes(), None),
+        ];
+        let code: Vec<u8> = insns.iter().flat_map(|(insn, _)| insn.to_vec()).collect();
+        let decoded: Vec<(u64, &[u8])> = riscv_instructions(&code, 0x1000).collect();
+        assert_eq!(decoded.len(), insns.len());
+
+        let load_base = 0x7000_0000;
+        let mut branches = find_branches_in_code(Architecture::Riscv64, &code, 0x1000, load_base)
+            .unwrap()
+            .into_iter();
+        for ((inst_addr, insn), (_, expected)) in decoded.iter().zip(insns) {
+            let Some((branch_type, targ_addr)) = expected else {
+                continue;
+            };
+            let branch = branches.next().unwrap();
+            assert_eq!(branch.inst_addr, load_base + inst_addr);
+            assert_eq!(branch.inst_length, insn.len() as u32);
+            assert_eq!(branch.branch_type, branch_type, "{:x?}", branch);
+            assert_eq!(branch.targ_addr, targ_addr.map(|addr| addr + load_base));
+        }
+        assert!(branches.next().is_none());
+    }
+
+    #[test]
+    fn test_x86_64() {
+        use X86Transfer::*;
+        let table: &[(&[u8], Option<X86Transfer>)] = &[
+            // jmp rel8 and rel32
+            (
+                &[0xeb, 0x05],
+                Some(Branch(BranchType::DirectJump, Some(0x1007))),
+            ),
+            (
+                &[0xe9, 0x00, 0x01, 0x00, 0x00],
+                Some(Branch(BranchType::DirectJump, Some(0x1105))),
+            ),
+            // bnd jmp rel32
+            (
+                &[0xf2, 0xe9, 0x00, 0x00, 0x00, 0x00],
+                Some(Branch(BranchType::DirectJump, Some(0x1006))),
+            ),
+            // je rel8 and jne rel32
+            (
+                &[0x74, 0x05],
+                Some(Branch(BranchType::ConditionalDirectJump, Some(0x1007))),
+            ),
+            (
+                &[0x0f, 0x85, 0x00, 0x01, 0x00, 0x00],
+                Some(Branch(BranchType::ConditionalDirectJump, Some(0x1106))),
+            ),
+            // jrcxz, loop, loope and loopne
+            (
+                &[0xe3, 0x05],
+                Some(Branch(BranchType::ConditionalDirectJump, Some(0x1007))),
+            ),
+            (
+                &[0xe2, 0x05],
+                Some(Branch(BranchType::ConditionalDirectJump, Some(0x1007))),
+            ),
+            (
+                &[0xe1, 0x05],
+                Some(Branch(BranchType::ConditionalDirectJump, Some(0x1007))),
+            ),
+            (
+                &[0xe0, 0xfe],
+                Some(Branch(BranchType::ConditionalDirectJump, Some(0x1000))),
+            ),
+            // jmp *%rax, jmp *(%rip), notrack jmp *%rax and bnd jmp *%rax
+            (&[0xff, 0xe0], Some(Branch(BranchType::IndirectJump, None))),
+            (
+                &[0xff, 0x25, 0x00, 0x00, 0x00, 0x00],
+                Some(Branch(BranchType::IndirectJump, None)),
+            ),
+            (
+                &[0x3e, 0xff, 0xe0],
+                Some(Branch(BranchType::IndirectJump, None)),
+            ),
+            (
+                &[0xf2, 0xff, 0xe0],
+                Some(Branch(BranchType::IndirectJump, None)),
+            ),
+            // call rel32, call *%rax and notrack call *%rax
+            (
+                &[0xe8, 0x00, 0x00, 0x00, 0x00],
+                Some(Branch(BranchType::DirectCall, Some(0x1005))),
+            ),
+            (&[0xff, 0xd0], Some(Branch(BranchType::IndirectCall, None))),
+            (
+                &[0x3e, 0xff, 0xd0],
+                Some(Branch(BranchType::IndirectCall, None)),
+            ),
+            // ret, ret imm16 and bnd ret
+            (&[0xc3], Some(Branch(BranchType::Return, None))),
+            (&[0xc2, 0x08, 0x00], Some(Branch(BranchType::Return, None))),
+            (&[0xf2, 0xc3], Some(Branch(BranchType::Return, None))),
+            // ljmp *(%rsp) and lcall *(%rsp)
+            (&[0xff, 0x2c, 0x24], Some(NonPredicted)),
+            (&[0xff, 0x1c, 0x24], Some(NonPredicted)),
+            // lret, lret imm16 and lretq
+            (&[0xcb], Some(NonPredicted)),
+            (&[0xca, 0x08, 0x00], Some(NonPredicted)),
+            (&[0x48, 0xcb], Some(NonPredicted)),
+            // iret and iretq
+            (&[0xcf], Some(NonPredicted)),
+            (&[0x48, 0xcf], Some(NonPredicted)),
+            // syscall, sysret, sysretq, sysenter and sysexit
+            (&[0x0f, 0x05], Some(NonPredicted)),
+            (&[0x0f, 0x07], Some(NonPredicted)),
+            (&[0x48, 0x0f, 0x07], Some(NonPredicted)),
+            (&[0x0f, 0x34], Some(NonPredicted)),
+            (&[0x0f, 0x35], Some(NonPredicted)),
+            // int 0x80, int3 and int1
+            (&[0xcd, 0x80], Some(NonPredicted)),
+            (&[0xcc], Some(NonPredicted)),
+            (&[0xf1], Some(NonPredicted)),
+            // ud2, ud0 and ud1
+            (&[0x0f, 0x0b], Some(NonPredicted)),
+            (&[0x0f, 0xff], Some(NonPredicted)),
+            (&[0x0f, 0xb9], Some(NonPredicted)),
+            // xbegin and xabort
+            (&[0xc7, 0xf8, 0x00, 0x00, 0x00, 0x00], Some(NonPredicted)),
+            (&[0xc6, 0xf8, 0x00], Some(NonPredicted)),
+            // vmcall
+            (&[0x0f, 0x01, 0xc1], Some(NonPredicted)),
+            // not control transfers: xend, nop, mov %rax, %rbx, endbr64 and hlt
+            (&[0x0f, 0x01, 0xd5], None),
+            (&[0x90], None),
+            (&[0x48, 0x89, 0xc3], None),
+            (&[0xf3, 0x0f, 0x1e, 0xfa], None),
+            (&[0xf4], None),
+        ];
+        let cs = x86_64_disassembler().unwrap();
+        for (code, expected) in table {
+            let insns = cs.disasm_count(code, 0x1000, 1).unwrap();
+            let insn = insns.iter().next().unwrap();
+            assert_eq!(insn.len(), code.len(), "{}", insn);
+            assert_eq!(classify_x86_64(&cs, insn), *expected, "{}", insn);
+        }
+
+        // only predicted branches are found, invalid instructions are skipped:
+        // push %es, daa and aaa are invalid in 64-bit mode, and a truncated jmp at the end
+        let code: Vec<u8> = table
+            .iter()
+            .enumerate()
+            .flat_map(|(i, (code, _))| [code, &[0x06, 0x27, 0x37][i % 3..i % 3 + 1]].concat())
+            .chain([0x66, 0xe9, 0x00, 0x00])
+            .collect();
+        let branches = find_branches_in_code(Architecture::X86_64, &code, 0x1000, 0).unwrap();
+        let num_branches = table
+            .iter()
+            .filter(|(_, expected)| matches!(expected, Some(Branch(..))))
+            .count();
+        assert_eq!(branches.len(), num_branches);
     }
-    Ok(branches)
 }
@@ -66,5 +66,88 @@ struct __attribute__((packed)) file_header {
 //   struct image images[header.num_images];
 // }
 
-#define MAX_BRS (1 << 25)
+// version 1 (chunked) trace file, written by TraceFileEncoder:
+// entries are compressed into independent zstd frames, one per chunk,
+// so that the decoder can start from any chunk
+struct __attribute__((packed)) chunk {
+  // index of the first entry in the chunk
+  uint64_t entry_offset;
+  uint64_t num_entries;
+  // instructions executed before the chunk, UINT64_MAX if unknown
+  uint64_t instruction_offset;
+  // the last taken branch before the chunk, UINT64_MAX if none
+  uint64_t last_taken_br_index;
+  // offset of the zstd frame from the beginning of file
+  uint64_t data_offset;
+  uint64_t data_size;
+};
+
+struct __attribute__((packed)) file_header_v1 {
+  // same as file_header, with version = 1
+  struct file_header header;
+  uint64_t num_chunks;
+  // offset of chunks array from the beginning of file
+  uint64_t chunks_offset;
+};
+
+// optional sections in trace version 2, unknown kinds are ignored
+enum optional_section_kind {
+  // json object of string key/value pairs
+  OPTIONAL_SECTION_METADATA = 0,
+  // uint32_t instructions executed at each taken branch, one zstd frame per chunk
+  OPTIONAL_SECTION_INST_COUNTS = 1,
+  // struct inst_count_chunk array, one for each chunk
+  OPTIONAL_SECTION_INST_COUNT_CHUNKS = 2,
+  // struct image_ref array, one for each image
+  OPTIONAL_SECTION_IMAGE_REFS = 3,
+  // struct thread_segment array, in the order of entry_offset
+  OPTIONAL_SECTION_THREAD_SEGMENTS = 4,
+};
+
+struct __attribute__((packed)) inst_count_chunk {
+  // offset of zstd frame from the beginning of file
+  uint64_t data_offset;
+  uint64_t data_size;
+};
+
+// image kept in a shared image store instead of the trace file,
+// the data_size of its struct image is zero
+struct __attribute__((packed)) image_ref {
+  uint64_t size;
+  uint8_t sha256[32];
+};
+
+// entries from entry_offset until the next segment belong to the thread,
+// the first segment begins at entry 0; without segments all entries belong to thread 0.
+// instruction counts of multi-threaded traces are counted within each thread
+struct __attribute__((packed)) thread_segment {
+  uint64_t thread_id;
+  uint64_t entry_offset;
+};
+
+struct __attribute__((packed)) optional_section {
+  uint64_t kind;
+  // offset of section data from the beginning of file
+  uint64_t offset;
+  uint64_t size;
+};
+
+struct __attribute__((packed)) file_header_v2 {
+  // same as file_header_v1, with version = 2
+  struct file_header_v1 header;
+  uint64_t num_optional_sections;
+  // offset of optional_section array from the beginning of file
+  uint64_t optional_sections_offset;
+};
+
+// trace version 3 is written append-only, e.g. to a pipe:
+// a file_header_v2 with only magic and version set is at the beginning of file,
+// the complete file_header_v2 with version = 3 is at the end of file
+
+// trace versions 4 and 5 are the same as versions 2 and 3, but the entries are variable-width:
+// each entry is LEB128 of (br_index << 1 | taken), lifting the 2^31 limit of struct entry
+
+// branch tables of the tracers grow on demand up to the 31-bit br_index of struct entry
+#define MAX_BRS (1ULL << 31)
+#define INITIAL_BRS 16384
 #define MAX_IMAGES 128
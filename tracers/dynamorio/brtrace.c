@@ -58,7 +58,8 @@ static char log_file_name[256] = "brtrace.log";
 
 struct tls {
   file_t log;
-  struct branch brs[MAX_BRS];
+  struct branch *brs;
+  uint64_t brs_capacity;
   struct image images[MAX_IMAGES];
   struct hashmap_s br_map;
 
@@ -87,9 +88,22 @@ static inline void logger(struct tls *t, app_pc inst_addr, app_pc fall_addr,
   e.taken = taken;
 
   // insert branch if not exists
-  int it = (int)(uintptr_t)hashmap_get(&t->br_map, &br, sizeof(br));
+  uint64_t it = (uintptr_t)hashmap_get(&t->br_map, &br, sizeof(br));
   if (it == 0) {
     assert(t->num_brs < MAX_BRS);
+    if (t->num_brs == t->brs_capacity) {
+      // grow branch table on demand
+      uint64_t capacity = t->brs_capacity ? t->brs_capacity * 2 : INITIAL_BRS;
+      struct branch *brs =
+          (struct branch *)realloc(t->brs, sizeof(struct branch) * capacity);
+      if (brs == NULL) {
+        dr_fprintf(STDERR, "Failed to grow branch table to %lu branches\n",
+                   capacity);
+        dr_abort();
+      }
+      t->brs = brs;
+      t->brs_capacity = capacity;
+    }
     struct branch *pbr = (struct branch *)malloc(sizeof(br));
     *pbr = br;
     hashmap_put(&t->br_map, pbr, sizeof(br),
@@ -225,6 +239,8 @@ static void event_thread_init(void *drcontext) {
   t->log = log;
   t->num_entries = 0;
   t->num_brs = 0;
+  t->brs = NULL;
+  t->brs_capacity = 0;
   t->num_images = 0;
   t->buffer_size = 0;
   struct hashmap_create_options_s options;
@@ -317,6 +333,7 @@ static void event_thread_exit(void *drcontext) {
   dr_write_file(t->log, &header, sizeof(struct file_header));
 
   dr_close_file(t->log);
+  free(t->brs);
   fprintf(stderr, "Finished writing log\n");
 }
 
@@ -11,7 +11,8 @@
 // based on pin manual examples
 
 static FILE *trace = NULL;
-static struct branch brs[MAX_BRS];
+static struct branch *brs = NULL;
+static uint64_t brs_capacity = 0;
 static struct image images[MAX_IMAGES];
 static std::map<struct branch, int> br_map;
 static uint64_t num_entries = 0;
@@ -56,6 +57,19 @@ VOID RecordBranch(VOID *inst_addr, VOID *targ_addr, UINT32 inst_length,
   auto it = br_map.find(br);
   if (it == br_map.end()) {
     assert(num_brs < MAX_BRS);
+    if (num_brs == brs_capacity) {
+      // grow branch table on demand
+      uint64_t capacity = brs_capacity ? brs_capacity * 2 : INITIAL_BRS;
+      struct branch *new_brs =
+          (struct branch *)realloc(brs, sizeof(struct branch) * capacity);
+      if (new_brs == NULL) {
+        fprintf(stderr, "Failed to grow branch table to %lu branches\n",
+                capacity);
+        abort();
+      }
+      brs = new_brs;
+      brs_capacity = capacity;
+    }
     br_map[br] = num_brs;
     e.br_index = num_brs;
     brs[num_brs++] = br;